@@ -0,0 +1,61 @@
+use super::protocol;
+use super::table::Set;
+use super::NtResult;
+
+use std::cell::RefCell;
+
+/// A group of values set together by `Table::batch`. Nothing is applied
+/// until the whole batch is, so the values are seen and sent as one.
+///
+/// # Example
+///
+/// ```ignore
+/// try!(client.batch(|b| {
+///     let _ = b.set("/Shooter/speed".to_string(), 3000f64);
+///     let _ = b.set("/Shooter/enabled".to_string(), true);
+/// }));
+/// ```
+pub struct Batch {
+    entries: RefCell<Vec<(String, protocol::EntryType)>>,
+}
+
+impl Batch {
+    pub fn new() -> Batch {
+        Batch{entries: RefCell::new(Vec::new())}
+    }
+
+    /// The keys and values set, in the order they were set.
+    pub fn into_entries(self) -> Vec<(String, protocol::EntryType)> {
+        self.entries.unwrap()
+    }
+
+    /// Sets `key` to a value of any type.
+    pub fn set_entry(&self, key: String, value: protocol::EntryType) -> NtResult<()> {
+        self.entries.borrow_mut().push((key, value));
+        Ok(())
+    }
+}
+
+impl Set<bool> for Batch {
+    fn set(&self, key: String, value: bool) -> NtResult<()> {
+        self.set_entry(key, protocol::Boolean(value))
+    }
+}
+
+impl Set<f64> for Batch {
+    fn set(&self, key: String, value: f64) -> NtResult<()> {
+        self.set_entry(key, protocol::Number(value))
+    }
+}
+
+impl Set<String> for Batch {
+    fn set(&self, key: String, value: String) -> NtResult<()> {
+        self.set_entry(key, protocol::String(value))
+    }
+}
+
+impl Set<Vec<u8>> for Batch {
+    fn set(&self, key: String, value: Vec<u8>) -> NtResult<()> {
+        self.set_entry(key, protocol::Raw(value))
+    }
+}
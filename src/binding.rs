@@ -0,0 +1,159 @@
+use super::table::{Get, Set};
+use super::NtResult;
+
+/// Declares a struct whose fields are each bound to an entry under a
+/// prefix. Every field is a `Field` named after it, so the struct has
+/// typed values that can be published to and refreshed from any `Table`
+/// together, and each field tracks whether it changed on the last
+/// refresh.
+///
+/// This is a macro rather than a `#[deriving(NtTable)]` because custom
+/// derives need a compiler plugin, which is built as its own crate
+/// against the compiler's internals.
+///
+/// # Example
+///
+/// ```ignore
+/// nt_table! {
+///     struct ShooterTelemetry {
+///         speed: f64 = 0f64,
+///         enabled: bool = false,
+///     }
+/// }
+///
+/// let mut telemetry = ShooterTelemetry::new("/Shooter");
+/// telemetry.speed.set(3000f64);
+/// try!(telemetry.publish(&client));
+///
+/// telemetry.refresh(&client);
+/// if telemetry.enabled.changed() { ... }
+/// ```
+#[macro_export]
+macro_rules! nt_table(
+    ($(#[$attr:meta])* struct $name:ident { $($field:ident : $ty:ty = $default:expr),+ }) => (
+        nt_table!($(#[$attr])* struct $name { $($field : $ty = $default,)+ })
+    );
+    ($(#[$attr:meta])* struct $name:ident { $($field:ident : $ty:ty = $default:expr,)+ }) => (
+        $(#[$attr])*
+        pub struct $name {
+            prefix: String,
+            $(pub $field: $crate::Field<$ty>,)+
+        }
+
+        impl $name {
+            /// Binds every field to the entry named after it under `prefix`.
+            pub fn new(prefix: &str) -> $name {
+                let prefix = prefix.trim_right_chars('/').to_string();
+                $name {
+                    $($field: $crate::Field::new(format!("{}/{}", prefix, stringify!($field)), $default),)+
+                    prefix: prefix,
+                }
+            }
+
+            /// Sets every field's entry in a single batch. Fields after
+            /// one that fails aren't set, and the first error is returned.
+            pub fn publish<T: $crate::Table>(&self, table: &T) -> $crate::NtResult<()> {
+                let mut result = Ok(());
+                try!(table.batch(|b| {
+                    $(if result.is_ok() { result = self.$field.publish(b) })+
+                }));
+                result
+            }
+
+            /// Reads every field from a single snapshot of the table.
+            pub fn refresh<T: $crate::Table>(&mut self, table: &T) {
+                let snapshot = table.snapshot(format!("{}/", self.prefix).as_slice());
+                $(self.$field.refresh(&snapshot);)+
+            }
+
+            /// Whether any field changed on the last refresh.
+            pub fn changed(&self) -> bool {
+                false $(|| self.$field.changed())+
+            }
+        }
+    );
+)
+
+/// A typed value bound to an entry, as generated by `nt_table!`.
+pub struct Field<T> {
+    key: String,
+    value: T,
+    changed: bool,
+}
+
+impl<T: Clone + PartialEq> Field<T> {
+    pub fn new(key: String, value: T) -> Field<T> {
+        Field{key: key, value: value, changed: false}
+    }
+
+    pub fn key(&self) -> &str { self.key.as_slice() }
+
+    pub fn get(&self) -> T { self.value.clone() }
+
+    /// Sets the local value, which is sent on the next publish.
+    pub fn set(&mut self, value: T) {
+        self.value = value;
+    }
+
+    /// Whether the value in the table differed from the local value on
+    /// the last refresh.
+    pub fn changed(&self) -> bool { self.changed }
+
+    pub fn publish<S: Set<T>>(&self, table: &S) -> NtResult<()> {
+        table.set(self.key.clone(), self.value.clone())
+    }
+
+    /// Reads the value from `table`, keeping the local value if the
+    /// entry doesn't exist or has another type.
+    pub fn refresh<G: Get<T>>(&mut self, table: &G) {
+        match table.get(self.key.clone()) {
+            Some(value) => {
+                self.changed = value != self.value;
+                self.value = value;
+            },
+            None => self.changed = false,
+        }
+    }
+}
+
+/// Tests
+#[cfg(test)]
+mod test {
+    use super::super::{Table, Set, LocalTable};
+
+    nt_table! {
+        struct ShooterTelemetry {
+            speed: f64 = 0f64,
+            enabled: bool = false,
+            mode: String = "Idle".to_string()
+        }
+    }
+
+    #[test]
+    fn binding_publish_and_refresh() {
+        let table = LocalTable::new();
+        let mut telemetry = ShooterTelemetry::new("/Shooter/");
+        assert_eq!("/Shooter/speed", telemetry.speed.key());
+
+        telemetry.speed.set(3000f64);
+        telemetry.publish(&table).unwrap();
+        assert_eq!(3, table.snapshot("/Shooter/").len());
+
+        // Nothing changed remotely
+        telemetry.refresh(&table);
+        assert!(!telemetry.changed());
+
+        table.set("/Shooter/enabled".to_string(), true).unwrap();
+        telemetry.refresh(&table);
+        assert!(telemetry.enabled.changed());
+        assert!(!telemetry.speed.changed());
+        assert_eq!(true, telemetry.enabled.get());
+        assert_eq!(3000f64, telemetry.speed.get());
+
+        // A value of the wrong type is ignored
+        table.set("/Shooter/mode".to_string(), 1f64).unwrap();
+        telemetry.refresh(&table);
+        assert!(!telemetry.changed());
+        assert_eq!("Idle".to_string(), telemetry.mode.get());
+    }
+}
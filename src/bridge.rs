@@ -0,0 +1,245 @@
+use super::protocol;
+use super::{NtResult, NtError};
+use super::client::Client;
+use super::server::Server;
+use super::table::{Table, Event, Added, Updated, FlagsUpdated, Deleted, PropertiesUpdated};
+
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+
+/// Connects NT2 and NT3 clients to an NT4 server. They connect to the
+/// bridge as they would to a `Server`, each speaking its own version,
+/// and the bridge connects to the NT4 server as an NT4 client, mirroring
+/// changes from each side to the other.
+///
+/// The NT2 side allocates entry ids and sequence numbers as a `Server`
+/// does, and the NT4 side uses the topic ids the NT4 server announces.
+/// Values are translated as `Client::new_nt4` does, so NT4 integer and
+/// float topics appear as numbers and arrays aren't bridged. NT3 raw
+/// values are NT4 raw topics, and the persistent flag is NT4's
+/// persistent property. NT4 has no RPCs, so RPC entries stay on the NT2
+/// side.
+///
+/// # Example
+///
+/// ```ignore
+/// let bridge = networktables::Bridge::new("0.0.0.0:1735", "roborio.local:5810").unwrap();
+/// ```
+pub struct Bridge {
+    server: Arc<Server>,
+    client: Arc<Client>,
+    forwarded: Mutex<HashMap<String, Forwarded>>,
+    errors: Mutex<Vec<NtError>>,
+}
+
+// Locking order to avoid deadlocks:
+// - forwarded
+// - errors
+
+/// The last value of a key sent either way. NT2 updates are ordered
+/// by sequence number and NT4 values by timestamp, so each key keeps
+/// the NT4 server time of its latest value: NT2 updates are stamped
+/// with the estimated server time as they're forwarded, and NT4 values
+/// stamped before the latest one lost a race with an NT2 update.
+struct Forwarded {
+    value: protocol::EntryType,
+    server_us: Option<i64>,
+}
+
+impl Bridge {
+    /// Listens for NT2 clients on `address` and connects to the NT4
+    /// server at `upstream`.
+    pub fn new(address: &str, upstream: &str) -> NtResult<Arc<Bridge>> {
+        let server = try!(Server::new(address));
+        let client = match Client::new_nt4(upstream, "bridge") {
+            Ok(client) => client,
+            Err(e) => {
+                server.close();
+                return Err(e)
+            },
+        };
+        let bridge = Arc::new(Bridge{
+            server: server,
+            client: client,
+            forwarded: Mutex::new(HashMap::new()),
+            errors: Mutex::new(Vec::new()),
+        });
+
+        let (downstream_tx, downstream_rx) = channel();
+        let (upstream_tx, upstream_rx) = channel();
+        bridge.server.add_listener(downstream_tx);
+        bridge.client.add_listener(upstream_tx);
+        let (bridge2, bridge3) = (bridge.clone(), bridge.clone());
+        spawn(proc() for event in downstream_rx.iter() { bridge2.handle_downstream(event) });
+        spawn(proc() for event in upstream_rx.iter() { bridge3.handle_upstream(event) });
+        Ok(bridge)
+    }
+
+    /// The address NT2 clients connect to.
+    pub fn address(&self) -> &str { self.server.address() }
+
+    pub fn close(&self) {
+        self.server.close();
+        self.client.close();
+    }
+
+    /// The errors of both sides of the bridge, and of forwarding
+    /// between them.
+    pub fn get_errors(&self) -> Vec<NtError> {
+        let mut errors = self.server.get_errors();
+        errors.push_all(self.client.get_errors().as_slice());
+        errors.push_all(self.errors.lock().as_slice());
+        errors
+    }
+
+    /// Sends a change an NT2 client made to the NT4 server.
+    fn handle_downstream(&self, event: Event) {
+        let result = match event {
+            Added(key, value) | Updated(key, value) => {
+                // NT4 has no RPCs
+                if let protocol::Rpc(_) = value { return }
+                let mut forwarded = self.forwarded.lock();
+                let result = self.client.set_entry(key.clone(), value.clone());
+                if result.is_ok() {
+                    // Stamped with the estimated server time as it was set
+                    let server_us = match self.client.get_stamped(key.clone()) {
+                        Some((ref stored, ref timestamp)) if *stored == value => timestamp.server_us,
+                        _ => None,
+                    };
+                    forwarded.insert(key, Forwarded{value: value, server_us: server_us});
+                }
+                result
+            },
+            FlagsUpdated(key, flags) => self.client.set_flags(key, flags),
+            Deleted(key) => {
+                self.forwarded.lock().remove(&key);
+                self.client.delete(key)
+            },
+            PropertiesUpdated(_, _) => Ok(()),
+        };
+        if let Err(e) = result {
+            self.log_error(e.during("forwarding to the NT4 server"));
+        }
+    }
+
+    /// Sends a change from the NT4 server to the NT2 clients, unless
+    /// it's one the bridge made itself or it's older than the latest.
+    fn handle_upstream(&self, event: Event) {
+        let result = match event {
+            Added(key, value) | Updated(key, value) => {
+                let mut forwarded = self.forwarded.lock();
+                // The stamp is read with the value, so it's this value's.
+                // An event whose value was already replaced is followed by
+                // one for the replacement, which is forwarded instead.
+                // Uncached values are never stored, so have no stamp.
+                let server_us = match self.client.get_stamped(key.clone()) {
+                    Some((ref stored, _)) if *stored != value => return,
+                    Some((_, timestamp)) => timestamp.server_us,
+                    None => None,
+                };
+                match forwarded.get(&key) {
+                    Some(last) if last.value == value => return,
+                    Some(&Forwarded{server_us: Some(latest), ..}) if server_us.map_or(false, |t| t < latest) =>
+                        return,
+                    _ => (),
+                }
+                let result = self.server.set_entry(key.clone(), value.clone());
+                forwarded.insert(key, Forwarded{value: value, server_us: server_us});
+                result
+            },
+            Deleted(key) => {
+                match self.forwarded.lock().remove(&key) {
+                    // Already deleted if an NT2 client deleted it
+                    Some(_) => self.server.delete_entry(key),
+                    None => Ok(()),
+                }
+            },
+            // Only NT2 clients change flags, and NT2 has no properties
+            FlagsUpdated(_, _) | PropertiesUpdated(_, _) => Ok(()),
+        };
+        if let Err(e) = result {
+            self.log_error(e.during("forwarding to NT2 clients"));
+        }
+    }
+
+    fn log_error(&self, err: NtError) {
+        let mut errors = self.errors.lock();
+        errors.push(err);
+    }
+}
+
+/// Tests
+#[cfg(test)]
+mod test {
+    use super::Bridge;
+    use super::super::{Client, Nt4Server, Get, Set, Connected};
+    use serialize::json;
+    use std::io::timer::sleep;
+    use std::time::Duration;
+
+    /// Polls `f` until it's true, giving up after a couple of seconds.
+    fn wait_for(f: || -> bool) -> bool {
+        for _ in range(0u, 200u) {
+            if f() { return true }
+            sleep(Duration::milliseconds(10));
+        }
+        false
+    }
+
+    #[test]
+    fn bridge_forwards_both_ways() {
+        let upstream = Nt4Server::new("127.0.0.1:0").unwrap();
+        let bridge = Bridge::new("127.0.0.1:0", upstream.address()).unwrap();
+        let old = Client::new(bridge.address()).unwrap();
+        let new = Client::new_nt4(upstream.address(), "new").unwrap();
+        assert!(wait_for(|| old.get_state() == Connected));
+
+        old.set("/Old".to_string(), 1f64).unwrap();
+        assert!(wait_for(|| { let n: Option<f64> = new.get("/Old".to_string()); n == Some(1f64) }));
+        new.set("/New".to_string(), "hi".to_string()).unwrap();
+        assert!(wait_for(|| { let s: Option<String> = old.get("/New".to_string()); s == Some("hi".to_string()) }));
+
+        // Updates go back the other way under each side's own ids
+        new.set("/Old".to_string(), 2f64).unwrap();
+        assert!(wait_for(|| { let n: Option<f64> = old.get("/Old".to_string()); n == Some(2f64) }));
+        old.set("/New".to_string(), "there".to_string()).unwrap();
+        assert!(wait_for(|| { let s: Option<String> = new.get("/New".to_string()); s == Some("there".to_string()) }));
+
+        assert!(bridge.get_errors().is_empty());
+        assert!(old.get_errors().is_empty() && new.get_errors().is_empty());
+        old.close();
+        new.close();
+        bridge.close();
+        upstream.close();
+    }
+
+    #[test]
+    fn bridge_serves_nt3_clients() {
+        let upstream = Nt4Server::new("127.0.0.1:0").unwrap();
+        let bridge = Bridge::new("127.0.0.1:0", upstream.address()).unwrap();
+        // Client::new offers NT3, which the bridge speaks
+        let old = Client::new(bridge.address()).unwrap();
+        let new = Client::new_nt4(upstream.address(), "new").unwrap();
+        assert!(wait_for(|| old.get_state() == Connected));
+
+        // Raw values go both ways
+        new.set("/Pose".to_string(), vec![1u8, 2]).unwrap();
+        assert!(wait_for(|| { let p: Option<Vec<u8>> = old.get("/Pose".to_string()); p == Some(vec![1u8, 2]) }));
+        old.set("/Pose".to_string(), vec![3u8]).unwrap();
+        assert!(wait_for(|| { let p: Option<Vec<u8>> = new.get("/Pose".to_string()); p == Some(vec![3u8]) }));
+
+        // And the persistent flag becomes the persistent property
+        old.set_persistent("/Pose".to_string()).unwrap();
+        assert!(wait_for(|| {
+            new.get_properties("/Pose".to_string())
+               .map_or(false, |p| p.get(&"persistent".to_string()) == Some(&json::Boolean(true)))
+        }));
+
+        assert!(bridge.get_errors().is_empty());
+        assert!(old.get_errors().is_empty() && new.get_errors().is_empty());
+        old.close();
+        new.close();
+        bridge.close();
+        upstream.close();
+    }
+}
@@ -1,40 +1,47 @@
 use super::protocol;
+use super::protocol::{Message, KeepAlive, ClientHelloComplete, Assignment, Update, FlagsUpdate, Delete, ClearAll,
+                      ExecuteRpc};
 use super::NtResult;
-use super::{NtError, KeyAlreadyExists, IdAlreadyExists, OutOfOrderSequenceNumbers,  NetworkProblem};
+use super::table::{Get, Set, Table, Event, Listeners, Added, Updated, FlagsUpdated, Deleted,
+                   PropertiesUpdated};
+use super::{NtError, KeyAlreadyExists, IdAlreadyExists, IdDoesntExist, KeyDoesntExist,
+            OutOfOrderSequenceNumbers, NetworkProblem, TypeMismatch, UnknownRpcCall,
+            InvalidMessage, RequiresNt4, UnsupportedMessage, UnsupportedVersion, RequiresNt3,
+            OutOfRpcUids};
+use super::{nt4, websocket, msgpack};
 
-use std::sync::{Arc, Mutex};
-use std::collections::HashMap;
+use super::store::Store;
+use super::snapshot::Snapshot;
+use super::batch::Batch;
+use super::stats;
+use super::stats::Stats;
+use super::limiter::Limiter;
 
-use std::io::Listener;
+use std::sync::{Arc, Mutex, RWLock};
+use std::collections::{HashMap, TreeMap};
+use std::mem;
+
+use std::io::{Listener, MemWriter, IoError, EndOfFile};
 use std::io::net::tcp::TcpStream;
 use std::io::Timer;
 use std::time::Duration;
 
-/// A trait for getting values of different types by a key.
-pub trait Get<T> {
-    /// Returns the value of `Some(T)` if it exists in the tabel and
-    /// is coercible into the desired value.
-    fn get(&self, key: String) -> Option<T>;
-}
+use serialize::json;
 
-/// A trait for setting values of different types by a key.
-pub trait Set<T> {
-    /// Returns the value of `Some(T)` if it exists in the tabel and
-    /// is coercible into the desired value.
-    fn set(&self, key: String, value: T) -> NtResult<()>;
-}
+use time::precise_time_ns;
 
-// TODO: Figure out table trait
-// pub trait Table : Get<bool> + Get<f64> + Get<String> {}
-// pub trait Table : Get<bool + f64 + String> {}
-
-// TODO: better map without race conditions
 // Locking order to avoid deadlocks:
-// - entries_by_name
-// - entries_by_id
+// - store
 // - send_queue
+// - limiter
+// - rpc_calls
+// - topics
 // - state
+// - version
 // - connection
+// - listeners
+// - stats
+// - time_offset
 
 /// A [NetworkTables 2.0](https://docs.google.com/document/d/1On9BkUgkmMmTnfVxSQlOZMWsa9Vas6-8cT19TX59Tho/edit)
 /// client. It acts as a distributed HashTable that is synchronized
@@ -47,18 +54,67 @@ pub trait Set<T> {
 /// use networktables;
 /// let networktables::Client::new("localhost:1735").unwrap();
 /// ```
+///
+/// `Client::new` offers NT3, and falls back to NT2 if the server only
+/// speaks that. Flags, deletes, raw values and RPCs need NT3.
+///
+/// With `Client::new_nt4` it connects to an NT4 server instead. Topics
+/// appear as entries, and values are sent by publishing topics.
 #[deriving(Sync)]
 pub struct Client {
-    entries_by_name: Mutex<HashMap<String, protocol::Entry>>,
-    entries_by_id: Mutex<HashMap<u16, protocol::Entry>>,
-    send_queue: Mutex<Vec<protocol::Entry>>,
+    // Readers only take the read lock, so gets don't wait on each
+    // other, only on the listener while it applies a message.
+    store: RWLock<Store>,
+    send_queue: Mutex<Vec<Message>>,
+    limiter: Mutex<Option<Limiter>>,
+    rpc_calls: Mutex<RpcCalls>,
+    // Only set for NT4 connections
+    topics: Option<Mutex<Topics>>,
     state: Mutex<State>,
     errors: Mutex<Vec<NtError>>,
+    address: String,
+    /// The protocol version spoken with the server. NT4 connections
+    /// count as NT3, since they have everything it does.
+    version: Mutex<u16>,
 	connection: Mutex<TcpStream>,
+    listeners: Listeners,
+    stats: Mutex<Stats>,
+    /// Microseconds to add to the local clock to get the NT4 server's.
+    time_offset: Mutex<Option<i64>>,
+}
+
+// How long RPC calls wait on a response by default.
+const RPC_TIMEOUT_MS: i64 = 5000;
+
+/// The RPC calls waiting on a response, by entry id and call uid, with
+/// the `precise_time_ns` they give up at.
+struct RpcCalls {
+    next_uid: u16,
+    timeout: Duration,
+    waiting: HashMap<(u16, u16), (Sender<Vec<u8>>, u64)>,
+}
+
+/// The NT4 topics the server has announced, and the messages waiting
+/// to be sent that don't map onto entries.
+struct Topics {
+    by_id: HashMap<i64, Topic>,
+    ids: HashMap<String, i64>,
+    /// The pubuid of each topic this client publishes.
+    pubuids: HashMap<String, i64>,
+    next_pubuid: i64,
+    text_queue: Vec<nt4::ClientMessage>,
+}
+
+struct Topic {
+    name: String,
+    type_str: String,
+    properties: json::JsonObject,
+    /// Whether listeners have been told about a value yet.
+    received: bool,
 }
 
 /// The state of the clients connection.
-#[deriving(PartialEq,Eq,Sync,Clone,Show)]
+#[deriving(PartialEq,Sync,Clone,Show)]
 pub enum State {
     /// The state between starting and receiving th hello complete message.
     Initializing,
@@ -71,30 +127,80 @@ pub enum State {
 }
 
 impl Client {
-    pub fn new(address: &'static str) -> NtResult<Arc<Client>> {
-        let connection = Mutex::new(try!(TcpStream::connect(address)));
-        {   // Make sure the lock is  released
-            try!(protocol::write_hello(&mut *connection.lock()))
-        }
+    pub fn new(address: &str) -> NtResult<Arc<Client>> {
+        let (connection, hello_bytes) = try!(Client::connect(address, protocol::VERSION_3));
         
+        let mut stats = Stats::new();
+        stats.record_sent(protocol::HELLO, hello_bytes);
+
+        // TODO: Block until initialized?
+        Ok(Client::start(address, protocol::VERSION_3, connection, None, Initializing, stats))
+    }
+
+    /// Connects to the server at `address` and says hello with `version`,
+    /// returning the connection and the size of the hello.
+    fn connect(address: &str, version: u16) -> NtResult<(TcpStream, u64)> {
+        let mut hello = MemWriter::new();
+        try!(protocol::write_hello(&mut hello, version, protocol::DEFAULT_IDENTITY));
+        let mut connection = try!(TcpStream::connect(address));
+        try!(connection.write(hello.get_ref()).map_err(|e| NtError::new(NetworkProblem(e))
+             .during("writing hello").with_message(protocol::HELLO)));
+        Ok((connection, hello.get_ref().len() as u64))
+    }
+
+    /// Connects to the NT4 server at `address`, identifying as `name`.
+    /// The client subscribes to every topic, so its entries mirror the
+    /// server's topics. Topics whose values have no entry type, such as
+    /// arrays, are skipped.
+    pub fn new_nt4(address: &str, name: &str) -> NtResult<Arc<Client>> {
+        let mut connection = try!(TcpStream::connect(address));
+        try!(websocket::connect(&mut connection, address, format!("/nt/{}", name).as_slice(),
+                                websocket::NT4_PROTOCOLS.as_slice())
+             .map_err(|e| e.during("connecting WebSocket")));
+        // Sync clocks straight away, then once a second
+        try!(websocket::write_frame(&mut connection, &try!(time_sync_frame()), true)
+             .map_err(|e| e.during("writing time sync")));
+
+        // Values are sent every 20ms, the same as the send loop
+        let options = nt4::SubscriptionOptions{periodic: 0.02, prefix: true, ..nt4::SubscriptionOptions::new()};
+        let topics = Topics{
+            by_id: HashMap::new(),
+            ids: HashMap::new(),
+            pubuids: HashMap::new(),
+            next_pubuid: 0,
+            text_queue: vec![nt4::Subscribe(0, vec!["".to_string()], options)],
+        };
+        Ok(Client::start(address, protocol::VERSION_3, connection, Some(topics), Connected, Stats::new()))
+    }
+
+    fn start(address: &str, version: u16, connection: TcpStream, topics: Option<Topics>, state: State,
+             stats: Stats) -> Arc<Client> {
         let client = Arc::new(Client{
-            entries_by_name: Mutex::new(HashMap::new()),
-            entries_by_id: Mutex::new(HashMap::new()),
+            store: RWLock::new(Store::new()),
             send_queue: Mutex::new(Vec::new()),
-            state: Mutex::new(Initializing),
+            limiter: Mutex::new(None),
+            rpc_calls: Mutex::new(RpcCalls{next_uid: 0, timeout: Duration::milliseconds(RPC_TIMEOUT_MS),
+                                           waiting: HashMap::new()}),
+            topics: topics.map(|topics| Mutex::new(topics)),
+            state: Mutex::new(state),
             errors: Mutex::new(Vec::new()),
-            connection: connection,
+            address: address.to_string(),
+            version: Mutex::new(version),
+            connection: Mutex::new(connection),
+            listeners: Listeners::new(),
+            stats: Mutex::new(stats),
+            time_offset: Mutex::new(None),
         });
         
         let (client2, client3) = (client.clone(), client.clone());
         spawn(proc() client2.listen());
         spawn(proc() client3.send());
-
-        // TODO: Block until initialized?
-        Ok(client)
+        client
     }
 
     pub fn close(&self) {
+        // No responses will arrive once closed
+        self.rpc_calls.lock().waiting.clear();
         let mut state = self.state.lock();
         match *state {
             Initializing | Connected => { *state = Closed; },
@@ -109,8 +215,176 @@ impl Client {
 
     pub fn get_state(&self) -> State { self.state.lock().clone() }
     pub fn get_errors(&self) -> Vec<NtError> { self.errors.lock().clone() }
+    /// Returns the connection's traffic counters as of now.
+    pub fn stats(&self) -> Stats {
+        let depth = stats::queue_depth(self.send_queue.lock().as_slice());
+        let mut stats = self.stats.lock().clone();
+        stats.send_queue_depth = depth;
+        stats
+    }
+
+    /// Limits the bytes sent per second, or removes the limit with
+    /// `None`. While over the limit nothing is sent, and later values
+    /// for a key replace queued ones, so a slow link gets the latest
+    /// values rather than a growing backlog.
+    pub fn set_bandwidth_limit(&self, bytes_per_second: Option<u64>) {
+        *self.limiter.lock() = bytes_per_second.map(|b| Limiter::new(b, precise_time_ns()));
+    }
+
+    /// Estimates the NT4 server's clock, in microseconds, from the
+    /// round trips of periodic time syncs. `None` before the first sync
+    /// completes and for NT2 connections.
+    pub fn server_time(&self) -> Option<i64> {
+        self.time_offset.lock().map(|offset| local_us() + offset)
+    }
+
+    /// Returns when the value of the entry with `key` was received or
+    /// set, see `Timestamp`.
+    pub fn get_timestamp(&self, key: String) -> Option<protocol::Timestamp> {
+        self.store.read().get(&key).map(|entry| entry.timestamp.clone())
+    }
+
+    /// Returns the value of the entry with `key` along with its
+    /// timestamp, read together so the timestamp is that value's.
+    pub fn get_stamped(&self, key: String) -> Option<(protocol::EntryType, protocol::Timestamp)> {
+        self.store.read().get(&key).map(|entry| (entry.value.clone(), entry.timestamp.clone()))
+    }
+
+    /// Returns the flags of the entry with `key`, see `set_flags`.
+    pub fn get_flags(&self, key: String) -> Option<u8> {
+        self.store.read().get(&key).map(|entry| entry.flags)
+    }
+
+    /// Sets the flags of an existing entry and sends them to the server.
+    /// Flags are only part of NT3, so this fails over NT2.
+    pub fn set_flags(&self, key: String, flags: u8) -> NtResult<()> {
+        self.update_flags(key, |_| flags)
+    }
+
+    /// Marks an existing entry to be saved by the server and restored
+    /// when it restarts.
+    pub fn set_persistent(&self, key: String) -> NtResult<()> {
+        self.update_flags(key, |flags| flags | protocol::FLAG_PERSISTENT)
+    }
+
+    fn update_flags(&self, key: String, f: |u8| -> u8) -> NtResult<()> {
+        try!(self.check_nt3("set flags").map_err(|e| e.with_key(key.clone())));
+        let mut store = self.store.write();
+        let mut queue = self.send_queue.lock();
+
+        if let Some(entry) = store.get_assigned_mut(&key) {
+            entry.flags = f(entry.flags);
+            let (id, flags) = (entry.id, entry.flags);
+            queue.retain(|m| match *m {
+                FlagsUpdate(queued, _) => queued != id,
+                _ => true,
+            });
+            queue.push(FlagsUpdate(id, flags));
+            self.listeners.notify(FlagsUpdated(key, flags));
+            return Ok(())
+        }
+
+        // Entries waiting on an id send their flags with the request
+        if let Some(entry) = store.get_pending_mut(&key) {
+            entry.flags = f(entry.flags);
+            for queued in queue.iter_mut() {
+                if is_request_for(queued, &key) {
+                    *queued = Assignment(entry.clone());
+                }
+            }
+            self.listeners.notify(FlagsUpdated(key, entry.flags));
+            return Ok(())
+        }
+
+        Err(NtError::new(KeyDoesntExist(key.clone())).with_key(key))
+    }
+
+    /// Returns the NT4 properties of `topic`, or `None` if the server
+    /// hasn't announced it.
+    pub fn get_properties(&self, topic: String) -> Option<json::JsonObject> {
+        match self.topics {
+            Some(ref topics) => topics.lock().get(&topic).map(|t| t.properties.clone()),
+            None => None,
+        }
+    }
+
+    /// Sets the NT4 property `name` of an announced topic and sends it to
+    /// the server, or removes it if `value` is `null`. Once a topic isn't
+    /// `cached` its values go to listeners without being stored, and
+    /// listeners are told its stored value is deleted.
+    pub fn set_property(&self, topic: String, name: String, value: json::Json) -> NtResult<()> {
+        let topics = match self.topics {
+            Some(ref topics) => topics,
+            None => return Err(NtError::new(RequiresNt4("set properties")).with_key(topic)),
+        };
+        let mut store = self.store.write();
+        let mut topics = topics.lock();
+        let id = match topics.ids.get(&topic) {
+            Some(id) => *id,
+            None => return Err(NtError::new(KeyDoesntExist(topic.clone())).with_key(topic)),
+        };
+
+        let mut update = TreeMap::new();
+        update.insert(name, value);
+        {
+            let topic = topics.by_id.get_mut(&id).unwrap();
+            nt4::merge_properties(&mut topic.properties, &update);
+            self.drop_if_uncached(&mut *store, topic, id);
+        }
+        topics.text_queue.push(nt4::SetProperties(topic.clone(), update.clone()));
+        self.listeners.notify(PropertiesUpdated(topic, update));
+        Ok(())
+    }
+
+    /// Calls the remote procedure with `key`, returning a receiver for
+    /// its result. The receiver hangs up if the connection closes, or
+    /// the RPC timeout passes, before the response arrives. RPCs are
+    /// only part of NT3, so this fails over NT2.
+    pub fn call_rpc(&self, key: String, params: Vec<u8>) -> NtResult<Receiver<Vec<u8>>> {
+        try!(self.check_nt3("call RPCs").map_err(|e| e.with_key(key.clone())));
+        try!(protocol::check_length(params.len(), self.version()).map_err(|e| e.with_key(key.clone())));
+        let id = match self.store.read().get_assigned(&key) {
+            Some(&protocol::Entry{id, value: protocol::Rpc(_), ..}) => id,
+            Some(_) => return Err(NtError::new(TypeMismatch(key.clone())).with_key(key)),
+            None => return Err(NtError::new(KeyDoesntExist(key.clone())).with_key(key)),
+        };
+
+        let (tx, rx) = channel();
+        let mut queue = self.send_queue.lock();
+        let mut calls = self.rpc_calls.lock();
+        // Each call waiting on a response needs its own uid
+        let next_uid = calls.next_uid;
+        let uid = match range(0u, 0x10000).map(|i| next_uid + i as u16)
+                                          .find(|uid| !calls.waiting.contains_key(&(id, *uid))) {
+            Some(uid) => uid,
+            None => return Err(NtError::new(OutOfRpcUids(id)).with_key(key).with_id(id)),
+        };
+        let deadline = precise_time_ns() + calls.timeout.num_nanoseconds().unwrap_or(0) as u64;
+        calls.next_uid = uid + 1;
+        calls.waiting.insert((id, uid), (tx, deadline));
+        queue.push(ExecuteRpc(id, uid, params));
+        Ok(rx)
+    }
+
+    /// Sets how long later RPC calls wait on a response. The default is
+    /// 5 seconds. Servers don't respond to calls they can't handle, so
+    /// without a timeout those calls would wait forever.
+    pub fn set_rpc_timeout(&self, timeout: Duration) {
+        self.rpc_calls.lock().timeout = timeout;
+    }
+
     fn clone_connection(&self) -> TcpStream { self.connection.lock().clone() }
 
+    fn version(&self) -> u16 { *self.version.lock() }
+
+    /// Fails with `RequiresNt3` if the server only speaks NT2.
+    fn check_nt3(&self, operation: &'static str) -> NtResult<()> {
+        match self.version() {
+            version if version >= protocol::VERSION_3 => Ok(()),
+            _ => Err(NtError::new(RequiresNt3(operation))),
+        }
+    }
+
     fn send(&self) {
         let keep_alive_cutoff: u64 = 1000 /*ms*/ / 20 /*ms*/;
         let mut counter = 0;
@@ -119,123 +393,575 @@ impl Client {
 
         loop {
             periodic.recv();
-            if let Err(e) = self.send_queue() {
-                return self.log_fatal(e)
-            }
-            
             counter += 1;
             if (counter % keep_alive_cutoff) == 0 {
                 counter = 0;
-                if let Err(e) = self.send_keep_alive() {
-                    return self.log_fatal(e)
+                // NT4 servers ping us instead, so the time is synced
+                match self.topics {
+                    Some(_) => if let Err(e) = time_sync_frame().and_then(|f| self.write_frame(&f)) {
+                        return self.log_fatal(e)
+                    },
+                    None => self.queue_keep_alive(),
                 }
             }
+
+            self.expire_rpc_calls();
+            if let Err(e) = self.send_queue() {
+                return self.log_fatal(e)
+            }
         }
     }
 
     fn send_queue(&self) -> NtResult<()> {
+        if let Some(ref topics) = self.topics {
+            return self.send_nt4_queue(topics)
+        }
+        // The version isn't settled until the server says hello
+        if self.get_state() == Initializing { return Ok(()) }
         let mut connection = self.clone_connection();
+        let version = self.version();
 
-        // Send all entries in the queue
+        // Encode all messages in the queue, then send them in one write
+        // so messages queued together always go out together.
         let mut queue = self.send_queue.lock();
-        for entry in queue.iter() {
-            try!(match entry.id.clone() {
-                protocol::CLIENT_REQUEST_ID => protocol::write_assignment(&mut connection, entry),
-                _ => protocol::write_update(&mut connection, entry),
-            });
+        if queue.is_empty() { return Ok(()) }
+        let start = precise_time_ns();
+        let mut limiter = self.limiter.lock();
+        if let Some(ref mut limiter) = *limiter {
+            if !limiter.ready(start) {
+                self.stats.lock().deferred_flushes += 1;
+                return Ok(())
+            }
+        }
+
+        // Messages the version can't carry are dropped rather than
+        // failing the connection. They can still be queued while the
+        // version is being settled, by a set racing the NT2 fallback.
+        let mut w = MemWriter::new();
+        let mut sizes = Vec::with_capacity(queue.len());
+        for message in queue.iter() {
+            let before = w.get_ref().len();
+            let written = match *message {
+                Assignment(ref entry) => protocol::write_assignment(&mut w, entry, version)
+                    .map_err(|e| e.during("writing entry assignment")
+                             .with_key(entry.name.clone()).with_id(entry.id)),
+                Update(ref entry) => protocol::write_update(&mut w, entry, version)
+                    .map_err(|e| e.during("writing entry update")
+                             .with_key(entry.name.clone()).with_id(entry.id)),
+                Delete(id) => protocol::write_message(&mut w, message, version)
+                    .map_err(|e| e.during("writing entry delete").with_id(id)),
+                _ => protocol::write_message(&mut w, message, version),
+            };
+            // Nothing is written for a message that fails
+            if let Err(e) = written {
+                self.log_error(e.with_message(message.message_type()));
+                continue
+            }
+            sizes.push((message.message_type(), (w.get_ref().len() - before) as u64));
+        }
+        try!(connection.write(w.get_ref())
+             .map_err(|e| NtError::new(NetworkProblem(e)).during("sending queued entries")));
+        if let Some(ref mut limiter) = *limiter {
+            limiter.consume(w.get_ref().len() as u64);
         }
 
+        let mut stats = self.stats.lock();
+        for &(message, bytes) in sizes.iter() {
+            stats.record_sent(message, bytes);
+        }
+        stats.record_flush(precise_time_ns() - start);
+
         // Clear queue
         *queue = Vec::new();
         Ok(())
     }
 
-    fn send_keep_alive(&self) -> NtResult<()> {
-        let mut connection = self.clone_connection();
-        protocol::write_keep_alive(&mut connection)
+    /// Sends the queued messages as NT4 ones: a text frame publishing
+    /// topics and setting properties, then a binary frame of values.
+    fn send_nt4_queue(&self, topics: &Mutex<Topics>) -> NtResult<()> {
+        let mut queue = self.send_queue.lock();
+        let mut limiter = self.limiter.lock();
+        let mut topics = topics.lock();
+        if queue.is_empty() && topics.text_queue.is_empty() { return Ok(()) }
+        let start = precise_time_ns();
+        if let Some(ref mut limiter) = *limiter {
+            if !limiter.ready(start) {
+                self.stats.lock().deferred_flushes += 1;
+                return Ok(())
+            }
+        }
+
+        let mut values = Vec::new();
+        for message in queue.iter() {
+            match *message {
+                Assignment(ref entry) | Update(ref entry) => match topics.value_for(entry) {
+                    Some(value) => values.push(value),
+                    None => self.log_error(NtError::new(TypeMismatch(entry.name.clone()))
+                                           .during("publishing value").with_key(entry.name.clone())),
+                },
+                FlagsUpdate(id, flags) => topics.set_persistent(id as i64, flags & protocol::FLAG_PERSISTENT != 0),
+                Delete(id) => topics.unpublish(id as i64),
+                ClearAll(_) => {
+                    let ids: Vec<i64> = topics.by_id.keys().map(|id| *id).collect();
+                    for id in ids.into_iter() { topics.unpublish(id) }
+                },
+                // NT4 has no RPCs
+                _ => (),
+            }
+        }
+
+        let mut sent = 0u64;
+        if !topics.text_queue.is_empty() {
+            let text = nt4::write_client_messages(topics.text_queue.as_slice());
+            sent += text.len() as u64;
+            try!(self.write_frame(&websocket::Text(text)));
+        }
+        if !values.is_empty() {
+            let bytes = try!(nt4::write_values(values.as_slice()));
+            sent += bytes.len() as u64;
+            try!(self.write_frame(&websocket::Binary(bytes)));
+        }
+        if let Some(ref mut limiter) = *limiter {
+            limiter.consume(sent);
+        }
+        self.stats.lock().record_flush(precise_time_ns() - start);
+
+        topics.text_queue = Vec::new();
+        *queue = Vec::new();
+        Ok(())
+    }
+
+    /// Writes a frame to the server. Clients mask every frame.
+    fn write_frame(&self, frame: &websocket::Frame) -> NtResult<()> {
+        websocket::write_frame(&mut *self.connection.lock(), frame, true)
+            .map_err(|e| e.during("writing WebSocket frame"))
+    }
+
+    /// Hangs up on the RPC calls whose timeout has passed.
+    fn expire_rpc_calls(&self) {
+        let now = precise_time_ns();
+        let mut calls = self.rpc_calls.lock();
+        let expired: Vec<(u16, u16)> = calls.waiting.iter()
+            .filter(|&(_, &(_, deadline))| deadline <= now)
+            .map(|(call, _)| *call)
+            .collect();
+        for call in expired.iter() {
+            calls.waiting.remove(call);
+        }
+    }
+
+    /// Queues a keep alive if nothing else is queued. It's sent like any
+    /// other message, so it waits on the bandwidth limit as the server's
+    /// keep alives do.
+    fn queue_keep_alive(&self) {
+        let mut queue = self.send_queue.lock();
+        if queue.is_empty() {
+            queue.push(KeepAlive);
+        }
     }
     
     fn listen(&self) {
-        let mut connection = self.clone_connection();
+        if let Some(ref topics) = self.topics {
+            return self.listen_nt4(topics)
+        }
+        let mut stream = protocol::CountingReader::new(self.clone_connection());
 
         loop {
-            let msg = match connection.read_u8() {
+            let offset = stream.count();
+            let msg = match stream.read_u8() {
                 Ok(b) => b,
-                Err(e) => return self.log_fatal(NtError{kind: NetworkProblem(e)}),
+                Err(e) => return self.log_fatal(NtError::new(NetworkProblem(e))
+                                                .during("reading message type").at_offset(offset)),
+            };
+            let result = match msg {
+                m if self.version() < protocol::first_version(m) => Err(NtError::new(UnsupportedMessage(m))),
+                protocol::KEEP_ALIVE => Ok(()),
+                protocol::VERSION_UNSUPPORTED => self.handle_version_unsupported(&mut stream),
+                protocol::SERVER_HELLO => protocol::parse_server_hello(&mut stream).map(|_| ()),
+                protocol::HELLO_COMPLETE => Ok(self.handle_hello_complete()),
+                protocol::ENTRY_ASSIGNMENT => self.handle_entry_assignment(&mut stream),
+                protocol::ENTRY_UPDATE => self.handle_entry_update(&mut stream),
+                protocol::ENTRY_FLAGS_UPDATE => self.handle_flags_update(&mut stream),
+                protocol::ENTRY_DELETE => self.handle_entry_delete(&mut stream),
+                protocol::RPC_RESPONSE => self.handle_rpc_response(&mut stream),
+                protocol::CLEAR_ALL_ENTRIES => self.handle_clear_all(&mut stream),
+                // The message's length is unknown, so the stream can't be read past it
+                m => Err(NtError::new(UnsupportedMessage(m))),
             };
-            match msg {
-                protocol::HELLO_COMPLETE => self.handle_hello_complete(),
-                protocol::ENTRY_ASSIGNMENT => self.handle_entry_assignment(),
-                protocol::ENTRY_UPDATE => self.handle_entry_update(),
-                m => println!("Unsupported message type 0x{:02X}", m), // panic!(format!("Unsupported message type {}", m)), // TODO: Handle more gracefully
+            if let Err(e) = result {
+                return self.log_fatal(e.with_message(msg).at_offset(offset))
             }
+            self.stats.lock().record_received(msg, stream.count() - offset);
+            if msg == protocol::VERSION_UNSUPPORTED {
+                // Carry on with the new connection
+                stream = protocol::CountingReader::new(self.clone_connection());
+            }
+        }
+    }
+
+    fn listen_nt4(&self, topics: &Mutex<Topics>) {
+        let mut stream = self.clone_connection();
+
+        loop {
+            let result = match websocket::read_frame(&mut stream) {
+                Ok(websocket::Text(text)) => self.handle_nt4_text(topics, text.as_slice()),
+                Ok(websocket::Binary(bytes)) => self.handle_nt4_values(topics, bytes.as_slice()),
+                Ok(websocket::Ping(payload)) => self.write_frame(&websocket::Pong(payload)),
+                Ok(websocket::Pong(_)) => Ok(()),
+                Ok(websocket::Close) =>
+                    Err(NtError::new(NetworkProblem(IoError{kind: EndOfFile, desc: "WebSocket closed",
+                                                            detail: None}))),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                return self.log_fatal(e.during("reading NT4 frame"))
+            }
+        }
+    }
+
+    fn handle_nt4_text(&self, topics: &Mutex<Topics>, text: &str) -> NtResult<()> {
+        for message in try!(nt4::parse_server_messages(text)).into_iter() {
+            match message {
+                nt4::Announce(name, id, type_str, _, properties) =>
+                    self.handle_announce(topics, name, id, type_str, properties),
+                nt4::Unannounce(name, id) => self.handle_unannounce(topics, name, id),
+                // Our own updates were applied when they were set
+                nt4::Properties(_, true, _) => (),
+                nt4::Properties(name, false, update) => self.handle_properties(topics, name, update),
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_announce(&self, topics: &Mutex<Topics>, name: String, id: i64, type_str: String,
+                       properties: json::JsonObject) {
+        let mut store = self.store.write();
+        let mut topics = topics.lock();
+        let topic = Topic{name: name.clone(), type_str: type_str, properties: properties, received: false};
+
+        // A value we set before the topic existed becomes its entry
+        let received = match store.remove_pending(&name) {
+            Some(mut entry) => {
+                match entry_id(id) {
+                    Some(entry_id) if topic.is_cached() => {
+                        entry.id = entry_id;
+                        store.insert(entry);
+                    },
+                    Some(_) => (),
+                    None => self.log_error(bad_topic_id(id).with_key(name.clone())),
+                }
+                true
+            },
+            None => false,
+        };
+        let deleted = store.take_deleted(&name);
+        topics.ids.insert(name, id);
+        topics.by_id.insert(id, Topic{received: received, ..topic});
+        if deleted {
+            // Deleted while the publish was out
+            topics.unpublish(id);
         }
     }
 
+    fn handle_unannounce(&self, topics: &Mutex<Topics>, name: String, id: i64) {
+        let mut store = self.store.write();
+        let mut topics = topics.lock();
+        let topic = match topics.by_id.remove(&id) {
+            Some(topic) => topic,
+            None => return self.log_error(NtError::new(InvalidMessage(format!("Unannounced unknown topic id={}.", id)))
+                                          .with_key(name)),
+        };
+        topics.ids.remove(&name);
+        topics.pubuids.remove(&name);
+
+        let stored = entry_id(id).and_then(|id| store.remove_by_id(id)).is_some();
+        if stored || (topic.received && !topic.is_cached()) {
+            self.listeners.notify(Deleted(name));
+        }
+    }
+
+    fn handle_properties(&self, topics: &Mutex<Topics>, name: String, update: json::JsonObject) {
+        let mut store = self.store.write();
+        let mut topics = topics.lock();
+        let id = match topics.ids.get(&name) {
+            Some(id) => *id,
+            None => return,
+        };
+        let topic = topics.by_id.get_mut(&id).unwrap();
+        nt4::merge_properties(&mut topic.properties, &update);
+        self.drop_if_uncached(&mut *store, topic, id);
+        self.listeners.notify(PropertiesUpdated(name, update));
+    }
+
+    /// Removes the stored value of the topic with `id` if it's no longer
+    /// cached, telling listeners it's deleted. Its next value is then
+    /// added again.
+    fn drop_if_uncached(&self, store: &mut Store, topic: &mut Topic, id: i64) {
+        if topic.is_cached() { return }
+        if entry_id(id).and_then(|id| store.remove_by_id(id)).is_some() {
+            topic.received = false;
+            self.listeners.notify(Deleted(topic.name.clone()));
+        }
+    }
+
+    fn handle_nt4_values(&self, topics: &Mutex<Topics>, bytes: &[u8]) -> NtResult<()> {
+        let values = try!(nt4::parse_values(bytes));
+
+        let mut store = self.store.write();
+        let mut topics = topics.lock();
+        for value in values.into_iter() {
+            if value.id == nt4::TIME_SYNC_ID {
+                self.handle_time_sync(value);
+                continue
+            }
+            let topic = match topics.by_id.get_mut(&value.id) {
+                Some(topic) => topic,
+                None => {
+                    self.log_error(NtError::new(InvalidMessage(format!("Value for unknown topic id={}.", value.id))));
+                    continue
+                },
+            };
+            let decoded = match nt4::decode_value(value.type_id, &value.value) {
+                Some(decoded) => decoded,
+                None => continue,
+            };
+            let name = topic.name.clone();
+            let received = topic.received;
+            topic.received = true;
+            let timestamp = received_now(Some(value.timestamp));
+
+            // Uncached values go straight to listeners
+            if !topic.is_cached() {
+                self.listeners.notify(if received { Updated(name, decoded) } else { Added(name, decoded) });
+                continue
+            }
+            let id = match entry_id(value.id) {
+                Some(id) => id,
+                None => { self.log_error(bad_topic_id(value.id).with_key(name)); continue },
+            };
+            if let Some(entry) = store.get_by_id_mut(id) {
+                entry.value = decoded.clone();
+                entry.sequence.increment();
+                entry.timestamp = timestamp;
+                self.listeners.notify(Updated(name, decoded));
+                continue
+            }
+            let mut entry = protocol::Entry{
+                name: name.clone(),
+                id: id,
+                sequence: protocol::SequenceNumber(0u16),
+                flags: if nt4::flag(&topic.properties, "persistent", false) { protocol::FLAG_PERSISTENT } else { 0 },
+                value: decoded.clone(),
+                timestamp: timestamp,
+            };
+            entry.sequence.increment();
+            store.insert(entry);
+            self.listeners.notify(Added(name, decoded));
+        }
+        Ok(())
+    }
+
+    /// Estimates the server's clock from a time sync reply, assuming
+    /// the reply took half of the round trip.
+    fn handle_time_sync(&self, value: nt4::ValueMessage) {
+        let sent = match value.value {
+            msgpack::Int(sent) => sent,
+            _ => return self.log_error(NtError::new(InvalidMessage("Time sync without a send time.".to_string()))),
+        };
+        let now = local_us();
+        *self.time_offset.lock() = Some(value.timestamp + (now - sent) / 2 - now);
+    }
+
+    /// Falls back to the older version the server speaks instead of
+    /// ours. The server hangs up after refusing a version, so the
+    /// client reconnects and says hello again with the older one.
+    fn handle_version_unsupported<R: Reader>(&self, r: &mut R) -> NtResult<()> {
+        let supported = try!(r.read_be_u16());
+        if supported >= self.version() || supported < protocol::VERSION {
+            return Err(NtError::new(UnsupportedVersion(supported)))
+        }
+        let (connection, hello_bytes) = try!(Client::connect(self.address.as_slice(), supported));
+        self.stats.lock().record_sent(protocol::HELLO, hello_bytes);
+
+        // Nothing has been sent yet, but some of what's queued may need
+        // the newer version
+        self.send_queue.lock().retain(|m| protocol::can_send(m, supported));
+        // Hold the state so a close can't miss the new connection
+        let state = self.state.lock();
+        if *state != Initializing {
+            return Err(NtError::new(UnsupportedVersion(supported)))
+        }
+        *self.version.lock() = supported;
+        let mut old = mem::replace(&mut *self.connection.lock(), connection);
+        let _ = old.close_read();
+        let _ = old.close_write();
+        Ok(())
+    }
+
+    /// Finishes the handshake. NT3 servers are told the client's half is
+    /// done too, after the entries set while saying hello, which are
+    /// already queued.
     fn handle_hello_complete(&self) {
+        let mut queue = self.send_queue.lock();
         let mut state = self.state.lock();
         if *state == Initializing {
+            if self.version() >= protocol::VERSION_3 {
+                queue.push(ClientHelloComplete);
+            }
             *state = Connected;
         }
     }
 
-    fn handle_entry_assignment(&self) {
-        let mut connection = self.clone_connection();
-        let entry = match protocol::parse_assignment(&mut connection) {
-            Ok(e) => e,
-            Err(e) => return self.log_fatal(e),
-        };
+    fn handle_entry_assignment<R: Reader>(&self, r: &mut R) -> NtResult<()> {
+        let entry = try!(protocol::parse_assignment(r, self.version()));
         
-        let mut names = self.entries_by_name.lock();
-        if names.contains_key(&entry.name) {
-            self.log_error(NtError{kind: KeyAlreadyExists(entry.name)});
-            return
+        let mut store = self.store.write();
+        if let Some(existing) = store.get_assigned(&entry.name) {
+            let (key, id) = (entry.name.clone(), entry.id);
+            self.log_error(NtError::new(KeyAlreadyExists(existing.clone(), entry))
+                           .with_message(protocol::ENTRY_ASSIGNMENT).with_key(key).with_id(id));
+            return Ok(())
+        }
+
+        if let Some(existing) = store.get_by_id(entry.id) {
+            let (key, id) = (entry.name.clone(), entry.id);
+            self.log_error(NtError::new(IdAlreadyExists(existing.clone(), entry))
+                           .with_message(protocol::ENTRY_ASSIGNMENT).with_key(key).with_id(id));
+            return Ok(())
         }
 
-        let mut ids = self.entries_by_id.lock();
-        if ids.contains_key(&entry.id) {
-            self.log_error(NtError{kind: IdAlreadyExists(entry.id)});
-            return
+        // Reconcile with our own request for this key, if we made one
+        let mut entry = entry;
+        entry.timestamp = received_now(None);
+        let mut queue = self.send_queue.lock();
+        if store.take_deleted(&entry.name) && self.check_nt3("delete entries").is_ok() {
+            // Deleted while the request was out
+            queue.push(Delete(entry.id));
+            return Ok(())
+        }
+        if let Some(local) = store.remove_pending(&entry.name) {
+            // Someone else may have assigned the key before our request went out
+            queue.retain(|m| !is_request_for(m, &entry.name));
+            // Send on anything set since the request went out, unless the
+            // server's entry has another type, which it keeps
+            if !protocol::same_type(&local.value, &entry.value) {
+                let (key, id) = (entry.name.clone(), entry.id);
+                self.log_error(NtError::new(TypeMismatch(key.clone()))
+                               .with_message(protocol::ENTRY_ASSIGNMENT).with_key(key).with_id(id));
+            } else if local.value != entry.value {
+                entry.value = local.value;
+                entry.timestamp = local.timestamp;
+                entry.sequence.increment();
+                queue.push(Update(entry.clone()));
+            }
         }
 
-        let (name, id) = (entry.name.clone(), entry.id.clone());
-        names.insert(name, entry.clone());
-        ids.insert(id, entry);
+        let name = entry.name.clone();
+        store.insert(entry.clone());
+        self.listeners.notify(Added(name, entry.value));
+        Ok(())
     }
 
-    fn handle_entry_update(&self) {
-        let mut connection = self.clone_connection();
-        let entry = match protocol::parse_update(&mut connection, |id| self.id_lookup(id)) {
-            Ok(e) => e,
-            Err(e) => return self.log_fatal(e)
-        };
+    fn handle_entry_update<R: Reader>(&self, r: &mut R) -> NtResult<()> {
+        let mut entry = try!(protocol::parse_update(r, self.version(), |id| self.id_lookup(id)));
         
-        let mut names = self.entries_by_name.lock();
-        let mut ids = self.entries_by_id.lock();
+        let mut store = self.store.write();
 
         // Test sequence numbers
         let name = entry.name.clone();
         {
             // Limit the scope of borrowing
-            let old_entry = match names.get(&name) {
+            let old_entry = match store.get_by_id(entry.id) {
                 Some(e) => e,
                 None => // TODO: Handle more gracefully? Name must exist to update unless entry got corrupted.
                     panic!("No entry exists to update with name={}", entry.name),
             };
-            if old_entry.sequence >= entry.sequence {
-                self.log_error(NtError{kind: OutOfOrderSequenceNumbers(old_entry.sequence, entry.sequence)});
-                return
+            if !entry.sequence.is_newer_than(&old_entry.sequence) {
+                self.stats.lock().out_of_order += 1;
+                self.log_error(NtError::new(OutOfOrderSequenceNumbers(old_entry.sequence, entry.sequence))
+                               .with_message(protocol::ENTRY_UPDATE).with_key(name).with_id(entry.id));
+                return Ok(())
             }
+            // Updates don't carry flags
+            entry.flags = old_entry.flags;
         }
+        entry.timestamp = received_now(None);
+
+        store.insert(entry.clone());
+        self.listeners.notify(Updated(name, entry.value));
+        Ok(())
+    }
+
+    fn handle_flags_update<R: Reader>(&self, r: &mut R) -> NtResult<()> {
+        let id = try!(r.read_be_u16());
+        let flags = try!(r.read_u8());
 
-        names.insert(name, entry.clone());
-        let id = entry.id.clone();
-        ids.insert(id, entry);
+        let mut store = self.store.write();
+        let name = match store.get_by_id_mut(id) {
+            Some(entry) => {
+                entry.flags = flags;
+                entry.name.clone()
+            },
+            None => {
+                self.log_error(NtError::new(IdDoesntExist(id)).with_message(protocol::ENTRY_FLAGS_UPDATE).with_id(id));
+                return Ok(())
+            },
+        };
+        self.listeners.notify(FlagsUpdated(name, flags));
+        Ok(())
+    }
+
+    fn handle_entry_delete<R: Reader>(&self, r: &mut R) -> NtResult<()> {
+        let id = try!(r.read_be_u16());
+
+        let mut store = self.store.write();
+        let entry = match store.remove_by_id(id) {
+            Some(entry) => entry,
+            None => {
+                self.log_error(NtError::new(IdDoesntExist(id)).with_message(protocol::ENTRY_DELETE).with_id(id));
+                return Ok(())
+            },
+        };
+        // Drop anything we were about to send for it
+        self.send_queue.lock().retain(|m| !is_update_for(m, id));
+        self.listeners.notify(Deleted(entry.name));
+        Ok(())
+    }
+
+    fn handle_clear_all<R: Reader>(&self, r: &mut R) -> NtResult<()> {
+        if try!(r.read_be_u32()) != protocol::CLEAR_ALL_MAGIC {
+            return Ok(())
+        }
+
+        let mut store = self.store.write();
+        let names = store.clear();
+        self.send_queue.lock().retain(|m| match *m {
+            Assignment(_) | Update(_) | Delete(_) => false,
+            _ => true,
+        });
+        for name in names.into_iter() {
+            self.listeners.notify(Deleted(name));
+        }
+        Ok(())
+    }
+
+    fn handle_rpc_response<R: Reader>(&self, r: &mut R) -> NtResult<()> {
+        let id = try!(r.read_be_u16());
+        let uid = try!(r.read_be_u16());
+        let result = try!(protocol::parse_raw(r, self.version()));
+
+        match self.rpc_calls.lock().waiting.remove(&(id, uid)) {
+            // The caller may have stopped waiting
+            Some((tx, _)) => { let _ = tx.send_opt(result); },
+            None => self.log_error(NtError::new(UnknownRpcCall(id, uid))
+                                   .with_message(protocol::RPC_RESPONSE).with_id(id)),
+        }
+        Ok(())
     }
 
     fn get_entry(&self, key: String) -> Option<protocol::EntryType> {
-        let names = self.entries_by_name.lock();
-        match names.get(&key) {
+        let store = self.store.read();
+        match store.get(&key) {
             Some(entry) => {
                 Some((*entry).value.clone())
             },
@@ -243,29 +969,98 @@ impl Client {
         }
     }
     
-    fn set_entry(&self, key: String, value: protocol::EntryType) -> NtResult<()> {
-        let names = self.entries_by_name.lock();
+    /// Sets the entry with `key` to `value`, whatever its type, as the
+    /// `Set` impls do. Fails if the value can't be sent over the
+    /// connection's version, or the entry holds another type.
+    pub fn set_entry(&self, key: String, value: protocol::EntryType) -> NtResult<()> {
+        try!(check_entry(&key, &value, self.version()));
+        let mut store = self.store.write();
+        try!(check_type(&*store, &key, &value));
         let mut queue = self.send_queue.lock();
-        let mut entry = match names.get(&key) {
-            Some(entry) => entry.clone(),
-                // TODO: Assert that values have the same type or Err(NtError{kind: ???})
-            None => protocol::Entry{
-                name: key,
+        let event = self.queue_entry(&mut *store, &mut *queue, key, value);
+        self.listeners.notify(event);
+        Ok(())
+    }
+
+    /// Applies a local set to the store straight away, so it's visible
+    /// to gets before the server sees it, and queues it to be sent.
+    /// Updates from the server only replace it once their sequence
+    /// number is newer.
+    fn queue_entry(&self, store: &mut Store, queue: &mut Vec<Message>,
+                   key: String, value: protocol::EntryType) -> Event {
+        let timestamp = received_now(self.server_time());
+        if self.is_uncached(&key) {
+            // Sent like a request for an id, but never stored
+            queue.push(Assignment(protocol::Entry{
+                name: key.clone(),
                 id: protocol::CLIENT_REQUEST_ID,
-                sequence: protocol::SequenceNumber(0u16),
-                value: protocol::Boolean(false),
-            },
+                sequence: protocol::SequenceNumber(1u16),
+                flags: 0,
+                value: value.clone(),
+                timestamp: timestamp,
+            }));
+            return Updated(key, value)
+        }
+
+        if let Some(entry) = store.get_assigned_mut(&key) {
+            entry.value = value.clone();
+            entry.sequence.increment();
+            entry.timestamp = timestamp;
+            // Replace an update that hasn't been sent yet rather than
+            // sending both, since only the latest value matters.
+            let queued = queue.iter_mut().find(|m| match **m {
+                Update(ref queued) => queued.id == entry.id,
+                _ => false,
+            });
+            match queued {
+                Some(queued) => {
+                    *queued = Update(entry.clone());
+                    self.stats.lock().coalesced += 1;
+                },
+                None => queue.push(Update(entry.clone())),
+            }
+            return Updated(key, value)
+        }
+
+        // Only request one id per key. Later values replace the request
+        // if it hasn't been sent, otherwise they are sent as an update
+        // once the id is assigned.
+        if let Some(entry) = store.get_pending_mut(&key) {
+            entry.value = value.clone();
+            entry.timestamp = timestamp;
+            for queued in queue.iter_mut() {
+                if is_request_for(queued, &key) {
+                    *queued = Assignment(entry.clone());
+                }
+            }
+            return Updated(key, value)
+        }
+
+        let mut entry = protocol::Entry{
+            name: key.clone(),
+            id: protocol::CLIENT_REQUEST_ID,
+            sequence: protocol::SequenceNumber(0u16),
+            flags: 0,
+            value: value.clone(),
+            timestamp: timestamp,
         };
-        
-        entry.value = value;
         entry.sequence.increment();
-        queue.push(entry);
-        Ok(())
+        store.insert_pending(entry.clone());
+        queue.push(Assignment(entry));
+        Added(key, value)
+    }
+
+    /// Whether `key` is an NT4 topic that isn't cached.
+    fn is_uncached(&self, key: &String) -> bool {
+        match self.topics {
+            Some(ref topics) => topics.lock().get(key).map_or(false, |topic| !topic.is_cached()),
+            None => false,
+        }
     }
 
     fn id_lookup(&self, id: u16) -> Option<(String, protocol::EntryType)> {
-        let ids = self.entries_by_id.lock();
-        let entry = match ids.get(&id) {
+        let store = self.store.read();
+        let entry = match store.get_by_id(id) {
             Some(entry) => entry.clone(),
             None => return None,
         };
@@ -273,6 +1068,7 @@ impl Client {
     }
 
     fn log_fatal(&self, err: NtError) {
+        self.rpc_calls.lock().waiting.clear();
         match self.get_state() {
             Closed | Error(_) => self.log_error(err),
             Initializing | Connected => {
@@ -288,6 +1084,95 @@ impl Client {
     }
 }
 
+impl Table for Client {
+    fn add_listener(&self, listener: Sender<Event>) {
+        self.listeners.add(listener)
+    }
+
+    fn snapshot(&self, prefix: &str) -> Snapshot {
+        self.store.read().snapshot(prefix)
+    }
+
+    /// Removes the entry locally and tells the server to delete it. An
+    /// entry that hasn't been assigned an id yet is removed along with
+    /// its request for one, or deleted once assigned if the request has
+    /// already gone out. Deletes are only part of NT3, so this fails
+    /// over NT2.
+    fn delete(&self, key: String) -> NtResult<()> {
+        let mut store = self.store.write();
+        if store.get(&key).is_none() { return Ok(()) }
+        try!(self.check_nt3("delete entries").map_err(|e| e.with_key(key.clone())));
+        let entry = store.remove(&key).unwrap();
+
+        let mut queue = self.send_queue.lock();
+        match entry.id {
+            protocol::CLIENT_REQUEST_ID => {
+                if queue.iter().any(|m| is_request_for(m, &key)) {
+                    queue.retain(|m| !is_request_for(m, &key));
+                } else {
+                    store.mark_deleted(key.clone());
+                }
+            },
+            id => {
+                queue.retain(|m| !is_update_for(m, id));
+                queue.push(Delete(id));
+            },
+        }
+        self.listeners.notify(Deleted(key));
+        Ok(())
+    }
+
+    /// Removes every entry locally and tells the server to delete them
+    /// all. Anything queued to be sent for the entries is dropped, and
+    /// entries whose request for an id already went out are deleted
+    /// once assigned, like `delete` does. Fails over NT2.
+    fn delete_all(&self) -> NtResult<()> {
+        try!(self.check_nt3("delete entries"));
+        let mut store = self.store.write();
+        let mut queue = self.send_queue.lock();
+        for name in store.pending_names().into_iter() {
+            if !queue.iter().any(|m| is_request_for(m, &name)) {
+                store.mark_deleted(name);
+            }
+        }
+        let names = store.clear();
+        queue.retain(|m| match *m {
+            Assignment(_) | Update(_) | Delete(_) => false,
+            _ => true,
+        });
+        queue.push(ClearAll(protocol::CLEAR_ALL_MAGIC));
+        for name in names.into_iter() {
+            self.listeners.notify(Deleted(name));
+        }
+        Ok(())
+    }
+
+    fn batch(&self, f: |&Batch|) -> NtResult<()> {
+        let batch = Batch::new();
+        f(&batch);
+        // Either the whole batch is queued or none of it is
+        let entries = batch.into_entries();
+        let version = self.version();
+        for &(ref key, ref value) in entries.iter() {
+            try!(check_entry(key, value, version));
+        }
+
+        // Queue everything under one lock so it all goes in the same send
+        let mut store = self.store.write();
+        for &(ref key, ref value) in entries.iter() {
+            try!(check_type(&*store, key, value));
+        }
+        let mut queue = self.send_queue.lock();
+        let events: Vec<Event> = entries.into_iter()
+            .map(|(key, value)| self.queue_entry(&mut *store, &mut *queue, key, value))
+            .collect();
+        for event in events.into_iter() {
+            self.listeners.notify(event);
+        }
+        Ok(())
+    }
+}
+
 impl Get<bool> for Client {
     fn get(&self, key: String) -> Option<bool> {
         match self.get_entry(key) {
@@ -315,6 +1200,15 @@ impl Get<String> for Client {
     }
 }
 
+impl Get<Vec<u8>> for Client {
+    fn get(&self, key: String) -> Option<Vec<u8>> {
+        match self.get_entry(key) {
+            Some(protocol::Raw(bytes)) => Some(bytes),
+            _ => None,
+        }
+    }
+}
+
 impl Set<bool> for Client {
     fn set(&self, key: String, value: bool) -> NtResult<()> {
         self.set_entry(key, protocol::Boolean(value))
@@ -333,3 +1227,137 @@ impl Set<String> for Client {
     }
 }
 
+impl Set<Vec<u8>> for Client {
+    fn set(&self, key: String, value: Vec<u8>) -> NtResult<()> {
+        self.set_entry(key, protocol::Raw(value))
+    }
+}
+
+impl Topics {
+    fn get(&self, name: &String) -> Option<&Topic> {
+        match self.ids.get(name) {
+            Some(id) => self.by_id.get(id),
+            None => None,
+        }
+    }
+
+    /// Encodes the value of `entry` to send, publishing the topic first
+    /// if this client doesn't yet. Returns `None` if the value doesn't
+    /// fit the topic's type.
+    fn value_for(&mut self, entry: &protocol::Entry) -> Option<nt4::ValueMessage> {
+        let type_str = match self.get(&entry.name) {
+            Some(topic) => topic.type_str.clone(),
+            None => match nt4::type_str(&entry.value) {
+                Some(type_str) => type_str.to_string(),
+                None => return None,
+            },
+        };
+        let (type_id, value) = match nt4::encode_value(type_str.as_slice(), &entry.value) {
+            Some(encoded) => encoded,
+            None => return None,
+        };
+
+        let existing = self.pubuids.get(&entry.name).map(|pubuid| *pubuid);
+        let pubuid = match existing {
+            Some(pubuid) => pubuid,
+            None => {
+                let pubuid = self.next_pubuid;
+                self.next_pubuid += 1;
+                self.pubuids.insert(entry.name.clone(), pubuid);
+                let mut properties = TreeMap::new();
+                if entry.flags & protocol::FLAG_PERSISTENT != 0 {
+                    properties.insert("persistent".to_string(), json::Boolean(true));
+                }
+                self.text_queue.push(nt4::Publish(entry.name.clone(), pubuid, type_str, properties));
+                pubuid
+            },
+        };
+        // Values set before the clocks were synced are stamped by the server
+        let timestamp = entry.timestamp.server_us.unwrap_or(0);
+        Some(nt4::ValueMessage{id: pubuid, timestamp: timestamp, type_id: type_id, value: value})
+    }
+
+    fn set_persistent(&mut self, id: i64, persistent: bool) {
+        let mut update = TreeMap::new();
+        update.insert("persistent".to_string(), json::Boolean(persistent));
+        if let Some(topic) = self.by_id.get_mut(&id) {
+            nt4::merge_properties(&mut topic.properties, &update);
+            self.text_queue.push(nt4::SetProperties(topic.name.clone(), update));
+        }
+    }
+
+    /// Stops publishing the topic with `id`, if this client publishes it.
+    fn unpublish(&mut self, id: i64) {
+        let name = match self.by_id.get(&id) {
+            Some(topic) => topic.name.clone(),
+            None => return,
+        };
+        if let Some(pubuid) = self.pubuids.remove(&name) {
+            self.text_queue.push(nt4::Unpublish(pubuid));
+        }
+    }
+}
+
+impl Topic {
+    fn is_cached(&self) -> bool { nt4::flag(&self.properties, "cached", true) }
+}
+
+/// A timestamp for a value stored now, published at `server_us`.
+fn received_now(server_us: Option<i64>) -> protocol::Timestamp {
+    protocol::Timestamp{local_ns: precise_time_ns(), server_us: server_us}
+}
+
+/// The local clock in microseconds, as used for time syncs.
+fn local_us() -> i64 { (precise_time_ns() / 1000) as i64 }
+
+/// A time sync carrying the time it was sent, which the server echoes
+/// back with its own time.
+fn time_sync_frame() -> NtResult<websocket::Frame> {
+    let sync = nt4::ValueMessage{id: nt4::TIME_SYNC_ID, timestamp: 0, type_id: nt4::TYPE_INT,
+                                 value: msgpack::Int(local_us())};
+    Ok(websocket::Binary(try!(nt4::write_values([sync].as_slice()))))
+}
+
+/// The entry id of the NT4 topic with `id`, if it fits in one.
+fn entry_id(id: i64) -> Option<u16> {
+    if id >= 0 && id < protocol::CLIENT_REQUEST_ID as i64 { Some(id as u16) } else { None }
+}
+
+fn bad_topic_id(id: i64) -> NtError {
+    NtError::new(InvalidMessage(format!("Topic id={} doesn't fit in an entry id.", id)))
+}
+
+/// Fails if `key` or `value` is too long to send with `version`, or
+/// `version` doesn't have the value's type, so it's rejected before
+/// being queued.
+fn check_entry(key: &String, value: &protocol::EntryType, version: u16) -> NtResult<()> {
+    protocol::check_length(key.len(), version)
+        .and(protocol::check_value(value, version))
+        .map_err(|e| e.with_key(key.clone()))
+}
+
+/// Fails with `TypeMismatch` if `key` holds a value of another type,
+/// whether or not the server has assigned it yet.
+fn check_type(store: &Store, key: &String, value: &protocol::EntryType) -> NtResult<()> {
+    match store.get(key) {
+        Some(entry) if !protocol::same_type(&entry.value, value) =>
+            Err(NtError::new(TypeMismatch(key.clone())).with_key(key.clone())),
+        _ => Ok(()),
+    }
+}
+
+/// Whether `message` is a queued request for an id for `name`.
+fn is_request_for(message: &Message, name: &String) -> bool {
+    match *message {
+        Assignment(ref entry) => entry.id == protocol::CLIENT_REQUEST_ID && entry.name == *name,
+        _ => false,
+    }
+}
+
+/// Whether `message` is a queued update to the entry with `id`.
+fn is_update_for(message: &Message, id: u16) -> bool {
+    match *message {
+        Update(ref entry) => entry.id == id,
+        _ => false,
+    }
+}
@@ -1,65 +1,212 @@
 use super::SequenceNumber;
+use super::protocol::Entry;
 
 use std::error::Error;
 use std::error::FromError;
 use std::io::IoError;
+use std::fmt;
 
 pub type NtResult<T> = Result<T, NtError>;
 
-#[deriving(PartialEq,Eq,Show,Clone)]
+#[deriving(PartialEq,Show,Clone)]
 pub enum NtErrorKind {
     UnsupportedType(u8),
+    UnsupportedMessage(u8),
+    UnsupportedVersion(u16),
     StringConversionError,
-    KeyAlreadyExists(String),
-    IdAlreadyExists(u16),
+    KeyAlreadyExists(Entry, Entry), /* (existing, incoming) */
+    IdAlreadyExists(Entry, Entry), /* (existing, incoming) */
     IdDoesntExist(u16),
     OutOfOrderSequenceNumbers(SequenceNumber, SequenceNumber), /* (old, new) */
     NetworkProblem(IoError),
+    UnsupportedField(String), /* key */
+    KeyDoesntExist(String),
+    TypeMismatch(String), /* key */
+    Leb128Overflow,
+    UnknownRpcCall(u16, u16), /* (id, uid) */
+    ValueTooLarge(uint, uint), /* (length, limit) */
+    HandshakeFailed(String), /* reason */
+    UnsupportedOpcode(u8),
+    InvalidMessage(String), /* reason */
+    RequiresNt4(&'static str), /* operation */
+    OutOfIds,
+    RequiresNt3(&'static str), /* operation */
+    OutOfRpcUids(u16), /* id */
 }
 
-#[deriving(PartialEq,Eq,Show,Clone)]
+/// An error along with the context it occurred in. The context fields
+/// are filled in as the error propagates up, so any of them may be
+/// missing.
+#[deriving(PartialEq,Clone)]
 pub struct NtError {
     pub kind: NtErrorKind,
-    // pub desc: &'static str,
-    // pub detail: Option<String>,
-    // pub cause: Option<Error>,
+    /// The operation that failed, e.g. "writing entry update".
+    pub operation: Option<&'static str>,
+    /// The type of the message being read or written.
+    pub message: Option<u8>,
+    /// The key of the entry involved.
+    pub key: Option<String>,
+    /// The id of the entry involved.
+    pub id: Option<u16>,
+    /// The byte offset into the stream of the start of the message.
+    pub offset: Option<u64>,
+    /// The error that caused this one.
+    pub cause: Option<Box<NtError>>,
+}
+
+impl NtError {
+    pub fn new(kind: NtErrorKind) -> NtError {
+        NtError{kind: kind, operation: None, message: None, key: None, id: None,
+                offset: None, cause: None}
+    }
+
+    pub fn during(mut self, operation: &'static str) -> NtError {
+        self.operation = Some(operation);
+        self
+    }
+
+    pub fn with_message(mut self, message: u8) -> NtError {
+        self.message = Some(message);
+        self
+    }
+
+    pub fn with_key(mut self, key: String) -> NtError {
+        self.key = Some(key);
+        self
+    }
+
+    pub fn with_id(mut self, id: u16) -> NtError {
+        self.id = Some(id);
+        self
+    }
+
+    pub fn at_offset(mut self, offset: u64) -> NtError {
+        self.offset = Some(offset);
+        self
+    }
+
+    pub fn caused_by(mut self, cause: NtError) -> NtError {
+        self.cause = Some(box cause);
+        self
+    }
 }
 
 impl Error for NtError {
     fn description(&self) -> &str {
         match self.kind {
             UnsupportedType(_) => "Unsupported entry type.",
+            UnsupportedMessage(_) => "Unsupported message type.",
+            UnsupportedVersion(_) => "Unsupported protocol version.",
             StringConversionError => "Error parsing string.",
-            KeyAlreadyExists(_) => "Key already exists.",
-            IdAlreadyExists(_) => "ID already exists.",
+            KeyAlreadyExists(_, _) => "Key already exists.",
+            IdAlreadyExists(_, _) => "ID already exists.",
             IdDoesntExist(_) => "ID Doesn't exists.",
             OutOfOrderSequenceNumbers(_, _) => "Sequence number too old.",
             NetworkProblem(_) => "Problem connecting to server.",
+            UnsupportedField(_) => "Field type can't be stored in a table.",
+            KeyDoesntExist(_) => "Key doesn't exist.",
+            TypeMismatch(_) => "Value has the wrong type.",
+            Leb128Overflow => "LEB128 number doesn't fit in 64 bits.",
+            UnknownRpcCall(_, _) => "Response to an unknown RPC call.",
+            ValueTooLarge(_, _) => "Value is too long to send.",
+            HandshakeFailed(_) => "WebSocket handshake failed.",
+            UnsupportedOpcode(_) => "Unsupported WebSocket opcode.",
+            InvalidMessage(_) => "Invalid NT4 message.",
+            RequiresNt4(_) => "Only supported over NT4.",
+            OutOfIds => "Every entry ID is in use.",
+            RequiresNt3(_) => "Only supported over NT3 or later.",
+            OutOfRpcUids(_) => "Every RPC call UID is in use.",
         }
     }
 
     fn detail(&self) -> Option<String>{
         match self.kind {
             UnsupportedType(entry_type) => Some(format!("Unsupported entry type={}.", entry_type)),
+            UnsupportedMessage(message) => Some(format!("Unsupported message type=0x{:02X}.", message)),
+            UnsupportedVersion(version) => Some(format!("Unsupported protocol version=0x{:04X}.", version)),
             StringConversionError => None,
-            KeyAlreadyExists(ref key) => Some(format!("Key={} already exists.", key)),
-            IdAlreadyExists(id) => Some(format!("ID={} already exists.", id)),
+            KeyAlreadyExists(ref existing, ref incoming) =>
+                Some(format!("Key={} already exists with id={} value={}, received id={} value={}.",
+                             existing.name, existing.id, existing.value, incoming.id, incoming.value)),
+            IdAlreadyExists(ref existing, ref incoming) =>
+                Some(format!("ID={} already exists with key={} value={}, received key={} value={}.",
+                             existing.id, existing.name, existing.value, incoming.name, incoming.value)),
             IdDoesntExist(id) => Some(format!("ID={} Doesn't exists.", id)),
             OutOfOrderSequenceNumbers(old, new) => Some(format!("{} >= {}, should be less than.", old, new)),
             NetworkProblem(ref err) => err.detail(),
+            UnsupportedField(ref key) => Some(format!("Key={} has a type that can't be stored.", key)),
+            KeyDoesntExist(ref key) => Some(format!("Key={} doesn't exist.", key)),
+            TypeMismatch(ref key) => Some(format!("Key={} has the wrong type.", key)),
+            Leb128Overflow => None,
+            UnknownRpcCall(id, uid) => Some(format!("No call with ID={} UID={} is waiting on a response.", id, uid)),
+            ValueTooLarge(length, limit) => Some(format!("Length={} is over the limit of {} bytes.", length, limit)),
+            HandshakeFailed(ref reason) => Some(reason.clone()),
+            UnsupportedOpcode(opcode) => Some(format!("Unsupported opcode=0x{:X}.", opcode)),
+            InvalidMessage(ref reason) => Some(reason.clone()),
+            RequiresNt4(operation) => Some(format!("Can't {} without an NT4 connection.", operation)),
+            OutOfIds => None,
+            RequiresNt3(operation) => Some(format!("Can't {} over an NT2 connection.", operation)),
+            OutOfRpcUids(id) => Some(format!("Every call to ID={} is waiting on a response.", id)),
         }
     }
 
     fn cause(&self) -> Option<&Error> {
         match self.kind {
             NetworkProblem(ref err) => Some(&*err as &Error),
-            _ => None,
+            _ => match self.cause {
+                Some(ref cause) => Some(&**cause as &Error),
+                None => None,
+            },
         }
     }
 }
 
+impl fmt::Show for NtError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.detail() {
+            Some(detail) => try!(write!(f, "{} {}", self.description(), detail)),
+            None => try!(write!(f, "{}", self.description())),
+        }
+        if let Some(operation) = self.operation { try!(write!(f, " While {}.", operation)) }
+        if let Some(message) = self.message { try!(write!(f, " Message=0x{:02X}.", message)) }
+        if let Some(ref key) = self.key { try!(write!(f, " Key={}.", key)) }
+        if let Some(id) = self.id { try!(write!(f, " ID={}.", id)) }
+        if let Some(offset) = self.offset { try!(write!(f, " Offset={}.", offset)) }
+        if let Some(ref cause) = self.cause { try!(write!(f, " Caused by: {}", cause)) }
+        Ok(())
+    }
+}
+
 impl FromError<IoError> for NtError {
     fn from_error(err: IoError) -> NtError {
-        NtError{kind: NetworkProblem(err)}
+        NtError::new(NetworkProblem(err))
+    }
+}
+
+/// Tests
+#[cfg(test)]
+mod test {
+    use super::{NtError, IdDoesntExist, NetworkProblem};
+    use std::error::Error;
+    use std::io::standard_error;
+    use std::io::EndOfFile;
+
+    #[test]
+    fn error_context() {
+        let err = NtError::new(IdDoesntExist(7u16)).with_message(0x11u8).at_offset(42u64);
+        let shown = format!("{}", err);
+        assert!(shown.as_slice().contains("ID=7"));
+        assert!(shown.as_slice().contains("Message=0x11"));
+        assert!(shown.as_slice().contains("Offset=42"));
+    }
+
+    #[test]
+    fn error_cause() {
+        let io = NtError::new(NetworkProblem(standard_error(EndOfFile)));
+        assert!(io.cause().is_some());
+
+        let err = NtError::new(IdDoesntExist(7u16)).caused_by(NtError::new(IdDoesntExist(8u16)));
+        assert_eq!("ID Doesn't exists.", err.cause().unwrap().description());
+        assert!(NtError::new(IdDoesntExist(7u16)).cause().is_none());
     }
 }
@@ -1,14 +1,46 @@
-#![feature(if_let)]
+#![feature(if_let, macro_rules)]
 
-pub use self::client::{Client, Get, Set};
-pub use self::errors::{NtResult, NtError, NtErrorKind, UnsupportedType, StringConversionError,
+extern crate serialize;
+extern crate time;
+
+pub use self::client::{Client, State, Initializing, Connected, Closed};
+pub use self::server::{Server, Nt4Server, RpcHandler};
+pub use self::bridge::Bridge;
+pub use self::table::{Table, Get, Set, LocalTable, Event, Added, Updated, FlagsUpdated,
+                      Deleted, PropertiesUpdated};
+pub use self::snapshot::Snapshot;
+pub use self::batch::Batch;
+pub use self::structs::{TableEncoder, TableDecoder};
+pub use self::binding::Field;
+pub use self::stats::{Stats, MessageStats};
+pub use self::errors::{NtResult, NtError, NtErrorKind, UnsupportedType, UnsupportedMessage,
+                       UnsupportedVersion, StringConversionError,
                        KeyAlreadyExists, IdAlreadyExists, IdDoesntExist, OutOfOrderSequenceNumbers,
-                       NetworkProblem,};
+                       NetworkProblem, UnsupportedField, KeyDoesntExist, TypeMismatch,
+                       Leb128Overflow, UnknownRpcCall, ValueTooLarge, HandshakeFailed,
+                       UnsupportedOpcode, InvalidMessage, RequiresNt4, OutOfIds,
+                       RequiresNt3, OutOfRpcUids};
 pub use sequence_numbers::SequenceNumber;
+pub use protocol::{Entry, EntryType, Timestamp, UNSTAMPED, FLAG_PERSISTENT};
+
+pub mod mock;
 
+#[macro_escape]
+mod binding;
 mod client;
+mod table;
 mod server;
+mod bridge;
+mod store;
+mod snapshot;
+mod batch;
+mod structs;
+mod stats;
+mod limiter;
 mod protocol;
+mod websocket;
+mod msgpack;
+mod nt4;
 mod sequence_numbers;
 mod errors;
 
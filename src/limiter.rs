@@ -0,0 +1,65 @@
+/// A token bucket bounding the bytes sent per second. Tokens refill
+/// continuously up to one second's worth. A send may take more tokens
+/// than are left, putting the bucket into debt, so a queue is always
+/// sent whole; nothing more is sent until the debt is paid off.
+pub struct Limiter {
+    bytes_per_second: u64,
+    tokens: i64,
+    last_ns: u64,
+}
+
+impl Limiter {
+    pub fn new(bytes_per_second: u64, now_ns: u64) -> Limiter {
+        Limiter{bytes_per_second: bytes_per_second, tokens: bytes_per_second as i64, last_ns: now_ns}
+    }
+
+    /// Whether anything may be sent at `now_ns`.
+    pub fn ready(&mut self, now_ns: u64) -> bool {
+        self.refill(now_ns);
+        self.tokens > 0
+    }
+
+    /// Takes the tokens for `bytes` that were just sent.
+    pub fn consume(&mut self, bytes: u64) {
+        self.tokens -= bytes as i64;
+    }
+
+    fn refill(&mut self, now_ns: u64) {
+        if now_ns <= self.last_ns { return }
+        let elapsed = now_ns - self.last_ns;
+        let earned = (elapsed as f64 / 1e9 * self.bytes_per_second as f64) as i64;
+        // Wait for at least a whole token, so frequent checks don't
+        // round every refill down to nothing.
+        if earned == 0 { return }
+        self.tokens = ::std::cmp::min(self.tokens + earned, self.bytes_per_second as i64);
+        self.last_ns = now_ns;
+    }
+}
+
+/// Tests
+#[cfg(test)]
+mod test {
+    use super::Limiter;
+
+    const SECOND: u64 = 1000000000;
+
+    #[test]
+    fn limiter_refills_and_allows_debt() {
+        let mut limiter = Limiter::new(1000, 0);
+        assert!(limiter.ready(0));
+
+        // A send larger than the bucket goes out, then waits off the debt
+        limiter.consume(1500);
+        assert!(!limiter.ready(0));
+        assert!(!limiter.ready(SECOND / 2));
+        assert!(limiter.ready(SECOND / 2 + SECOND / 100));
+
+        // Idle time never earns more than a second's worth
+        let mut limiter = Limiter::new(1000, 0);
+        limiter.consume(1000);
+        assert!(!limiter.ready(0));
+        assert!(limiter.ready(10 * SECOND));
+        limiter.consume(1001);
+        assert!(!limiter.ready(10 * SECOND));
+    }
+}
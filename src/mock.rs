@@ -0,0 +1,176 @@
+use super::protocol;
+use super::NtResult;
+
+pub use super::protocol::{Message, KeepAlive, Hello, VersionUnsupported, HelloComplete,
+                          ServerHello, ClientHelloComplete, Assignment, Update, FlagsUpdate, Delete, ClearAll, ExecuteRpc,
+                          RpcResponse, Boolean, Number, String, Raw, Rpc, VERSION, VERSION_3, CLIENT_REQUEST_ID, CLEAR_ALL_MAGIC, KEEP_ALIVE, HELLO,
+                          ENTRY_ASSIGNMENT, ENTRY_UPDATE};
+
+use std::collections::HashMap;
+use std::io::{Listener, Acceptor, MemWriter};
+use std::io::net::tcp::{TcpListener, TcpAcceptor, TcpStream};
+
+// How long to wait for the client before giving up, so a broken
+// client fails a test rather than hanging it.
+const TIMEOUT_MS: u64 = 2000;
+
+/// A scriptable server for testing clients. It listens on an
+/// ephemeral localhost port and accepts one connection at a time,
+/// sending exactly what the test tells it to.
+///
+/// # Example
+///
+/// ```ignore
+/// let mut server = MockServer::new().unwrap();
+/// let client = Client::new(server.address()).unwrap();
+/// server.handshake().unwrap();
+/// server.send(&Assignment(entry)).unwrap();
+/// assert_eq!(Assignment(expected), server.recv().unwrap());
+/// ```
+pub struct MockServer {
+    acceptor: TcpAcceptor,
+    address: ::std::string::String,
+    stream: Option<TcpStream>,
+    // The protocol version agreed on in the handshake.
+    version: u16,
+    // The entries assigned on the connection, needed to parse updates.
+    entries: HashMap<u16, protocol::Entry>,
+}
+
+impl MockServer {
+    pub fn new() -> NtResult<MockServer> {
+        let listener = try!(TcpListener::bind("127.0.0.1:0"));
+        let mut acceptor = try!(listener.listen());
+        let address = format!("{}", try!(acceptor.socket_name()));
+        Ok(MockServer{
+            acceptor: acceptor,
+            address: address,
+            stream: None,
+            version: VERSION_3,
+            entries: HashMap::new(),
+        })
+    }
+
+    /// The address clients should connect to.
+    pub fn address(&self) -> &str { self.address.as_slice() }
+
+    /// Waits for a client to connect, replacing any current connection.
+    pub fn accept(&mut self) -> NtResult<()> {
+        self.drop_connection();
+        self.acceptor.set_timeout(Some(TIMEOUT_MS));
+        let mut stream = try!(self.acceptor.accept());
+        stream.set_read_timeout(Some(TIMEOUT_MS));
+        self.stream = Some(stream);
+        self.version = VERSION_3;
+        self.entries = HashMap::new();
+        Ok(())
+    }
+
+    /// Accepts a client and completes the hello exchange with it over
+    /// NT3, returning once the client says its half is done.
+    pub fn handshake(&mut self) -> NtResult<()> {
+        try!(self.accept());
+        self.expect_hello(VERSION_3)
+    }
+
+    /// Accepts a client and has it fall back to NT2, as a server that
+    /// only speaks NT2 would: the NT3 hello is refused, and the client
+    /// reconnects to say hello again.
+    pub fn handshake_nt2(&mut self) -> NtResult<()> {
+        try!(self.accept());
+        match try!(self.recv()) {
+            Hello(VERSION_3, _) => try!(self.send(&VersionUnsupported(VERSION))),
+            m => panic!("Expected Hello({}), received {}", VERSION_3, m),
+        }
+        try!(self.accept());
+        self.version = VERSION;
+        self.expect_hello(VERSION)
+    }
+
+    fn expect_hello(&mut self, version: u16) -> NtResult<()> {
+        match try!(self.recv()) {
+            Hello(v, _) if v == version => (),
+            m => panic!("Expected Hello({}), received {}", version, m),
+        }
+        if version < VERSION_3 {
+            return self.send(&HelloComplete)
+        }
+        try!(self.send(&ServerHello(0, "mock".to_string())));
+        try!(self.send(&HelloComplete));
+        match try!(self.recv_skipping_keep_alives()) {
+            ClientHelloComplete => Ok(()),
+            m => panic!("Expected ClientHelloComplete, received {}", m),
+        }
+    }
+
+    pub fn send(&mut self, message: &Message) -> NtResult<()> {
+        if let Assignment(ref entry) = *message {
+            self.entries.insert(entry.id, entry.clone());
+        }
+        let bytes = try!(encode(message, self.version));
+        self.send_raw(bytes.as_slice())
+    }
+
+    /// Sends bytes as is, for messages the codec can't produce.
+    pub fn send_raw(&mut self, bytes: &[u8]) -> NtResult<()> {
+        Ok(try!(self.stream().write(bytes)))
+    }
+
+    /// Sends only the first `len` bytes of a message, as if the
+    /// connection broke part way through the frame.
+    pub fn send_truncated(&mut self, message: &Message, len: uint) -> NtResult<()> {
+        let bytes = try!(encode(message, self.version));
+        assert!(len < bytes.len(), "Message is only {} bytes", bytes.len());
+        self.send_raw(bytes.slice_to(len))
+    }
+
+    /// Receives the next `len` bytes from the client as is, for checking
+    /// exactly what was sent.
+    pub fn recv_raw(&mut self, len: uint) -> NtResult<Vec<u8>> {
+        Ok(try!(self.stream().read_exact(len)))
+    }
+
+    /// Receives the next message from the client.
+    pub fn recv(&mut self) -> NtResult<Message> {
+        let (entries, version) = (&self.entries, self.version);
+        let stream = match self.stream {
+            Some(ref mut stream) => stream,
+            None => panic!("No client is connected"),
+        };
+        protocol::parse_message(stream, version, |id| match entries.get(&id) {
+            Some(entry) => Some((entry.name.clone(), entry.value.clone())),
+            None => None,
+        })
+    }
+
+    /// Receives the next message from the client that isn't a keep alive.
+    pub fn recv_skipping_keep_alives(&mut self) -> NtResult<Message> {
+        loop {
+            match try!(self.recv()) {
+                KeepAlive => continue,
+                m => return Ok(m),
+            }
+        }
+    }
+
+    /// Closes the connection to the client without any warning.
+    pub fn drop_connection(&mut self) {
+        if let Some(mut stream) = self.stream.take() {
+            let _ = stream.close_read();
+            let _ = stream.close_write();
+        }
+    }
+
+    fn stream(&mut self) -> &mut TcpStream {
+        match self.stream {
+            Some(ref mut stream) => stream,
+            None => panic!("No client is connected"),
+        }
+    }
+}
+
+fn encode(message: &Message, version: u16) -> NtResult<Vec<u8>> {
+    let mut w = MemWriter::new();
+    try!(protocol::write_message(&mut w, message, version));
+    Ok(w.unwrap())
+}
@@ -0,0 +1,259 @@
+use super::{NtResult, NtError, UnsupportedType, StringConversionError, InvalidMessage};
+
+use std::cmp;
+
+/// A [MessagePack](https://github.com/msgpack/msgpack/blob/master/spec.md)
+/// value, as carried by NT4 binary frames. Only the types NT4 uses are
+/// supported; extension types are rejected.
+#[deriving(Show, Clone, PartialEq)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    /// Any integer. Unsigned 64 bit numbers above `i64::MAX` aren't
+    /// supported.
+    Int(i64),
+    F32(f32),
+    F64(f64),
+    Str(String),
+    Bin(Vec<u8>),
+    Array(Vec<Value>),
+    Map(Vec<(Value, Value)>),
+}
+
+// Nesting deeper than this is rejected, so a crafted value can't
+// overflow the stack.
+const MAX_DEPTH: uint = 32;
+
+/// Writes `value` in its smallest encoding.
+pub fn write_value<W: Writer>(w: &mut W, value: &Value) -> NtResult<()> {
+    match *value {
+        Nil => try!(w.write_u8(0xC0)),
+        Bool(b) => try!(w.write_u8(if b { 0xC3 } else { 0xC2 })),
+        Int(n) => try!(write_int(w, n)),
+        F32(f) => {
+            try!(w.write_u8(0xCA));
+            try!(w.write_be_f32(f));
+        },
+        F64(f) => {
+            try!(w.write_u8(0xCB));
+            try!(w.write_be_f64(f));
+        },
+        Str(ref s) => {
+            try!(write_length(w, s.len(), 0xA0, 31, [0xD9, 0xDA, 0xDB]));
+            try!(w.write(s.as_bytes()));
+        },
+        Bin(ref bytes) => {
+            try!(write_length(w, bytes.len(), 0x00, 0, [0xC4, 0xC5, 0xC6]));
+            try!(w.write(bytes.as_slice()));
+        },
+        Array(ref values) => {
+            // There's no 8 bit array length
+            try!(write_length(w, values.len(), 0x90, 15, [0xDC, 0xDC, 0xDD]));
+            for value in values.iter() {
+                try!(write_value(w, value));
+            }
+        },
+        Map(ref pairs) => {
+            try!(write_length(w, pairs.len(), 0x80, 15, [0xDE, 0xDE, 0xDF]));
+            for &(ref key, ref value) in pairs.iter() {
+                try!(write_value(w, key));
+                try!(write_value(w, value));
+            }
+        },
+    }
+    Ok(())
+}
+
+fn write_int<W: Writer>(w: &mut W, n: i64) -> NtResult<()> {
+    match n {
+        0 ... 0x7F => try!(w.write_u8(n as u8)),
+        -32 ... -1 => try!(w.write_u8(n as u8)),
+        0x80 ... 0xFF => { try!(w.write_u8(0xCC)); try!(w.write_u8(n as u8)) },
+        0x100 ... 0xFFFF => { try!(w.write_u8(0xCD)); try!(w.write_be_u16(n as u16)) },
+        0x10000 ... 0xFFFFFFFF => { try!(w.write_u8(0xCE)); try!(w.write_be_u32(n as u32)) },
+        -0x80 ... -33 => { try!(w.write_u8(0xD0)); try!(w.write_i8(n as i8)) },
+        -0x8000 ... -0x81 => { try!(w.write_u8(0xD1)); try!(w.write_be_i16(n as i16)) },
+        -0x80000000 ... -0x8001 => { try!(w.write_u8(0xD2)); try!(w.write_be_i32(n as i32)) },
+        n if n > 0 => { try!(w.write_u8(0xCF)); try!(w.write_be_u64(n as u64)) },
+        n => { try!(w.write_u8(0xD3)); try!(w.write_be_i64(n)) },
+    }
+    Ok(())
+}
+
+/// Writes the marker and length of a string, binary, array or map:
+/// `fixed` with the length in its low bits when it's at most
+/// `fixed_max`, otherwise one of `markers` for an 8, 16 or 32 bit
+/// length.
+fn write_length<W: Writer>(w: &mut W, len: uint, fixed: u8, fixed_max: uint, markers: [u8, ..3])
+                           -> NtResult<()> {
+    if len <= fixed_max && fixed_max > 0 {
+        return Ok(try!(w.write_u8(fixed | len as u8)))
+    }
+    if len <= 0xFF && markers[0] != markers[1] {
+        try!(w.write_u8(markers[0]));
+        return Ok(try!(w.write_u8(len as u8)))
+    }
+    if len <= 0xFFFF {
+        try!(w.write_u8(markers[1]));
+        return Ok(try!(w.write_be_u16(len as u16)))
+    }
+    try!(w.write_u8(markers[2]));
+    Ok(try!(w.write_be_u32(len as u32)))
+}
+
+/// Reads a single value.
+pub fn read_value<R: Reader>(r: &mut R) -> NtResult<Value> {
+    read_nested(r, 0)
+}
+
+fn read_nested<R: Reader>(r: &mut R, depth: uint) -> NtResult<Value> {
+    if depth > MAX_DEPTH {
+        return Err(NtError::new(InvalidMessage("MessagePack value nested too deeply".to_string())))
+    }
+    let marker = try!(r.read_u8());
+    let value = match marker {
+        0x00 ... 0x7F => Int(marker as i64),
+        0x80 ... 0x8F => try!(read_map(r, (marker & 0x0F) as uint, depth)),
+        0x90 ... 0x9F => try!(read_array(r, (marker & 0x0F) as uint, depth)),
+        0xA0 ... 0xBF => try!(read_str(r, (marker & 0x1F) as uint)),
+        0xC0 => Nil,
+        0xC2 => Bool(false),
+        0xC3 => Bool(true),
+        0xC4 => Bin(try!(read_bytes(r, try!(r.read_u8()) as uint))),
+        0xC5 => Bin(try!(read_bytes(r, try!(r.read_be_u16()) as uint))),
+        0xC6 => Bin(try!(read_bytes(r, try!(r.read_be_u32()) as uint))),
+        0xCA => F32(try!(r.read_be_f32())),
+        0xCB => F64(try!(r.read_be_f64())),
+        0xCC => Int(try!(r.read_u8()) as i64),
+        0xCD => Int(try!(r.read_be_u16()) as i64),
+        0xCE => Int(try!(r.read_be_u32()) as i64),
+        0xCF => match try!(r.read_be_u64()) {
+            n if n > ::std::i64::MAX as u64 => return Err(NtError::new(UnsupportedType(marker))),
+            n => Int(n as i64),
+        },
+        0xD0 => Int(try!(r.read_i8()) as i64),
+        0xD1 => Int(try!(r.read_be_i16()) as i64),
+        0xD2 => Int(try!(r.read_be_i32()) as i64),
+        0xD3 => Int(try!(r.read_be_i64())),
+        0xD9 => try!(read_str(r, try!(r.read_u8()) as uint)),
+        0xDA => try!(read_str(r, try!(r.read_be_u16()) as uint)),
+        0xDB => try!(read_str(r, try!(r.read_be_u32()) as uint)),
+        0xDC => try!(read_array(r, try!(r.read_be_u16()) as uint, depth)),
+        0xDD => try!(read_array(r, try!(r.read_be_u32()) as uint, depth)),
+        0xDE => try!(read_map(r, try!(r.read_be_u16()) as uint, depth)),
+        0xDF => try!(read_map(r, try!(r.read_be_u32()) as uint, depth)),
+        0xE0 ... 0xFF => Int(marker as i8 as i64),
+        // Extension types and the unused marker
+        _ => return Err(NtError::new(UnsupportedType(marker))),
+    };
+    Ok(value)
+}
+
+// Lengths come from the input, so nothing is allocated up front; a
+// corrupt length fails at the end of the input instead.
+
+fn read_bytes<R: Reader>(r: &mut R, len: uint) -> NtResult<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut remaining = len;
+    while remaining > 0 {
+        let chunk = cmp::min(remaining, 4096);
+        try!(r.push_exact(&mut bytes, chunk));
+        remaining -= chunk;
+    }
+    Ok(bytes)
+}
+
+fn read_str<R: Reader>(r: &mut R, len: uint) -> NtResult<Value> {
+    match String::from_utf8(try!(read_bytes(r, len))) {
+        Ok(s) => Ok(Str(s)),
+        Err(_) => Err(NtError::new(StringConversionError)),
+    }
+}
+
+fn read_array<R: Reader>(r: &mut R, len: uint, depth: uint) -> NtResult<Value> {
+    let mut values = Vec::new();
+    for _ in range(0, len) {
+        values.push(try!(read_nested(r, depth + 1)));
+    }
+    Ok(Array(values))
+}
+
+fn read_map<R: Reader>(r: &mut R, len: uint, depth: uint) -> NtResult<Value> {
+    let mut pairs = Vec::new();
+    for _ in range(0, len) {
+        let key = try!(read_nested(r, depth + 1));
+        pairs.push((key, try!(read_nested(r, depth + 1))));
+    }
+    Ok(Map(pairs))
+}
+
+/// Tests
+#[cfg(test)]
+mod test {
+    use super::{Value, Nil, Bool, Int, F32, F64, Str, Bin, Array, Map, write_value, read_value};
+    use std::io::{MemWriter, BufReader};
+    use std::rand;
+    use std::rand::Rng;
+
+    fn encode(value: &Value) -> Vec<u8> {
+        let mut w = MemWriter::new();
+        write_value(&mut w, value).unwrap();
+        w.unwrap()
+    }
+
+    fn decode(bytes: &[u8]) -> Value {
+        let mut r = BufReader::new(bytes);
+        let value = read_value(&mut r).unwrap();
+        assert!(r.eof());
+        value
+    }
+
+    #[test]
+    fn msgpack_encodings() {
+        let cases = [(Nil, vec![0xC0u8]), (Bool(true), vec![0xC3]), (Int(0), vec![0x00]),
+                     (Int(127), vec![0x7F]), (Int(128), vec![0xCC, 0x80]), (Int(-1), vec![0xFF]),
+                     (Int(-32), vec![0xE0]), (Int(-33), vec![0xD0, 0xDF]),
+                     (Int(0x10000), vec![0xCE, 0x00, 0x01, 0x00, 0x00]),
+                     (Int(-0x8001), vec![0xD2, 0xFF, 0xFF, 0x7F, 0xFF]),
+                     (F64(1.5), vec![0xCB, 0x3F, 0xF8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]),
+                     (Str("abc".to_string()), vec![0xA3, 0x61, 0x62, 0x63]),
+                     (Bin(vec![0x01]), vec![0xC4, 0x01, 0x01]),
+                     (Array(vec![Int(1), Bool(false)]), vec![0x92, 0x01, 0xC2]),
+                     (Map(vec![(Str("a".to_string()), Nil)]), vec![0x81, 0xA1, 0x61, 0xC0])];
+        for &(ref value, ref bytes) in cases.iter() {
+            assert_eq!(*bytes, encode(value));
+            assert_eq!(*value, decode(bytes.as_slice()));
+        }
+    }
+
+    #[test]
+    fn msgpack_round_trips() {
+        let long = String::from_char(70000, 'x');
+        let values = vec![Int(::std::i64::MAX), Int(::std::i64::MIN), Int(0xFFFFFFFF), Int(-0x80000001),
+                          F32(0.25), Str(String::from_char(31, 'x')), Str(String::from_char(32, 'x')),
+                          Str(long), Bin(Vec::from_elem(300, 0xFF)),
+                          Array(Vec::from_fn(16, |i| Int(i as i64))),
+                          Array(vec![Array(vec![Str("nested".to_string())])])];
+        for value in values.iter() {
+            assert_eq!(*value, decode(encode(value).as_slice()));
+        }
+        for _ in range(0u, 1000) {
+            let value = Int(rand::random::<i64>() >> (rand::random::<uint>() % 64));
+            assert_eq!(value, decode(encode(&value).as_slice()));
+        }
+    }
+
+    #[test]
+    fn fuzz_read_value() {
+        for _ in range(0u, 10000) {
+            let len = rand::random::<uint>() % 65;
+            let bytes: Vec<u8> = rand::task_rng().gen_iter::<u8>().take(len).collect();
+            let _ = read_value(&mut BufReader::new(bytes.as_slice()));
+        }
+        // Deep nesting is an error, not a stack overflow
+        let deep = Vec::from_elem(10000, 0x91u8);
+        assert!(read_value(&mut BufReader::new(deep.as_slice())).is_err());
+        // As is a length longer than the input
+        assert!(read_value(&mut BufReader::new([0xDDu8, 0xFF, 0xFF, 0xFF, 0xFF].as_slice())).is_err());
+    }
+}
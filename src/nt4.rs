@@ -0,0 +1,468 @@
+use super::{NtResult, NtError, InvalidMessage};
+use super::msgpack;
+use super::protocol;
+
+use serialize::json;
+use serialize::json::{Json, JsonObject};
+use std::collections::TreeMap;
+use std::io::{MemWriter, BufReader};
+
+/// Protocol constants
+
+// The id used by binary frames that synchronize clocks rather than
+// carry a topic's value.
+pub const TIME_SYNC_ID: i64 = -1;
+
+// Subscription update intervals, in seconds.
+pub const DEFAULT_PERIODIC: f64 = 0.1;
+pub const MIN_PERIODIC: f64 = 0.005;
+
+// The data type ids used in binary frames.
+pub const TYPE_BOOLEAN: u8 = 0;
+pub const TYPE_DOUBLE: u8 = 1;
+pub const TYPE_INT: u8 = 2;
+pub const TYPE_FLOAT: u8 = 3;
+pub const TYPE_STRING: u8 = 4;
+pub const TYPE_RAW: u8 = 5;
+pub const TYPE_BOOLEAN_ARRAY: u8 = 16;
+pub const TYPE_DOUBLE_ARRAY: u8 = 17;
+pub const TYPE_INT_ARRAY: u8 = 18;
+pub const TYPE_FLOAT_ARRAY: u8 = 19;
+pub const TYPE_STRING_ARRAY: u8 = 20;
+
+/// The binary frame type id for a topic's type string. Types without
+/// their own id, such as "msgpack" or "struct:Pose2d", are sent raw.
+pub fn type_id(type_str: &str) -> u8 {
+    match type_str {
+        "boolean" => TYPE_BOOLEAN,
+        "double" => TYPE_DOUBLE,
+        "int" => TYPE_INT,
+        "float" => TYPE_FLOAT,
+        "string" | "json" => TYPE_STRING,
+        "boolean[]" => TYPE_BOOLEAN_ARRAY,
+        "double[]" => TYPE_DOUBLE_ARRAY,
+        "int[]" => TYPE_INT_ARRAY,
+        "float[]" => TYPE_FLOAT_ARRAY,
+        "string[]" => TYPE_STRING_ARRAY,
+        _ => TYPE_RAW,
+    }
+}
+
+/// The type string a new topic is published with for `value`, or
+/// `None` for RPCs, which NT4 doesn't have.
+pub fn type_str(value: &protocol::EntryType) -> Option<&'static str> {
+    match *value {
+        protocol::Boolean(_) => Some("boolean"),
+        protocol::Number(_) => Some("double"),
+        protocol::String(_) => Some("string"),
+        protocol::Raw(_) => Some("raw"),
+        protocol::Rpc(_) => None,
+    }
+}
+
+/// Encodes `value` for a topic of type `type_str`, returning the type
+/// id to send it with, or `None` if the value doesn't fit the topic.
+/// Numbers are converted to whichever numeric type the topic has.
+pub fn encode_value(type_str: &str, value: &protocol::EntryType) -> Option<(u8, msgpack::Value)> {
+    let type_id = type_id(type_str);
+    let encoded = match (type_id, value) {
+        (TYPE_BOOLEAN, &protocol::Boolean(b)) => msgpack::Bool(b),
+        (TYPE_DOUBLE, &protocol::Number(n)) => msgpack::F64(n),
+        (TYPE_FLOAT, &protocol::Number(n)) => msgpack::F32(n as f32),
+        (TYPE_INT, &protocol::Number(n)) => msgpack::Int(n as i64),
+        (TYPE_STRING, &protocol::String(ref s)) => msgpack::Str(s.clone()),
+        (TYPE_RAW, &protocol::Raw(ref bytes)) => msgpack::Bin(bytes.clone()),
+        _ => return None,
+    };
+    Some((type_id, encoded))
+}
+
+/// Decodes a value sent with `type_id` into an entry value. Every
+/// numeric type becomes a number; arrays have no entry type, so they
+/// decode to `None`.
+pub fn decode_value(type_id: u8, value: &msgpack::Value) -> Option<protocol::EntryType> {
+    let number = match *value {
+        msgpack::Int(n) => Some(n as f64),
+        msgpack::F32(n) => Some(n as f64),
+        msgpack::F64(n) => Some(n),
+        _ => None,
+    };
+    match (type_id, value) {
+        (TYPE_BOOLEAN, &msgpack::Bool(b)) => Some(protocol::Boolean(b)),
+        (TYPE_DOUBLE, _) | (TYPE_FLOAT, _) | (TYPE_INT, _) => number.map(protocol::Number),
+        (TYPE_STRING, &msgpack::Str(ref s)) => Some(protocol::String(s.clone())),
+        (TYPE_RAW, &msgpack::Bin(ref bytes)) => Some(protocol::Raw(bytes.clone())),
+        _ => None,
+    }
+}
+
+/// How a subscriber wants topics and values sent.
+#[deriving(Show, Clone, PartialEq)]
+pub struct SubscriptionOptions {
+    /// The interval values are sent at, in seconds.
+    pub periodic: f64,
+    /// Send every value rather than only the latest one each interval.
+    pub all: bool,
+    /// Only announce the topics, without sending values.
+    pub topics_only: bool,
+    /// Match topics whose names start with the subscription's names,
+    /// rather than only exact names.
+    pub prefix: bool,
+}
+
+impl SubscriptionOptions {
+    pub fn new() -> SubscriptionOptions {
+        SubscriptionOptions{periodic: DEFAULT_PERIODIC, all: false, topics_only: false, prefix: false}
+    }
+
+    /// Whether a subscription to `topics` with these options covers the
+    /// topic `name`.
+    pub fn matches(&self, topics: &[String], name: &str) -> bool {
+        topics.iter().any(|t| match self.prefix {
+            true => name.starts_with(t.as_slice()),
+            false => name == t.as_slice(),
+        })
+    }
+}
+
+/// A message a client sends in a text frame.
+#[deriving(Show, Clone, PartialEq)]
+pub enum ClientMessage {
+    Publish(String, i64, String, JsonObject), /* (name, pubuid, type, properties) */
+    Unpublish(i64), /* pubuid */
+    SetProperties(String, JsonObject), /* (name, update) */
+    Subscribe(i64, Vec<String>, SubscriptionOptions), /* (subuid, topics, options) */
+    Unsubscribe(i64), /* subuid */
+}
+
+/// A message a server sends in a text frame.
+#[deriving(Show, Clone, PartialEq)]
+pub enum ServerMessage {
+    Announce(String, i64, String, Option<i64>, JsonObject), /* (name, id, type, pubuid, properties) */
+    Unannounce(String, i64), /* (name, id) */
+    Properties(String, bool, JsonObject), /* (name, ack, update) */
+}
+
+/// A topic's value, as sent in binary frames. Clients send values with
+/// the pubuid they published with as the id, and servers with the
+/// topic's id.
+#[deriving(Show, Clone, PartialEq)]
+pub struct ValueMessage {
+    pub id: i64,
+    /// Microseconds on the server's clock, or the client's when a
+    /// client sends a time sync.
+    pub timestamp: i64,
+    pub type_id: u8,
+    pub value: msgpack::Value,
+}
+
+/// Whether the boolean property `name` is set, or `default` if it's
+/// missing.
+pub fn flag(properties: &JsonObject, name: &str, default: bool) -> bool {
+    match properties.get(&name.to_string()) {
+        Some(&json::Boolean(b)) => b,
+        _ => default,
+    }
+}
+
+/// Applies a properties update: null values delete a property, and
+/// anything else sets it.
+pub fn merge_properties(properties: &mut JsonObject, update: &JsonObject) {
+    for (name, value) in update.iter() {
+        match *value {
+            json::Null => { properties.remove(name); },
+            ref value => { properties.insert(name.clone(), value.clone()); },
+        }
+    }
+}
+
+pub fn write_client_messages(messages: &[ClientMessage]) -> String {
+    let list = messages.iter().map(|message| match *message {
+        Publish(ref name, pubuid, ref type_str, ref properties) =>
+            method("publish", vec![("name", json::String(name.clone())), ("pubuid", json::I64(pubuid)),
+                                   ("type", json::String(type_str.clone())),
+                                   ("properties", json::Object(properties.clone()))]),
+        Unpublish(pubuid) => method("unpublish", vec![("pubuid", json::I64(pubuid))]),
+        SetProperties(ref name, ref update) =>
+            method("setproperties", vec![("name", json::String(name.clone())),
+                                         ("update", json::Object(update.clone()))]),
+        Subscribe(subuid, ref topics, ref options) => {
+            let options = object(vec![("periodic", json::F64(options.periodic)),
+                                      ("all", json::Boolean(options.all)),
+                                      ("topicsonly", json::Boolean(options.topics_only)),
+                                      ("prefix", json::Boolean(options.prefix))]);
+            method("subscribe", vec![("topics", json::List(topics.iter().map(|t| json::String(t.clone())).collect())),
+                                     ("subuid", json::I64(subuid)), ("options", options)])
+        },
+        Unsubscribe(subuid) => method("unsubscribe", vec![("subuid", json::I64(subuid))]),
+    }).collect();
+    json::List(list).to_string()
+}
+
+/// Parses the messages in a client's text frame. Messages with unknown
+/// methods are skipped, so newer clients can still talk to this server.
+pub fn parse_client_messages(text: &str) -> NtResult<Vec<ClientMessage>> {
+    let mut messages = Vec::new();
+    for (method, params) in try!(parse_methods(text)).into_iter() {
+        let message = match method.as_slice() {
+            "publish" => Publish(try!(string(&params, "name")), try!(int(&params, "pubuid")),
+                                 try!(string(&params, "type")),
+                                 try!(optional_object(&params, "properties"))),
+            "unpublish" => Unpublish(try!(int(&params, "pubuid"))),
+            "setproperties" => SetProperties(try!(string(&params, "name")),
+                                             try!(optional_object(&params, "update"))),
+            "subscribe" => {
+                let topics: Vec<String> = match params.get(&"topics".to_string()) {
+                    Some(&json::List(ref topics)) => topics.iter().filter_map(|t| t.as_string())
+                                                                 .map(|t| t.to_string()).collect(),
+                    _ => return Err(invalid("subscribe without topics")),
+                };
+                let options = try!(optional_object(&params, "options"));
+                let mut parsed = SubscriptionOptions::new();
+                if let Some(periodic) = options.get(&"periodic".to_string()).and_then(|p| p.as_f64()) {
+                    parsed.periodic = if periodic < MIN_PERIODIC { MIN_PERIODIC } else { periodic };
+                }
+                parsed.all = flag(&options, "all", false);
+                parsed.topics_only = flag(&options, "topicsonly", false);
+                parsed.prefix = flag(&options, "prefix", false);
+                Subscribe(try!(int(&params, "subuid")), topics, parsed)
+            },
+            "unsubscribe" => Unsubscribe(try!(int(&params, "subuid"))),
+            _ => continue,
+        };
+        messages.push(message);
+    }
+    Ok(messages)
+}
+
+pub fn write_server_messages(messages: &[ServerMessage]) -> String {
+    let list = messages.iter().map(|message| match *message {
+        Announce(ref name, id, ref type_str, pubuid, ref properties) => {
+            let mut params = vec![("name", json::String(name.clone())), ("id", json::I64(id)),
+                                  ("type", json::String(type_str.clone())),
+                                  ("properties", json::Object(properties.clone()))];
+            if let Some(pubuid) = pubuid {
+                params.push(("pubuid", json::I64(pubuid)));
+            }
+            method("announce", params)
+        },
+        Unannounce(ref name, id) =>
+            method("unannounce", vec![("name", json::String(name.clone())), ("id", json::I64(id))]),
+        Properties(ref name, ack, ref update) =>
+            method("properties", vec![("name", json::String(name.clone())), ("ack", json::Boolean(ack)),
+                                      ("update", json::Object(update.clone()))]),
+    }).collect();
+    json::List(list).to_string()
+}
+
+/// Parses the messages in a server's text frame, skipping unknown
+/// methods.
+pub fn parse_server_messages(text: &str) -> NtResult<Vec<ServerMessage>> {
+    let mut messages = Vec::new();
+    for (method, params) in try!(parse_methods(text)).into_iter() {
+        let message = match method.as_slice() {
+            "announce" => Announce(try!(string(&params, "name")), try!(int(&params, "id")),
+                                   try!(string(&params, "type")),
+                                   params.get(&"pubuid".to_string()).and_then(|p| p.as_i64()),
+                                   try!(optional_object(&params, "properties"))),
+            "unannounce" => Unannounce(try!(string(&params, "name")), try!(int(&params, "id"))),
+            "properties" => Properties(try!(string(&params, "name")),
+                                       params.get(&"ack".to_string()).and_then(|a| a.as_boolean())
+                                             .unwrap_or(false),
+                                       try!(optional_object(&params, "update"))),
+            _ => continue,
+        };
+        messages.push(message);
+    }
+    Ok(messages)
+}
+
+/// Writes values as the body of one binary frame.
+pub fn write_values(values: &[ValueMessage]) -> NtResult<Vec<u8>> {
+    let mut w = MemWriter::new();
+    for value in values.iter() {
+        try!(msgpack::write_value(&mut w, &msgpack::Array(vec![
+            msgpack::Int(value.id), msgpack::Int(value.timestamp),
+            msgpack::Int(value.type_id as i64), value.value.clone()])));
+    }
+    Ok(w.unwrap())
+}
+
+/// Parses the values in the body of a binary frame.
+pub fn parse_values(bytes: &[u8]) -> NtResult<Vec<ValueMessage>> {
+    let mut r = BufReader::new(bytes);
+    let mut values = Vec::new();
+    while !r.eof() {
+        match try!(msgpack::read_value(&mut r)) {
+            msgpack::Array(mut fields) => {
+                if fields.len() != 4 { return Err(invalid("value without 4 fields")) }
+                let value = fields.pop().unwrap();
+                match (&fields[0], &fields[1], &fields[2]) {
+                    (&msgpack::Int(id), &msgpack::Int(timestamp), &msgpack::Int(type_id))
+                        if type_id >= 0 && type_id <= 0xFF =>
+                        values.push(ValueMessage{id: id, timestamp: timestamp, type_id: type_id as u8,
+                                                 value: value}),
+                    _ => return Err(invalid("value with a non-integer id, timestamp or type")),
+                }
+            },
+            _ => return Err(invalid("value that isn't an array")),
+        }
+    }
+    Ok(values)
+}
+
+fn invalid(reason: &str) -> NtError {
+    NtError::new(InvalidMessage(format!("Received a {}.", reason)))
+}
+
+fn object(fields: Vec<(&str, Json)>) -> Json {
+    let mut object = TreeMap::new();
+    for (name, value) in fields.into_iter() {
+        object.insert(name.to_string(), value);
+    }
+    json::Object(object)
+}
+
+fn method(method: &str, params: Vec<(&str, Json)>) -> Json {
+    object(vec![("method", json::String(method.to_string())), ("params", object(params))])
+}
+
+/// Splits a text frame into its methods and their parameters.
+fn parse_methods(text: &str) -> NtResult<Vec<(String, JsonObject)>> {
+    let list = match json::from_str(text) {
+        Ok(json::List(list)) => list,
+        Ok(_) => return Err(invalid("text frame that isn't a JSON array")),
+        Err(e) => return Err(NtError::new(InvalidMessage(format!("Received invalid JSON: {}.", e)))),
+    };
+    let mut methods = Vec::new();
+    for message in list.into_iter() {
+        let mut message = match message {
+            json::Object(message) => message,
+            _ => return Err(invalid("message that isn't a JSON object")),
+        };
+        let method = try!(string(&message, "method"));
+        let params = match message.remove(&"params".to_string()) {
+            Some(json::Object(params)) => params,
+            _ => return Err(invalid("message without params")),
+        };
+        methods.push((method, params));
+    }
+    Ok(methods)
+}
+
+fn string(params: &JsonObject, name: &str) -> NtResult<String> {
+    match params.get(&name.to_string()) {
+        Some(&json::String(ref s)) => Ok(s.clone()),
+        _ => Err(NtError::new(InvalidMessage(format!("Expected a string {}.", name)))),
+    }
+}
+
+fn int(params: &JsonObject, name: &str) -> NtResult<i64> {
+    match params.get(&name.to_string()).and_then(|n| n.as_i64()) {
+        Some(n) => Ok(n),
+        None => Err(NtError::new(InvalidMessage(format!("Expected an integer {}.", name)))),
+    }
+}
+
+fn optional_object(params: &JsonObject, name: &str) -> NtResult<JsonObject> {
+    match params.get(&name.to_string()) {
+        Some(&json::Object(ref object)) => Ok(object.clone()),
+        None => Ok(TreeMap::new()),
+        _ => Err(NtError::new(InvalidMessage(format!("Expected an object {}.", name)))),
+    }
+}
+
+/// Tests
+#[cfg(test)]
+mod test {
+    use super::{ClientMessage, Publish, Unpublish, SetProperties, Subscribe, Unsubscribe, Announce,
+                Unannounce, Properties, SubscriptionOptions, ValueMessage, write_client_messages,
+                parse_client_messages, write_server_messages, parse_server_messages, write_values,
+                parse_values, merge_properties, flag, encode_value, decode_value, MIN_PERIODIC,
+                TYPE_DOUBLE, TYPE_INT, TYPE_STRING, TYPE_RAW, TYPE_DOUBLE_ARRAY};
+    use super::super::msgpack;
+    use super::super::protocol;
+    use serialize::json;
+    use std::collections::TreeMap;
+
+    fn properties(pairs: &[(&str, json::Json)]) -> json::JsonObject {
+        let mut properties = TreeMap::new();
+        for &(name, ref value) in pairs.iter() {
+            properties.insert(name.to_string(), value.clone());
+        }
+        properties
+    }
+
+    #[test]
+    fn nt4_text_messages_round_trip() {
+        let options = SubscriptionOptions{periodic: 0.5, all: true, topics_only: false, prefix: true};
+        let client = vec![Publish("/Pose".to_string(), 3, "double[]".to_string(),
+                                  properties(&[("persistent", json::Boolean(true))])),
+                          Unpublish(3), SetProperties("/Pose".to_string(), properties(&[])),
+                          Subscribe(7, vec!["/".to_string()], options), Unsubscribe(7)];
+        assert_eq!(client, parse_client_messages(write_client_messages(client.as_slice()).as_slice()).unwrap());
+
+        let server = vec![Announce("/Pose".to_string(), 1, "double[]".to_string(), Some(3), properties(&[])),
+                          Announce("/Other".to_string(), 2, "int".to_string(), None, properties(&[])),
+                          Unannounce("/Pose".to_string(), 1),
+                          Properties("/Pose".to_string(), true, properties(&[("retained", json::Null)]))];
+        assert_eq!(server, parse_server_messages(write_server_messages(server.as_slice()).as_slice()).unwrap());
+    }
+
+    #[test]
+    fn nt4_parses_client_messages() {
+        let text = r#"[{"method":"subscribe","params":{"topics":["/a"],"subuid":1,
+                        "options":{"periodic":0.0001,"topicsonly":true}}},
+                       {"method":"future","params":{}},
+                       {"method":"unsubscribe","params":{"subuid":1}}]"#;
+        let messages: Vec<ClientMessage> = parse_client_messages(text).unwrap();
+        match messages[0] {
+            Subscribe(1, ref topics, ref options) => {
+                assert_eq!(vec!["/a".to_string()], *topics);
+                assert_eq!(MIN_PERIODIC, options.periodic);
+                assert!(options.topics_only && !options.all && !options.prefix);
+            },
+            ref m => panic!("Unexpected message {}", m),
+        }
+        // Unknown methods are skipped
+        assert_eq!(Unsubscribe(1), messages[1]);
+
+        assert!(parse_client_messages("{}").is_err());
+        assert!(parse_client_messages(r#"[{"method":"unpublish","params":{}}]"#).is_err());
+        assert!(parse_client_messages("[").is_err());
+    }
+
+    #[test]
+    fn nt4_values_round_trip() {
+        let values = vec![ValueMessage{id: 1, timestamp: 1000, type_id: TYPE_DOUBLE, value: msgpack::F64(1.5)},
+                          ValueMessage{id: -1, timestamp: 0, type_id: 2, value: msgpack::Int(123456789)}];
+        assert_eq!(values, parse_values(write_values(values.as_slice()).unwrap().as_slice()).unwrap());
+
+        assert!(parse_values([0x93u8, 0x01, 0x02, 0x03].as_slice()).is_err());
+        assert!(parse_values([0x94u8, 0xA1, 0x61, 0x02, 0x03, 0x04].as_slice()).is_err());
+    }
+
+    #[test]
+    fn nt4_properties() {
+        let mut current = properties(&[("persistent", json::Boolean(true)), ("unit", json::String("m".to_string()))]);
+        merge_properties(&mut current, &properties(&[("persistent", json::Null), ("retained", json::Boolean(true))]));
+        assert!(!flag(&current, "persistent", false));
+        assert!(flag(&current, "retained", false));
+        assert!(flag(&current, "cached", true));
+        assert_eq!(Some(&json::String("m".to_string())), current.get(&"unit".to_string()));
+    }
+
+    #[test]
+    fn nt4_converts_values() {
+        assert_eq!(Some((TYPE_DOUBLE, msgpack::F64(1.5))), encode_value("double", &protocol::Number(1.5)));
+        assert_eq!(Some((TYPE_INT, msgpack::Int(3))), encode_value("int", &protocol::Number(3.0)));
+        assert_eq!(Some((TYPE_RAW, msgpack::Bin(vec![1]))), encode_value("struct:Pose2d", &protocol::Raw(vec![1])));
+        assert_eq!(None, encode_value("double", &protocol::String("1.5".to_string())));
+
+        assert_eq!(Some(protocol::Number(3.0)), decode_value(TYPE_INT, &msgpack::Int(3)));
+        assert_eq!(Some(protocol::Number(0.5)), decode_value(TYPE_DOUBLE, &msgpack::F32(0.5)));
+        assert_eq!(Some(protocol::String("a".to_string())), decode_value(TYPE_STRING, &msgpack::Str("a".to_string())));
+        assert_eq!(None, decode_value(TYPE_STRING, &msgpack::Int(3)));
+        assert_eq!(None, decode_value(TYPE_DOUBLE_ARRAY, &msgpack::Array(vec![msgpack::F64(1.0)])));
+    }
+}
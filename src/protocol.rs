@@ -1,153 +1,1029 @@
 
-use super::{NtResult, NtError, StringConversionError, UnsupportedType, IdDoesntExist};
+use super::{NtResult, NtError, StringConversionError, UnsupportedType, UnsupportedMessage, IdDoesntExist,
+            Leb128Overflow, ValueTooLarge, TypeMismatch};
 pub use super::sequence_numbers::SequenceNumber;
 
+use std::io::IoResult;
+use std::cmp;
+
 /// Protocol constants
 
 // The version of the protocol currently implemented.
-const VERSION: u16 = 0x0200;
+pub const VERSION: u16 = 0x0200;
+// NetworkTables 3.0, which adds flags to entry assignments among others.
+pub const VERSION_3: u16 = 0x0300;
 
 // ClientRequestID is the id clients use when requesting the server
 // assign an id to the key.
 pub const CLIENT_REQUEST_ID: u16 = 0xFFFF;
 
+// The identity NT3 peers send in their hellos unless given another.
+pub const DEFAULT_IDENTITY: &'static str = "networktables";
+
 // Values used to indicate the various message types used in the
 // NetworkTables protocol.
 pub const KEEP_ALIVE: u8 = 0x00;
 pub const HELLO: u8 = 0x01;
-// pub const VERSION_UNSUPPORTED: u8 = 0x02;
+pub const VERSION_UNSUPPORTED: u8 = 0x02;
 pub const HELLO_COMPLETE: u8 = 0x03;
+pub const SERVER_HELLO: u8 = 0x04;
+pub const CLIENT_HELLO_COMPLETE: u8 = 0x05;
 pub const ENTRY_ASSIGNMENT: u8 = 0x10;
 pub const ENTRY_UPDATE: u8 = 0x11;
+pub const ENTRY_FLAGS_UPDATE: u8 = 0x12;
+pub const ENTRY_DELETE: u8 = 0x13;
+pub const CLEAR_ALL_ENTRIES: u8 = 0x14;
+pub const EXECUTE_RPC: u8 = 0x20;
+pub const RPC_RESPONSE: u8 = 0x21;
+
+// Sent with clear all entries so a corrupted message can't wipe every
+// entry. Clears with any other value must be ignored.
+pub const CLEAR_ALL_MAGIC: u32 = 0xD06CB27A;
+
+// Bits of an entry's flags.
+pub const FLAG_PERSISTENT: u8 = 0x01;
 
 // Types of data that can be sent over NetworkTables.s
 const TYPE_BOOLEAN: u8 = 0x00;
 const TYPE_NUMBER: u8 = 0x01;
 const TYPE_STRING: u8 = 0x02;
+const TYPE_RAW: u8 = 0x03;
+const TYPE_RPC: u8 = 0x20;
 // const TYPE_BOOLEAN_ARRAY: u8 = 0x10;
 // const TYPE_DOUBLE_ARRAY: u8 = 0x11;
 // const TYPE_STRING_ARRAY: u8 = 0x12;
 
 
 /// Entry definition
-#[deriving(Show, Clone)]
+#[deriving(Show, Clone, PartialEq)]
 pub struct Entry {
     pub name: StdString,
     pub id: u16,
     pub sequence: SequenceNumber,
+    /// Only sent in NT3 assignments, see `FLAG_PERSISTENT`.
+    pub flags: u8,
     pub value: EntryType,
+    /// Never sent; a client fills it in as it stores the value.
+    pub timestamp: Timestamp,
+}
+
+/// When a value was produced, as far as the client storing it knows.
+#[deriving(Show, Clone, PartialEq)]
+pub struct Timestamp {
+    /// `precise_time_ns` when the value was received or set locally,
+    /// or 0 if it hasn't been stored.
+    pub local_ns: u64,
+    /// Microseconds on the NT4 server's clock when the value was
+    /// published. NT2 and NT3 don't have one.
+    pub server_us: Option<i64>,
 }
 
+/// The timestamp of a value that hasn't been stored.
+pub const UNSTAMPED: Timestamp = Timestamp{local_ns: 0, server_us: None};
+
 // Since we overloaded the name string, maybe we should have NtString
 // instead. We'll see what makes sense.
 type StdString = ::std::string::String;
 
-#[deriving(Show, Clone)]
+#[deriving(Show, Clone, PartialEq)]
 pub enum EntryType {
     Boolean(bool),
     Number(f64),
     String(StdString),
+    /// NT3 raw bytes, such as a serialized message.
+    Raw(Vec<u8>),
+    /// An NT3 remote procedure, whose value is its definition.
+    Rpc(Vec<u8>),
+}
+
+/// A complete message, for code that handles messages generically
+/// rather than by reading the type byte itself.
+#[deriving(Show, Clone, PartialEq)]
+pub enum Message {
+    KeepAlive,
+    /// The version the client speaks and, from NT3 on, its identity.
+    Hello(u16, StdString),
+    VersionUnsupported(u16),
+    /// The end of the server's half of the handshake.
+    HelloComplete,
+    /// NT3 only. The server's flags, with 0x01 set if it has seen the
+    /// client before, and its identity, sent before its entries.
+    ServerHello(u8, StdString),
+    /// NT3 only. The end of the client's half of the handshake, sent
+    /// after the entries the server didn't have.
+    ClientHelloComplete,
+    Assignment(Entry),
+    Update(Entry),
+    /// The id of an entry and its new flags.
+    FlagsUpdate(u16, u8),
+    /// The id of the entry deleted.
+    Delete(u16),
+    /// The magic value, which must be `CLEAR_ALL_MAGIC` for the
+    /// clear to take effect.
+    ClearAll(u32),
+    /// The id of an RPC entry, a unique id for the call and the
+    /// parameters.
+    ExecuteRpc(u16, u16, Vec<u8>),
+    /// The id of an RPC entry, the id of the call and the result.
+    RpcResponse(u16, u16, Vec<u8>),
+}
+
+impl Message {
+    /// The byte identifying this type of message on the wire.
+    pub fn message_type(&self) -> u8 {
+        match *self {
+            KeepAlive => KEEP_ALIVE,
+            Hello(_, _) => HELLO,
+            VersionUnsupported(_) => VERSION_UNSUPPORTED,
+            HelloComplete => HELLO_COMPLETE,
+            ServerHello(_, _) => SERVER_HELLO,
+            ClientHelloComplete => CLIENT_HELLO_COMPLETE,
+            Assignment(_) => ENTRY_ASSIGNMENT,
+            Update(_) => ENTRY_UPDATE,
+            FlagsUpdate(_, _) => ENTRY_FLAGS_UPDATE,
+            Delete(_) => ENTRY_DELETE,
+            ClearAll(_) => CLEAR_ALL_ENTRIES,
+            ExecuteRpc(_, _, _) => EXECUTE_RPC,
+            RpcResponse(_, _, _) => RPC_RESPONSE,
+        }
+    }
+}
+
+/// The first version of the protocol with messages of type `message`.
+pub fn first_version(message: u8) -> u16 {
+    match message {
+        SERVER_HELLO | CLIENT_HELLO_COMPLETE | ENTRY_FLAGS_UPDATE | ENTRY_DELETE | CLEAR_ALL_ENTRIES |
+        EXECUTE_RPC | RPC_RESPONSE => VERSION_3,
+        _ => VERSION,
+    }
+}
+
+/// Whether `message` can be sent to a peer speaking `version` of the
+/// protocol. Values are checked too, so NT2 peers aren't sent RPC
+/// entries or values too long for them.
+pub fn can_send(message: &Message, version: u16) -> bool {
+    if version < first_version(message.message_type()) { return false }
+    match *message {
+        Assignment(ref entry) | Update(ref entry) => check_value(&entry.value, version).is_ok(),
+        _ => true,
+    }
 }
 
 /// Protocol utilities
-pub fn write_hello<T: Writer>(w: &mut T) -> NtResult<()> {
+
+/// Writes a client hello for `version`. From NT3 on it carries the
+/// client's identity, which older versions leave out.
+pub fn write_hello<T: Writer>(w: &mut T, version: u16, identity: &str) -> NtResult<()> {
     try!(w.write_u8(HELLO));
-    Ok(try!(w.write_be_u16(VERSION)))
+    try!(w.write_be_u16(version));
+    if version >= VERSION_3 {
+        try!(write_string(w, identity.to_string(), version));
+    }
+    Ok(())
+}
+
+/// Parses a client hello, type byte excluded. The identity is empty
+/// before NT3.
+pub fn parse_hello<T: Reader>(r: &mut T) -> NtResult<(u16, StdString)> {
+    let version = try!(r.read_be_u16());
+    let identity = match version {
+        v if v >= VERSION_3 => try!(parse_string(r, version)),
+        _ => StdString::new(),
+    };
+    Ok((version, identity))
+}
+
+/// Writes an NT3 server hello with `flags` and the server's identity.
+pub fn write_server_hello<T: Writer>(w: &mut T, flags: u8, identity: &str) -> NtResult<()> {
+    try!(w.write_u8(SERVER_HELLO));
+    try!(w.write_u8(flags));
+    write_string(w, identity.to_string(), VERSION_3)
+}
+
+/// Parses an NT3 server hello, type byte excluded, into its flags and
+/// the server's identity.
+pub fn parse_server_hello<T: Reader>(r: &mut T) -> NtResult<(u8, StdString)> {
+    let flags = try!(r.read_u8());
+    Ok((flags, try!(parse_string(r, VERSION_3))))
 }
 
 pub fn write_keep_alive<T: Writer>(w: &mut T) -> NtResult<()> {
     Ok(try!(w.write_u8(KEEP_ALIVE)))
 }
 
-pub fn write_assignment<T: Writer>(w: &mut T, entry: &Entry) -> NtResult<()> {
+/// Writes an assignment as `version` of the protocol defines it.
+/// Nothing is written if the name or value is too long for `version`.
+pub fn write_assignment<T: Writer>(w: &mut T, entry: &Entry, version: u16) -> NtResult<()> {
+    if let Err(e) = check_length(entry.name.len(), version).and(check_value(&entry.value, version)) {
+        return Err(e.with_key(entry.name.clone()))
+    }
     try!(w.write_u8(ENTRY_ASSIGNMENT));
-    try!(write_string(w, entry.name.clone()));
-    try!(w.write_u8(match entry.value {
-        Boolean(_) => TYPE_BOOLEAN,
-        Number(_) => TYPE_NUMBER,
-        String(_) => TYPE_STRING,
-    }));
+    try!(write_string(w, entry.name.clone(), version));
+    try!(w.write_u8(type_id(&entry.value)));
     try!(w.write_be_u16(entry.id));
     try!(w.write_be_u16(entry.sequence.as_u16()));
+    if version >= VERSION_3 {
+        try!(w.write_u8(entry.flags));
+    }
     match entry.value {
         Boolean(b) => try!(w.write_u8(match b {true => 0x01u8, false => 0x00u8})),
         Number(n) => try!(w.write_be_f64(n)),
-        String(ref s) => try!(write_string(w, s.clone())),
+        String(ref s) => try!(write_string(w, s.clone(), version)),
+        Raw(ref bytes) => try!(write_raw(w, bytes.as_slice(), version)),
+        Rpc(ref definition) => try!(write_raw(w, definition.as_slice(), version)),
     };
     Ok(())
 }
 
-pub fn parse_assignment<T: Reader>(r: &mut T) -> NtResult<Entry> {
-    let name = try!(parse_string(r));
+/// The type byte an assignment of `value` carries.
+fn type_id(value: &EntryType) -> u8 {
+    match *value {
+        Boolean(_) => TYPE_BOOLEAN,
+        Number(_) => TYPE_NUMBER,
+        String(_) => TYPE_STRING,
+        Raw(_) => TYPE_RAW,
+        Rpc(_) => TYPE_RPC,
+    }
+}
+
+/// Whether two values are of the same type, so one can replace the
+/// other.
+pub fn same_type(a: &EntryType, b: &EntryType) -> bool {
+    type_id(a) == type_id(b)
+}
+
+/// Parses an assignment as `version` of the protocol defines it. Flags
+/// are 0 before NT3.
+pub fn parse_assignment<T: Reader>(r: &mut T, version: u16) -> NtResult<Entry> {
+    let name = try!(parse_string(r, version));
     let typ = try!(r.read_u8());
     let id = try!(r.read_be_u16());
     let seq_number = SequenceNumber(try!(r.read_be_u16()));
+    let flags = match version {
+        v if v >= VERSION_3 => try!(r.read_u8()),
+        _ => 0,
+    };
     let value = match typ {
         TYPE_BOOLEAN => Boolean(try!(r.read_u8()) != 0u8),
         TYPE_NUMBER => Number(try!(r.read_be_f64())),
-        TYPE_STRING => String(try!(parse_string(r))),
-        t => return Err(NtError{kind: UnsupportedType(t)}),
+        TYPE_STRING => String(try!(parse_string(r, version))),
+        TYPE_RAW if version >= VERSION_3 => Raw(try!(parse_raw(r, version))),
+        TYPE_RPC if version >= VERSION_3 => Rpc(try!(parse_raw(r, version))),
+        t => return Err(NtError::new(UnsupportedType(t)).with_key(name).with_id(id)),
     };
-    Ok(Entry{name: name, id: id, sequence: seq_number, value: value})
+    Ok(Entry{name: name, id: id, sequence: seq_number, flags: flags, value: value, timestamp: UNSTAMPED})
 }
 
-pub fn write_update<T: Writer>(w: &mut T, entry: &Entry) -> NtResult<()> {
+/// Writes an update as `version` of the protocol defines it, with the
+/// value's type from NT3 on. Nothing is written if the value is too
+/// long for `version`.
+pub fn write_update<T: Writer>(w: &mut T, entry: &Entry, version: u16) -> NtResult<()> {
+    if let Err(e) = check_value(&entry.value, version) {
+        return Err(e.with_key(entry.name.clone()).with_id(entry.id))
+    }
     try!(w.write_u8(ENTRY_UPDATE));
     try!(w.write_be_u16(entry.id));
     try!(w.write_be_u16(entry.sequence.as_u16()));
+    if version >= VERSION_3 {
+        try!(w.write_u8(type_id(&entry.value)));
+    }
     match entry.value {
         Boolean(b) => try!(w.write_u8(match b {true => 0x01u8, false => 0x00u8})),
         Number(n) => try!(w.write_be_f64(n)),
-        String(ref s) => try!(write_string(w, s.clone())),
+        String(ref s) => try!(write_string(w, s.clone(), version)),
+        Raw(ref bytes) => try!(write_raw(w, bytes.as_slice(), version)),
+        Rpc(ref definition) => try!(write_raw(w, definition.as_slice(), version)),
     };
     Ok(())
 }
 
-pub fn parse_update<T: Reader>(r: &mut T, f: |u16| -> Option<(StdString, EntryType)>)
+/// Parses an update as `version` of the protocol defines it. Updates
+/// don't carry flags, so they are always 0, and raw values and RPCs
+/// are rejected before NT3. From NT3 on updates carry a type, which
+/// must match the entry's.
+pub fn parse_update<T: Reader>(r: &mut T, version: u16, f: |u16| -> Option<(StdString, EntryType)>)
                                -> NtResult<Entry> {
     let id = try!(r.read_be_u16());
     let seq_number = SequenceNumber(try!(r.read_be_u16()));
+    let typ = match version {
+        v if v >= VERSION_3 => Some(try!(r.read_u8())),
+        _ => None,
+    };
     let (name, entry_type) = match f(id) {
         Some((name, entry_type)) => (name, entry_type),
-        None => return Err(NtError{kind: IdDoesntExist(id)}),
+        None => return Err(NtError::new(IdDoesntExist(id)).with_id(id)),
     };
+    if typ.map_or(false, |t| t != type_id(&entry_type)) {
+        return Err(NtError::new(TypeMismatch(name.clone())).with_key(name).with_id(id))
+    }
+    match entry_type {
+        Raw(_) | Rpc(_) if version < VERSION_3 =>
+            return Err(NtError::new(UnsupportedType(type_id(&entry_type))).with_key(name).with_id(id)),
+        _ => (),
+    }
     let value = match entry_type {
         Boolean(_) => Boolean(try!(r.read_u8()) != 0u8),
         Number(_) => Number(try!(r.read_be_f64())),
-        String(_) => String(try!(parse_string(r))),
+        String(_) => String(try!(parse_string(r, version))),
+        Raw(_) => Raw(try!(parse_raw(r, version))),
+        Rpc(_) => Rpc(try!(parse_raw(r, version))),
     };
-    Ok(Entry{name: name, id: id, sequence: seq_number, value: value})
+    Ok(Entry{name: name, id: id, sequence: seq_number, flags: 0, value: value, timestamp: UNSTAMPED})
+}
+
+pub fn write_flags_update<T: Writer>(w: &mut T, id: u16, flags: u8) -> NtResult<()> {
+    try!(w.write_u8(ENTRY_FLAGS_UPDATE));
+    try!(w.write_be_u16(id));
+    Ok(try!(w.write_u8(flags)))
 }
 
-pub fn write_string<T: Writer>(w: &mut T, s: StdString) -> NtResult<()> {
-    try!(w.write_be_u16(s.len() as u16));
-    for byte in s.into_bytes().iter() {
-        try!(w.write_u8(*byte))
+/// Writes a call to the RPC with `id`. Nothing is written if the
+/// parameters are too long for `version`.
+pub fn write_execute_rpc<T: Writer>(w: &mut T, id: u16, uid: u16, params: &[u8], version: u16)
+                                   -> NtResult<()> {
+    if let Err(e) = check_length(params.len(), version) {
+        return Err(e.with_id(id))
     }
-    // TODO: Assert number of bits written == length
-    // TODO: Assert that string length is 16 bits
-    Ok(())
+    try!(w.write_u8(EXECUTE_RPC));
+    try!(w.write_be_u16(id));
+    try!(w.write_be_u16(uid));
+    write_raw(w, params, version)
+}
+
+/// Writes the result of a call to the RPC with `id`. Nothing is
+/// written if the result is too long for `version`.
+pub fn write_rpc_response<T: Writer>(w: &mut T, id: u16, uid: u16, result: &[u8], version: u16)
+                                    -> NtResult<()> {
+    if let Err(e) = check_length(result.len(), version) {
+        return Err(e.with_id(id))
+    }
+    try!(w.write_u8(RPC_RESPONSE));
+    try!(w.write_be_u16(id));
+    try!(w.write_be_u16(uid));
+    write_raw(w, result, version)
+}
+
+pub fn write_delete<T: Writer>(w: &mut T, id: u16) -> NtResult<()> {
+    try!(w.write_u8(ENTRY_DELETE));
+    Ok(try!(w.write_be_u16(id)))
+}
+
+pub fn write_clear_all<T: Writer>(w: &mut T) -> NtResult<()> {
+    try!(w.write_u8(CLEAR_ALL_ENTRIES));
+    Ok(try!(w.write_be_u32(CLEAR_ALL_MAGIC)))
 }
 
-pub fn parse_string<T: Reader>(r: &mut T) -> NtResult<StdString> {
-    let length = try!(r.read_be_u16());
-    let vec = try!(r.read_exact(length as uint));
+/// Writes a message as `version` of the protocol defines it. Nothing is
+/// written if `version` doesn't have the message.
+pub fn write_message<T: Writer>(w: &mut T, message: &Message, version: u16) -> NtResult<()> {
+    let message_type = message.message_type();
+    if version < first_version(message_type) {
+        return Err(NtError::new(UnsupportedMessage(message_type)).with_message(message_type))
+    }
+    match *message {
+        KeepAlive => write_keep_alive(w),
+        Hello(version, ref identity) => write_hello(w, version, identity.as_slice()),
+        VersionUnsupported(version) => {
+            try!(w.write_u8(VERSION_UNSUPPORTED));
+            Ok(try!(w.write_be_u16(version)))
+        },
+        HelloComplete => Ok(try!(w.write_u8(HELLO_COMPLETE))),
+        ServerHello(flags, ref identity) => write_server_hello(w, flags, identity.as_slice()),
+        ClientHelloComplete => Ok(try!(w.write_u8(CLIENT_HELLO_COMPLETE))),
+        Assignment(ref entry) => write_assignment(w, entry, version),
+        Update(ref entry) => write_update(w, entry, version),
+        FlagsUpdate(id, flags) => write_flags_update(w, id, flags),
+        Delete(id) => write_delete(w, id),
+        ClearAll(magic) => {
+            try!(w.write_u8(CLEAR_ALL_ENTRIES));
+            Ok(try!(w.write_be_u32(magic)))
+        },
+        ExecuteRpc(id, uid, ref params) => write_execute_rpc(w, id, uid, params.as_slice(), version),
+        RpcResponse(id, uid, ref result) => write_rpc_response(w, id, uid, result.as_slice(), version),
+    }
+}
+
+/// Parses a message, type byte included, as `version` of the protocol
+/// defines it. `f` looks up the name and type of an id the same way as
+/// for `parse_update`.
+pub fn parse_message<T: Reader>(r: &mut T, version: u16, f: |u16| -> Option<(StdString, EntryType)>)
+                                -> NtResult<Message> {
+    match try!(r.read_u8()) {
+        m if version < first_version(m) => Err(NtError::new(UnsupportedMessage(m)).with_message(m)),
+        KEEP_ALIVE => Ok(KeepAlive),
+        HELLO => {
+            let (version, identity) = try!(parse_hello(r));
+            Ok(Hello(version, identity))
+        },
+        VERSION_UNSUPPORTED => Ok(VersionUnsupported(try!(r.read_be_u16()))),
+        HELLO_COMPLETE => Ok(HelloComplete),
+        SERVER_HELLO => {
+            let (flags, identity) = try!(parse_server_hello(r));
+            Ok(ServerHello(flags, identity))
+        },
+        CLIENT_HELLO_COMPLETE => Ok(ClientHelloComplete),
+        ENTRY_ASSIGNMENT => Ok(Assignment(try!(parse_assignment(r, version)))),
+        ENTRY_UPDATE => Ok(Update(try!(parse_update(r, version, f)))),
+        ENTRY_FLAGS_UPDATE => Ok(FlagsUpdate(try!(r.read_be_u16()), try!(r.read_u8()))),
+        ENTRY_DELETE => Ok(Delete(try!(r.read_be_u16()))),
+        CLEAR_ALL_ENTRIES => Ok(ClearAll(try!(r.read_be_u32()))),
+        EXECUTE_RPC => Ok(ExecuteRpc(try!(r.read_be_u16()), try!(r.read_be_u16()),
+                                     try!(parse_raw(r, version)))),
+        RPC_RESPONSE => Ok(RpcResponse(try!(r.read_be_u16()), try!(r.read_be_u16()),
+                                       try!(parse_raw(r, version)))),
+        m => Err(NtError::new(UnsupportedMessage(m)).with_message(m)),
+    }
+}
+
+/// A `Reader` that counts the bytes read through it, so errors can
+/// report where in the stream they occurred.
+pub struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Reader> CountingReader<R> {
+    pub fn new(inner: R) -> CountingReader<R> {
+        CountingReader{inner: inner, count: 0}
+    }
+
+    /// The number of bytes read so far.
+    pub fn count(&self) -> u64 { self.count }
+}
+
+impl<R: Reader> Reader for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        let n = try!(self.inner.read(buf));
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+/// Writes a UTF-8 string with a length prefix as `version` of the
+/// protocol defines it, see `write_length`.
+pub fn write_string<T: Writer>(w: &mut T, s: StdString, version: u16) -> NtResult<()> {
+    write_raw(w, s.as_bytes(), version)
+}
+
+pub fn parse_string<T: Reader>(r: &mut T, version: u16) -> NtResult<StdString> {
+    let vec = try!(parse_raw(r, version));
     match ::std::string::String::from_utf8(vec) {
         Ok(s) => Ok(s),
-        Err(_) => Err(NtError{kind: StringConversionError}),
+        Err(_) => Err(NtError::new(StringConversionError)),
+    }
+}
+
+/// Writes the length of a string or raw value: a big-endian u16 before
+/// NT3, and LEB128 from NT3 on.
+pub fn write_length<T: Writer>(w: &mut T, length: uint, version: u16) -> NtResult<()> {
+    try!(check_length(length, version));
+    match version {
+        v if v >= VERSION_3 => write_uleb128(w, length as u64),
+        _ => Ok(try!(w.write_be_u16(length as u16))),
+    }
+}
+
+/// The longest string or raw value, in bytes, that `version` of the
+/// protocol can send. NT2 lengths are 16 bits. NT3 lengths have no
+/// fixed size, but are held to 32 bits, which is already far more than
+/// a table is meant to carry.
+pub fn max_length(version: u16) -> uint {
+    match version {
+        v if v >= VERSION_3 => ::std::u32::MAX as uint,
+        _ => ::std::u16::MAX as uint,
+    }
+}
+
+/// Fails with `ValueTooLarge` if a string or raw value of `length`
+/// bytes can't be sent with `version` of the protocol.
+pub fn check_length(length: uint, version: u16) -> NtResult<()> {
+    let limit = max_length(version);
+    match length {
+        l if l > limit => Err(NtError::new(ValueTooLarge(length, limit))),
+        _ => Ok(()),
+    }
+}
+
+/// Checks the length of `value` if it's a string or raw value, and
+/// that `version` has its type.
+pub fn check_value(value: &EntryType, version: u16) -> NtResult<()> {
+    match *value {
+        Raw(_) | Rpc(_) if version < VERSION_3 => Err(NtError::new(UnsupportedType(type_id(value)))),
+        String(ref s) => check_length(s.len(), version),
+        Raw(ref bytes) | Rpc(ref bytes) => check_length(bytes.len(), version),
+        Boolean(_) | Number(_) => Ok(()),
+    }
+}
+
+pub fn parse_length<T: Reader>(r: &mut T, version: u16) -> NtResult<u64> {
+    match version {
+        v if v >= VERSION_3 => read_uleb128(r),
+        _ => Ok(try!(r.read_be_u16()) as u64),
     }
 }
 
+/// Writes `n` as an unsigned LEB128 number: 7 bits at a time, least
+/// significant first, with the high bit set on every byte but the last.
+pub fn write_uleb128<T: Writer>(w: &mut T, mut n: u64) -> NtResult<()> {
+    loop {
+        let byte = (n & 0x7F) as u8;
+        n >>= 7;
+        if n == 0 {
+            return Ok(try!(w.write_u8(byte)))
+        }
+        try!(w.write_u8(byte | 0x80));
+    }
+}
+
+/// Reads an unsigned LEB128 number, failing on numbers that don't fit
+/// in 64 bits rather than silently dropping the high bits.
+pub fn read_uleb128<T: Reader>(r: &mut T) -> NtResult<u64> {
+    let mut n = 0u64;
+    let mut shift = 0u;
+    loop {
+        let byte = try!(r.read_u8());
+        let bits = (byte & 0x7F) as u64;
+        if shift == 63 && bits > 1 || shift > 63 {
+            return Err(NtError::new(Leb128Overflow))
+        }
+        n |= bits << shift;
+        if byte & 0x80 == 0 {
+            return Ok(n)
+        }
+        shift += 7;
+    }
+}
+
+/// Writes raw bytes with a length prefix as `version` of the protocol
+/// defines it, see `write_length`.
+pub fn write_raw<T: Writer>(w: &mut T, bytes: &[u8], version: u16) -> NtResult<()> {
+    try!(write_length(w, bytes.len(), version));
+    Ok(try!(w.write(bytes)))
+}
+
+/// Reads raw bytes with a length prefix. The bytes are read a chunk at
+/// a time, so a corrupt length fails at the end of the input instead of
+/// allocating the whole length up front.
+pub fn parse_raw<T: Reader>(r: &mut T, version: u16) -> NtResult<Vec<u8>> {
+    let mut remaining = try!(parse_length(r, version));
+    let mut bytes = Vec::new();
+    while remaining > 0 {
+        let chunk = cmp::min(remaining, 4096);
+        try!(r.push_exact(&mut bytes, chunk as uint));
+        remaining -= chunk;
+    }
+    Ok(bytes)
+}
+
 /// Tests
 #[cfg(test)]
 mod test {
-    use super::{Entry, Boolean, Number, String};
+    use super::{Entry, EntryType, Boolean, Number, String, ENTRY_ASSIGNMENT, ENTRY_UPDATE, ENTRY_DELETE};
+    use super::{write_assignment, parse_assignment, write_update, parse_update, parse_string};
+    use super::{write_delete, write_clear_all, parse_message, write_message, Delete, ClearAll, CLEAR_ALL_MAGIC};
+    use super::{write_flags_update, FlagsUpdate, VERSION, VERSION_3, FLAG_PERSISTENT, ENTRY_FLAGS_UPDATE};
+    use super::{Raw, Rpc, ExecuteRpc, RpcResponse, write_uleb128, read_uleb128, parse_raw};
+    use super::{write_string, write_raw, can_send, Assignment, Update, UNSTAMPED};
+    use super::{write_execute_rpc, write_rpc_response};
+    use super::{write_hello, Hello, ServerHello, HelloComplete, ClientHelloComplete, SERVER_HELLO};
+    use super::super::{Leb128Overflow, ValueTooLarge, UnsupportedMessage, UnsupportedType, TypeMismatch};
     use super::SequenceNumber;
+    use std::io::{MemWriter, BufReader};
+    use std::rand;
+    use std::rand::Rng;
+    use std::mem;
+    use std::f64;
+
+    fn random_string(max_len: uint) -> ::std::string::String {
+        let len = rand::random::<uint>() % (max_len + 1);
+        rand::task_rng().gen_ascii_chars().take(len).collect()
+    }
+
+    fn random_entry(version: u16) -> Entry {
+        // NT2 only has booleans, numbers and strings
+        let types = if version >= VERSION_3 { 5 } else { 3 };
+        let value = match rand::random::<uint>() % types {
+            // Random bits cover NaNs, infinities and subnormals too
+            0 => Boolean(rand::random()),
+            1 => Number(unsafe { mem::transmute::<u64, f64>(rand::random()) }),
+            2 => String(random_string(1024)),
+            3 => Rpc(random_bytes(1024)),
+            _ => Raw(random_bytes(1024)),
+        };
+        Entry{name: random_string(64), id: rand::random(),
+              sequence: SequenceNumber(rand::random()), flags: rand::random(), value: value,
+              timestamp: UNSTAMPED}
+    }
+
+    fn edge_case_entries(version: u16) -> Vec<Entry> {
+        let max_string = ::std::string::String::from_char(0xFFFF, 'x');
+        let mut values = vec![Boolean(true), Boolean(false),
+                              Number(f64::NAN), Number(f64::INFINITY), Number(f64::NEG_INFINITY),
+                              Number(0f64), Number(-0f64), Number(f64::MAX), Number(f64::MIN),
+                              Number(f64::MIN_POS_VALUE),
+                              String("".into_string()), String("é漢🤖".into_string()),
+                              String(max_string.clone())];
+        if version >= VERSION_3 {
+            values.push(Raw(Vec::new()));
+            values.push(Raw(Vec::from_elem(0x10000, 0xFF)));
+        }
+        let names = vec!["".into_string(), "/SmartDashboard/Value".into_string(), max_string];
+        let mut entries = Vec::new();
+        for name in names.iter() {
+            for value in values.iter() {
+                entries.push(Entry{name: name.clone(), id: 0xFFFEu16, sequence: SequenceNumber(0xFFFFu16),
+                                   flags: 0xFF, value: value.clone(), timestamp: UNSTAMPED});
+            }
+        }
+        entries
+    }
+
+    // Like ==, but NaNs are compared by their bits
+    fn assert_same(expected: &Entry, actual: &Entry) {
+        assert_eq!(expected.name, actual.name);
+        assert_eq!(expected.id, actual.id);
+        assert_eq!(expected.sequence.as_u16(), actual.sequence.as_u16());
+        assert_eq!(expected.flags, actual.flags);
+        match (&expected.value, &actual.value) {
+            (&Number(e), &Number(a)) => unsafe {
+                assert_eq!(mem::transmute::<f64, u64>(e), mem::transmute::<f64, u64>(a))
+            },
+            (e, a) => assert_eq!(e, a),
+        }
+    }
+
+    fn assignment_round_trip(entry: &Entry, version: u16) -> Entry {
+        let mut w = MemWriter::new();
+        write_assignment(&mut w, entry, version).unwrap();
+        let bytes = w.unwrap();
+        let mut r = BufReader::new(bytes.as_slice());
+        assert_eq!(ENTRY_ASSIGNMENT, r.read_u8().unwrap());
+        let parsed = parse_assignment(&mut r, version).unwrap();
+        assert!(r.eof());
+        if version >= VERSION_3 { return parsed }
+        // NT2 assignments don't carry flags
+        assert_eq!(0, parsed.flags);
+        Entry{flags: entry.flags, ..parsed}
+    }
+
+    fn update_round_trip(entry: &Entry, version: u16) -> Entry {
+        let mut w = MemWriter::new();
+        write_update(&mut w, entry, version).unwrap();
+        let bytes = w.unwrap();
+        let mut r = BufReader::new(bytes.as_slice());
+        assert_eq!(ENTRY_UPDATE, r.read_u8().unwrap());
+        let parsed = parse_update(&mut r, version, |id| {
+            assert_eq!(entry.id, id);
+            Some((entry.name.clone(), entry.value.clone()))
+        }).unwrap();
+        assert!(r.eof());
+        assert_eq!(0, parsed.flags);
+        // Updates don't carry flags
+        Entry{flags: entry.flags, ..parsed}
+    }
+
+    fn random_bytes(max_len: uint) -> Vec<u8> {
+        let len = rand::random::<uint>() % (max_len + 1);
+        rand::task_rng().gen_iter::<u8>().take(len).collect()
+    }
+
+    #[test]
+    fn assignment_round_trips() {
+        for &version in [VERSION, VERSION_3].iter() {
+            for entry in edge_case_entries(version).iter() {
+                assert_same(entry, &assignment_round_trip(entry, version));
+            }
+            for _ in range::<int>(0, 1000) {
+                let entry = random_entry(version);
+                assert_same(&entry, &assignment_round_trip(&entry, version));
+            }
+        }
+    }
+
+    #[test]
+    fn update_round_trips() {
+        for &version in [VERSION, VERSION_3].iter() {
+            for entry in edge_case_entries(version).iter() {
+                assert_same(entry, &update_round_trip(entry, version));
+            }
+            for _ in range::<int>(0, 1000) {
+                let entry = random_entry(version);
+                assert_same(&entry, &update_round_trip(&entry, version));
+            }
+        }
+    }
+
+    #[test]
+    fn updates_by_version() {
+        let entry = Entry{name: "/A".into_string(), id: 1, sequence: SequenceNumber(2), flags: 0,
+                          value: Number(2f64), timestamp: UNSTAMPED};
+        fn lookup(id: u16) -> Option<(::std::string::String, EntryType)> {
+            match id {
+                1 => Some(("/A".into_string(), Number(0f64))),
+                _ => None,
+            }
+        }
+
+        // NT2 updates leave the type out, NT3 ones have it after the
+        // sequence number
+        let mut w = MemWriter::new();
+        write_update(&mut w, &entry, VERSION).unwrap();
+        assert_eq!(vec![0x11u8, 0x00, 0x01, 0x00, 0x02, 0x40, 0, 0, 0, 0, 0, 0, 0], w.get_ref().to_vec());
+        let mut w = MemWriter::new();
+        write_update(&mut w, &entry, VERSION_3).unwrap();
+        let bytes = vec![0x11u8, 0x00, 0x01, 0x00, 0x02, 0x01, 0x40, 0, 0, 0, 0, 0, 0, 0];
+        assert_eq!(bytes, w.get_ref().to_vec());
+        let mut r = BufReader::new(bytes.as_slice());
+        assert_eq!(Update(entry.clone()), parse_message(&mut r, VERSION_3, |id| lookup(id)).unwrap());
+
+        // A type other than the entry's is refused
+        let bytes = vec![0x11u8, 0x00, 0x01, 0x00, 0x02, 0x00, 0x01];
+        let mut r = BufReader::new(bytes.as_slice());
+        let err = parse_message(&mut r, VERSION_3, |id| lookup(id)).unwrap_err();
+        assert_eq!(TypeMismatch("/A".into_string()), err.kind);
+    }
+
+    #[test]
+    fn delete_and_clear_all_round_trip() {
+        let mut w = MemWriter::new();
+        write_delete(&mut w, 0x1234).unwrap();
+        write_clear_all(&mut w).unwrap();
+        assert_eq!(vec![0x13u8, 0x12, 0x34, 0x14, 0xD0, 0x6C, 0xB2, 0x7A], w.get_ref().to_vec());
+
+        let bytes = w.unwrap();
+        let mut r = BufReader::new(bytes.as_slice());
+        assert_eq!(Delete(0x1234), parse_message(&mut r, VERSION_3, |_| None).unwrap());
+        assert_eq!(ClearAll(CLEAR_ALL_MAGIC), parse_message(&mut r, VERSION_3, |_| None).unwrap());
+
+        // Neither is part of NT2
+        for message in [Delete(0x1234), ClearAll(CLEAR_ALL_MAGIC)].iter() {
+            assert!(write_message(&mut MemWriter::new(), message, VERSION).is_err());
+        }
+        let mut r = BufReader::new(bytes.as_slice());
+        assert_eq!(UnsupportedMessage(ENTRY_DELETE), parse_message(&mut r, VERSION, |_| None).unwrap_err().kind);
+    }
+
+    #[test]
+    fn uleb128_round_trips() {
+        let cases = [(0u64, vec![0x00u8]), (1, vec![0x01]), (127, vec![0x7F]), (128, vec![0x80, 0x01]),
+                     (300, vec![0xAC, 0x02]), (16383, vec![0xFF, 0x7F]), (16384, vec![0x80, 0x80, 0x01]),
+                     (::std::u64::MAX, vec![0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x01])];
+        for &(n, ref bytes) in cases.iter() {
+            let mut w = MemWriter::new();
+            write_uleb128(&mut w, n).unwrap();
+            assert_eq!(*bytes, w.get_ref().to_vec());
+            assert_eq!(n, read_uleb128(&mut BufReader::new(bytes.as_slice())).unwrap());
+        }
+        for _ in range::<int>(0, 1000) {
+            let n = rand::random::<u64>() >> (rand::random::<uint>() % 64);
+            let mut w = MemWriter::new();
+            write_uleb128(&mut w, n).unwrap();
+            let bytes = w.unwrap();
+            assert_eq!(n, read_uleb128(&mut BufReader::new(bytes.as_slice())).unwrap());
+        }
+
+        // More than 64 bits is an error rather than wrapping
+        let too_big = [0xFFu8, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x02];
+        assert_eq!(Leb128Overflow, read_uleb128(&mut BufReader::new(too_big.as_slice())).unwrap_err().kind);
+        let too_long = [0x80u8, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x00];
+        assert_eq!(Leb128Overflow, read_uleb128(&mut BufReader::new(too_long.as_slice())).unwrap_err().kind);
+        // As is running out of input
+        assert!(read_uleb128(&mut BufReader::new([0x80u8].as_slice())).is_err());
+    }
+
+    #[test]
+    fn rpc_round_trips() {
+        let entry = Entry{name: "/ZeroGyro".into_string(), id: 1, sequence: SequenceNumber(1),
+                          flags: 0, value: Rpc(vec![0x01, 0x02]), timestamp: UNSTAMPED};
+        assert_same(&entry, &assignment_round_trip(&entry, VERSION_3));
+
+        for message in [ExecuteRpc(1, 2, vec![0x03]), RpcResponse(1, 2, Vec::from_elem(200, 0x04))].iter() {
+            let mut w = MemWriter::new();
+            write_message(&mut w, message, VERSION_3).unwrap();
+            let bytes = w.unwrap();
+            let mut r = BufReader::new(bytes.as_slice());
+            assert_eq!(*message, parse_message(&mut r, VERSION_3, |_| None).unwrap());
+            assert!(r.eof());
+        }
+
+        // NT2 has no RPCs
+        let mut w = MemWriter::new();
+        assert_eq!(UnsupportedType(0x20), write_assignment(&mut w, &entry, VERSION).unwrap_err().kind);
+        assert!(write_message(&mut w, &ExecuteRpc(1, 2, vec![0x03]), VERSION).is_err());
+        assert!(w.get_ref().is_empty());
+        assert!(!can_send(&Assignment(entry.clone()), VERSION));
+        assert!(can_send(&Assignment(entry), VERSION_3));
+    }
+
+    #[test]
+    fn raw_values_need_nt3() {
+        let entry = Entry{name: "/Raw".into_string(), id: 1, sequence: SequenceNumber(1),
+                          flags: 0, value: Raw(vec![0x01]), timestamp: UNSTAMPED};
+        let mut w = MemWriter::new();
+        assert_eq!(UnsupportedType(0x03), write_assignment(&mut w, &entry, VERSION).unwrap_err().kind);
+        assert_eq!(UnsupportedType(0x03), write_update(&mut w, &entry, VERSION).unwrap_err().kind);
+        assert!(w.get_ref().is_empty());
+        assert!(!can_send(&Assignment(entry.clone()), VERSION));
+        assert!(can_send(&Assignment(entry), VERSION_3));
+
+        // Nor are they parsed from NT2 peers
+        let assignment = [0x00u8, 0x04, 0x2F, 0x52, 0x61, 0x77, 0x03, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x01];
+        let err = parse_assignment(&mut BufReader::new(assignment.as_slice()), VERSION).unwrap_err();
+        assert_eq!(UnsupportedType(0x03), err.kind);
+        let update = [0x00u8, 0x01, 0x00, 0x02, 0x00, 0x01, 0x01];
+        let err = parse_update(&mut BufReader::new(update.as_slice()), VERSION,
+                               |_| Some(("/Raw".into_string(), Raw(Vec::new())))).unwrap_err();
+        assert_eq!(UnsupportedType(0x03), err.kind);
+    }
+
+    #[test]
+    fn lengths_by_version() {
+        let long = ::std::string::String::from_char(70000, 'x');
+        let cases = [(VERSION, "abc", vec![0x00u8, 0x03]), (VERSION_3, "abc", vec![0x03u8]),
+                     (VERSION_3, long.as_slice(), vec![0xF0u8, 0xA2, 0x04])];
+        for &(version, s, ref prefix) in cases.iter() {
+            let mut w = MemWriter::new();
+            write_string(&mut w, s.into_string(), version).unwrap();
+            let bytes = w.unwrap();
+            assert_eq!(prefix.as_slice(), bytes.slice_to(prefix.len()));
+            assert_eq!(prefix.len() + s.len(), bytes.len());
+            let mut r = BufReader::new(bytes.as_slice());
+            assert_eq!(s, parse_string(&mut r, version).unwrap().as_slice());
+            assert!(r.eof());
+        }
+
+        // Raw values are prefixed the same way
+        let raw = Vec::from_elem(200, 0xFFu8);
+        let mut w = MemWriter::new();
+        write_raw(&mut w, raw.as_slice(), VERSION_3).unwrap();
+        assert_eq!([0xC8u8, 0x01].as_slice(), w.get_ref().slice_to(2));
+        let bytes = w.unwrap();
+        assert_eq!(raw, parse_raw(&mut BufReader::new(bytes.as_slice()), VERSION_3).unwrap());
+    }
+
+    #[test]
+    fn lengths_over_limit() {
+        let long = ::std::string::String::from_char(70000, 'x');
+        let entry = Entry{name: "/Long".into_string(), id: 1, sequence: SequenceNumber(1),
+                          flags: 0, value: String(long.clone()), timestamp: UNSTAMPED};
+
+        // Nothing is written, so the stream isn't left with half a message
+        let mut w = MemWriter::new();
+        let err = write_assignment(&mut w, &entry, VERSION).unwrap_err();
+        assert_eq!(ValueTooLarge(70000, 0xFFFF), err.kind);
+        assert_eq!(Some("/Long".into_string()), err.key);
+        let err = write_update(&mut w, &entry, VERSION).unwrap_err();
+        assert_eq!(ValueTooLarge(70000, 0xFFFF), err.kind);
+        assert!(w.get_ref().is_empty());
+
+        // Including for names
+        let named = Entry{name: long.clone(), value: Boolean(true), ..entry.clone()};
+        let err = write_assignment(&mut w, &named, VERSION).unwrap_err();
+        assert_eq!(ValueTooLarge(70000, 0xFFFF), err.kind);
+        assert!(w.get_ref().is_empty());
+        assert!(write_string(&mut w, long.clone(), VERSION).is_err());
+        assert!(w.get_ref().is_empty());
+
+        // And for RPC parameters and results
+        let err = write_execute_rpc(&mut w, 1, 2, long.as_bytes(), VERSION).unwrap_err();
+        assert_eq!(ValueTooLarge(70000, 0xFFFF), err.kind);
+        let err = write_rpc_response(&mut w, 1, 2, long.as_bytes(), VERSION).unwrap_err();
+        assert_eq!(ValueTooLarge(70000, 0xFFFF), err.kind);
+        assert!(w.get_ref().is_empty());
+
+        // NT3 has room for them
+        assert_same(&entry, &assignment_round_trip(&entry, VERSION_3));
+        assert_same(&named, &assignment_round_trip(&named, VERSION_3));
+    }
+
+    #[test]
+    fn flags_by_version() {
+        let entry = Entry{name: "/Persisted".into_string(), id: 1, sequence: SequenceNumber(2),
+                          flags: FLAG_PERSISTENT, value: Boolean(true), timestamp: UNSTAMPED};
+
+        // NT2 assignments don't have flags
+        let mut w = MemWriter::new();
+        write_assignment(&mut w, &entry, VERSION).unwrap();
+        let bytes = w.unwrap();
+        let mut r = BufReader::new(bytes.slice_from(1));
+        assert_eq!(Entry{flags: 0, ..entry.clone()}, parse_assignment(&mut r, VERSION).unwrap());
+        assert!(r.eof());
+
+        assert_same(&entry, &assignment_round_trip(&entry, VERSION_3));
+
+        let mut w = MemWriter::new();
+        write_flags_update(&mut w, 0x1234, FLAG_PERSISTENT).unwrap();
+        assert_eq!(vec![0x12u8, 0x12, 0x34, 0x01], w.get_ref().to_vec());
+        let bytes = w.unwrap();
+        let mut r = BufReader::new(bytes.as_slice());
+        assert_eq!(FlagsUpdate(0x1234, FLAG_PERSISTENT), parse_message(&mut r, VERSION_3, |_| None).unwrap());
+
+        // NT2 has no flags updates to write or parse
+        let mut w = MemWriter::new();
+        let err = write_message(&mut w, &FlagsUpdate(0x1234, FLAG_PERSISTENT), VERSION).unwrap_err();
+        assert_eq!(UnsupportedMessage(ENTRY_FLAGS_UPDATE), err.kind);
+        assert!(w.get_ref().is_empty());
+        let mut r = BufReader::new(bytes.as_slice());
+        let err = parse_message(&mut r, VERSION, |_| None).unwrap_err();
+        assert_eq!(UnsupportedMessage(ENTRY_FLAGS_UPDATE), err.kind);
+    }
+
+    #[test]
+    fn hellos_by_version() {
+        // NT2 hellos are only the version
+        let mut w = MemWriter::new();
+        write_hello(&mut w, VERSION, "robot").unwrap();
+        assert_eq!(vec![0x01u8, 0x02, 0x00], w.get_ref().to_vec());
+        let mut r = BufReader::new(w.get_ref());
+        assert_eq!(Hello(VERSION, "".to_string()), parse_message(&mut r, VERSION, |_| None).unwrap());
+
+        // NT3 ones add the client's identity
+        let mut w = MemWriter::new();
+        write_hello(&mut w, VERSION_3, "robot").unwrap();
+        assert_eq!(vec![0x01u8, 0x03, 0x00, 0x05, b'r', b'o', b'b', b'o', b't'], w.get_ref().to_vec());
+        let mut r = BufReader::new(w.get_ref());
+        assert_eq!(Hello(VERSION_3, "robot".to_string()), parse_message(&mut r, VERSION, |_| None).unwrap());
+
+        // The server answers with its flags and identity, and each side
+        // ends its half of the handshake
+        let messages = [(ServerHello(0x01, "server".to_string()),
+                         vec![0x04u8, 0x01, 0x06, b's', b'e', b'r', b'v', b'e', b'r']),
+                        (HelloComplete, vec![0x03u8]),
+                        (ClientHelloComplete, vec![0x05u8])];
+        for &(ref message, ref bytes) in messages.iter() {
+            let mut w = MemWriter::new();
+            write_message(&mut w, message, VERSION_3).unwrap();
+            assert_eq!(*bytes, w.get_ref().to_vec());
+            let mut r = BufReader::new(bytes.as_slice());
+            assert_eq!(*message, parse_message(&mut r, VERSION_3, |_| None).unwrap());
+        }
+
+        // NT2 has neither server hellos nor client hello completes
+        let mut w = MemWriter::new();
+        let err = write_message(&mut w, &ServerHello(0, "server".to_string()), VERSION).unwrap_err();
+        assert_eq!(UnsupportedMessage(SERVER_HELLO), err.kind);
+        let mut r = BufReader::new(&[0x05u8]);
+        assert!(parse_message(&mut r, VERSION, |_| None).is_err());
+    }
+
+    // Feed the parsers arbitrary bytes. Any result is fine as long as
+    // they return instead of panicking. Strings and raw values are read
+    // a chunk at a time, so a parser never allocates much more than the
+    // input it was given.
+    #[test]
+    fn fuzz_parse_assignment() {
+        for _ in range::<int>(0, 10000) {
+            let bytes = random_bytes(64);
+            let _ = parse_assignment(&mut BufReader::new(bytes.as_slice()), VERSION_3);
+        }
+    }
+
+    #[test]
+    fn fuzz_parse_update() {
+        let types = [Boolean(false), Number(0f64), String("".into_string()), Raw(Vec::new()),
+                     Rpc(Vec::new())];
+        for _ in range::<int>(0, 10000) {
+            let bytes = random_bytes(64);
+            let entry_type: EntryType = rand::task_rng().choose(&types).unwrap().clone();
+            let known = rand::random::<bool>();
+            let _ = parse_update(&mut BufReader::new(bytes.as_slice()), VERSION_3, |_| match known {
+                true => Some(("/Fuzz".into_string(), entry_type.clone())),
+                false => None,
+            });
+        }
+    }
+
+    #[test]
+    fn fuzz_parse_raw() {
+        for _ in range::<int>(0, 10000) {
+            let bytes = random_bytes(64);
+            let _ = parse_raw(&mut BufReader::new(bytes.as_slice()), VERSION_3);
+        }
+        // A huge length is an error at the end of the input, not an allocation
+        let huge = [0xFFu8, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x01, 0x41];
+        assert!(parse_raw(&mut BufReader::new(huge.as_slice()), VERSION_3).is_err());
+    }
+
+    #[test]
+    fn fuzz_parse_string() {
+        for _ in range::<int>(0, 10000) {
+            let bytes = random_bytes(64);
+            let _ = parse_string(&mut BufReader::new(bytes.as_slice()), VERSION);
+            let _ = parse_string(&mut BufReader::new(bytes.as_slice()), VERSION_3);
+        }
+        // A length longer than the input is an error, not a panic
+        assert!(parse_string(&mut BufReader::new([0xFFu8, 0xFFu8, 0x41u8].as_slice()), VERSION).is_err());
+        assert!(parse_string(&mut BufReader::new([0xFFu8, 0x7Fu8, 0x41u8].as_slice()), VERSION_3).is_err());
+        // As is invalid UTF-8
+        assert!(parse_string(&mut BufReader::new([0x00u8, 0x01u8, 0xFFu8].as_slice()), VERSION).is_err());
+        assert!(parse_string(&mut BufReader::new([0x01u8, 0xFFu8].as_slice()), VERSION_3).is_err());
+    }
     
     #[test]
     fn entry_basics() {
         let eb = Entry{name: "Boolean".into_string(),
-                       id: 0u16, sequence: SequenceNumber(0u16), value: Boolean(true)};
+                       id: 0u16, sequence: SequenceNumber(0u16), flags: 0, value: Boolean(true),
+                       timestamp: UNSTAMPED};
         assert_eq!("Boolean", eb.name.as_slice());
         assert_eq!(0u16, eb.id);
         assert_eq!(SequenceNumber(0u16), eb.sequence);
@@ -157,7 +1033,8 @@ mod test {
         });
         
         let ne = Entry{name: "Number".into_string(),
-                       id: 1u16, sequence: SequenceNumber(0u16), value: Number(42f64)};
+                       id: 1u16, sequence: SequenceNumber(0u16), flags: 0, value: Number(42f64),
+                       timestamp: UNSTAMPED};
         assert_eq!("Number", ne.name.as_slice());
         assert_eq!(1u16, ne.id);
         assert_eq!(SequenceNumber(0u16), ne.sequence);
@@ -167,8 +1044,8 @@ mod test {
         });
         
         let se = Entry{name: "String".into_string(),
-                       id: 2u16, sequence: SequenceNumber(0u16),
-                       value: String("Test".into_string())};
+                       id: 2u16, sequence: SequenceNumber(0u16), flags: 0,
+                       value: String("Test".into_string()), timestamp: UNSTAMPED};
         assert_eq!("String", se.name.as_slice());
         assert_eq!(2u16, se.id);
         assert_eq!(SequenceNumber(0u16), se.sequence);
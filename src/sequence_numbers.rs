@@ -1,47 +1,67 @@
 /// Sequence Numbers are a special type of number
 /// Implements [rfc1982](http://tools.ietf.org/html/rfc1982)
-// TODO: Document
+///
+/// Sequence numbers wrap around from 0xFFFF to 0, and `a < b` when `b`
+/// is less than half the number space ahead of `a`. Numbers exactly
+/// half the space apart (32768) are neither less than nor greater
+/// than each other, so `partial_cmp` returns `None` for them.
 #[deriving(Show, Clone)]
 pub struct SequenceNumber(pub u16);
 const SEQUENCE_NUMBER_DIVIDING_POINT: u16 = 32768u16;
 
 impl SequenceNumber {
     pub fn increment(&mut self) {
-        let SequenceNumber(n) = *self;
-        *self = SequenceNumber(n + 1);
+        *self = self.add(1);
+    }
+
+    /// The sequence number `n` after this one, wrapping past 0xFFFF.
+    pub fn add(&self, n: u16) -> SequenceNumber {
+        let SequenceNumber(s) = *self;
+        SequenceNumber(((s as u32 + n as u32) & 0xFFFF) as u16)
     }
-    
+
     pub fn as_u16(&self) -> u16 {
         let SequenceNumber(n) = *self;
         n
     }
-    
-    fn cmp(&self, other: &SequenceNumber) -> Ordering {
-        let s = match *self { SequenceNumber(n) => n };
-        let o = match *other { SequenceNumber(n) => n };
-
-        if s == o {
-            Equal
-        } else if (s < o && o-s < SEQUENCE_NUMBER_DIVIDING_POINT)
-            || (s > o && s-o > SEQUENCE_NUMBER_DIVIDING_POINT) {
-                Less
-        } else {
-            Greater
+
+    /// The number of increments it takes to get from this sequence
+    /// number to `other`, wrapping past 0xFFFF.
+    pub fn distance(&self, other: &SequenceNumber) -> u16 {
+        let s = self.as_u16() as u32;
+        let o = other.as_u16() as u32;
+        ((o + 0x10000 - s) & 0xFFFF) as u16
+    }
+
+    /// Whether this sequence number is strictly after `other`. Numbers
+    /// an undefined distance apart aren't newer than each other.
+    pub fn is_newer_than(&self, other: &SequenceNumber) -> bool {
+        self.compare(other) == Some(Greater)
+    }
+
+    /// Compares sequence numbers per RFC 1982, returning `None` when
+    /// they are exactly the dividing point apart.
+    pub fn compare(&self, other: &SequenceNumber) -> Option<Ordering> {
+        match self.distance(other) {
+            0 => Some(Equal),
+            SEQUENCE_NUMBER_DIVIDING_POINT => None,
+            d if d < SEQUENCE_NUMBER_DIVIDING_POINT => Some(Less),
+            _ => Some(Greater),
         }
     }
 }
 
 impl PartialEq for SequenceNumber {
     fn eq(&self, other: &SequenceNumber) -> bool {
-        self.cmp(other) == Equal
+        self.as_u16() == other.as_u16()
     }
 }
-    
+
 impl Eq for SequenceNumber {}
 
 impl PartialOrd for SequenceNumber {
     fn partial_cmp(&self, other: &SequenceNumber) -> Option<Ordering> {
-        Some(self.cmp(other))
+        self.compare(other)
     }
 }
 
@@ -50,7 +70,7 @@ impl PartialOrd for SequenceNumber {
 mod test {
     use super::{SequenceNumber, SEQUENCE_NUMBER_DIVIDING_POINT};
     use std::rand;
-    
+
     #[test]
     fn sequence_number_equality() {
         // TODO: why not? for n in rand::task_rng().gen_iter::<u16>().take(100) {
@@ -65,9 +85,55 @@ mod test {
         for _ in range::<int>(0, 100) {
             let n = rand::random::<u16>();
             let i = rand::random::<u16>() % (SEQUENCE_NUMBER_DIVIDING_POINT-1) + 1;
-            assert!(SequenceNumber(n) < SequenceNumber(n+i));
+            assert!(SequenceNumber(n) < SequenceNumber(n).add(i));
         }
     }
 
-    // TODO: Manually test edge cases
+    #[test]
+    fn sequence_number_increment_wraps() {
+        let mut n = SequenceNumber(0xFFFEu16);
+        n.increment();
+        assert_eq!(SequenceNumber(0xFFFFu16), n);
+        n.increment();
+        assert_eq!(SequenceNumber(0u16), n);
+        n.increment();
+        assert_eq!(SequenceNumber(1u16), n);
+        assert_eq!(SequenceNumber(4u16), SequenceNumber(0xFFFBu16).add(9));
+    }
+
+    #[test]
+    fn sequence_number_distance() {
+        assert_eq!(0u16, SequenceNumber(7u16).distance(&SequenceNumber(7u16)));
+        assert_eq!(3u16, SequenceNumber(7u16).distance(&SequenceNumber(10u16)));
+        assert_eq!(1u16, SequenceNumber(0xFFFFu16).distance(&SequenceNumber(0u16)));
+        assert_eq!(0xFFFFu16, SequenceNumber(0u16).distance(&SequenceNumber(0xFFFFu16)));
+    }
+
+    #[test]
+    fn sequence_number_edge_cases() {
+        // Across the wrap
+        assert!(SequenceNumber(0xFFFFu16) < SequenceNumber(0u16));
+        assert!(SequenceNumber(0u16) > SequenceNumber(0xFFFFu16));
+        assert!(SequenceNumber(0u16).is_newer_than(&SequenceNumber(0xFFFFu16)));
+        assert!(!SequenceNumber(0xFFFFu16).is_newer_than(&SequenceNumber(0u16)));
+
+        // Either side of the dividing point
+        assert!(SequenceNumber(0u16) < SequenceNumber(32767u16));
+        assert!(SequenceNumber(0u16) > SequenceNumber(32769u16));
+        assert!(SequenceNumber(32768u16) < SequenceNumber(0xFFFFu16));
+        assert!(SequenceNumber(1u16) > SequenceNumber(32769u16));
+
+        // Exactly at the dividing point is undefined
+        for &(a, b) in [(0u16, 32768u16), (32768u16, 0u16), (1u16, 32769u16),
+                        (0xFFFFu16, 32767u16)].iter() {
+            let (a, b) = (SequenceNumber(a), SequenceNumber(b));
+            assert_eq!(None, a.partial_cmp(&b));
+            assert!(!(a < b) && !(a > b) && !(a <= b) && !(a >= b));
+            assert!(!a.is_newer_than(&b) && !b.is_newer_than(&a));
+        }
+
+        // Equal numbers aren't newer than each other
+        assert!(!SequenceNumber(5u16).is_newer_than(&SequenceNumber(5u16)));
+        assert!(SequenceNumber(5u16) <= SequenceNumber(5u16));
+    }
 }
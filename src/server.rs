@@ -1 +1,1835 @@
+use super::protocol;
+use super::protocol::{Message, KeepAlive, VersionUnsupported, HelloComplete, ServerHello, Assignment,
+                      Update, FlagsUpdate, Delete, ClearAll, ExecuteRpc, RpcResponse};
+use super::NtResult;
+use super::table::{Event, Listeners, Added, Updated, FlagsUpdated, Deleted};
+use super::stats;
+use super::stats::Stats;
+use super::limiter::Limiter;
+use super::{nt4, websocket};
+use super::{NtError, UnsupportedMessage, UnsupportedVersion, KeyAlreadyExists, KeyDoesntExist,
+            OutOfOrderSequenceNumbers, NetworkProblem, IdDoesntExist, TypeMismatch, InvalidMessage,
+            OutOfIds};
 
+use serialize::json;
+use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, HashSet};
+use std::mem;
+
+use std::io::{Listener, Acceptor, MemWriter, IoError, EndOfFile};
+use std::io::net::tcp::{TcpListener, TcpAcceptor, TcpStream};
+use std::io::Timer;
+use std::time::Duration;
+
+use time::precise_time_ns;
+
+// How far ahead of the NT4 server's clock a client may stamp a value.
+// Client clocks are only estimates of the server's, so they can run a
+// little ahead.
+const MAX_CLOCK_SKEW_US: i64 = 100000;
+
+// Locking order to avoid deadlocks:
+// - entries
+// - rpcs
+// - connections
+// - bandwidth_limit
+// - a connection's send_queue
+// - a connection's limiter
+// - a connection's stream
+// - closed
+// - errors
+// - stats
+// - listeners
+
+/// A [NetworkTables 2.0](https://docs.google.com/document/d/1On9BkUgkmMmTnfVxSQlOZMWsa9Vas6-8cT19TX59Tho/edit)
+/// server. It is the authority on the value of every entry: an update
+/// is only accepted if its sequence number is newer than the current
+/// one. When an update is rejected, the current value is sent to every
+/// client under a new sequence number so that all of them converge on
+/// it, including any that had already accepted the losing value.
+///
+/// NT3 clients connect too. Each client speaks the version from its
+/// hello, and isn't sent changes its version can't carry: NT2 clients
+/// never see raw values or RPCs.
+///
+/// # Example
+///
+/// ```ignore
+/// let server = networktables::Server::new("0.0.0.0:1735").unwrap();
+/// ```
+pub struct Server {
+    entries: Mutex<Entries>,
+    connections: Mutex<Vec<Arc<Connection>>>,
+    acceptor: Mutex<TcpAcceptor>,
+    address: String,
+    closed: Mutex<bool>,
+    errors: Mutex<Vec<NtError>>,
+    bandwidth_limit: Mutex<Option<u64>>,
+    stats: Mutex<Stats>,
+    rpcs: Mutex<HashMap<u16, Arc<Box<RpcHandler + Send + Sync>>>>,
+    listeners: Listeners,
+}
+
+/// Handles calls to a remote procedure registered with
+/// `Server::register_rpc`.
+pub trait RpcHandler {
+    /// Returns the result of calling the procedure with `params`.
+    fn call(&self, params: Vec<u8>) -> Vec<u8>;
+}
+
+impl RpcHandler for fn(Vec<u8>) -> Vec<u8> {
+    fn call(&self, params: Vec<u8>) -> Vec<u8> { (*self)(params) }
+}
+
+struct Entries {
+    by_id: HashMap<u16, protocol::Entry>,
+    ids_by_name: HashMap<String, u16>,
+    next_id: u16,
+}
+
+impl Entries {
+    /// Takes the next unused id. Ids aren't reused, since clients may
+    /// not have seen an entry deleted, so they run out once every id
+    /// below `CLIENT_REQUEST_ID` has been handed out.
+    fn allocate_id(&mut self) -> NtResult<u16> {
+        if self.next_id == protocol::CLIENT_REQUEST_ID {
+            return Err(NtError::new(OutOfIds))
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        Ok(id)
+    }
+}
+
+/// A client connected to the server. Messages are queued and sent in
+/// batches by the server's send task, like `Client` does. Messages the
+/// client's version of the protocol doesn't have are never queued.
+struct Connection {
+    /// The protocol version from the client's hello.
+    version: u16,
+    send_queue: Mutex<Vec<Message>>,
+    limiter: Mutex<Option<Limiter>>,
+    stream: Mutex<TcpStream>,
+}
+
+impl Server {
+    pub fn new(address: &str) -> NtResult<Arc<Server>> {
+        let listener = try!(TcpListener::bind(address));
+        let mut acceptor = try!(listener.listen());
+        let address = format!("{}", try!(acceptor.socket_name()));
+
+        let server = Arc::new(Server{
+            entries: Mutex::new(Entries{
+                by_id: HashMap::new(),
+                ids_by_name: HashMap::new(),
+                next_id: 0,
+            }),
+            connections: Mutex::new(Vec::new()),
+            acceptor: Mutex::new(acceptor),
+            address: address,
+            closed: Mutex::new(false),
+            errors: Mutex::new(Vec::new()),
+            bandwidth_limit: Mutex::new(None),
+            stats: Mutex::new(Stats::new()),
+            rpcs: Mutex::new(HashMap::new()),
+            listeners: Listeners::new(),
+        });
+
+        let (server2, server3) = (server.clone(), server.clone());
+        spawn(proc() Server::accept(server2));
+        spawn(proc() server3.send());
+        Ok(server)
+    }
+
+    /// The address the server is listening on.
+    pub fn address(&self) -> &str { self.address.as_slice() }
+
+    pub fn close(&self) {
+        {
+            let mut closed = self.closed.lock();
+            if *closed { return }
+            *closed = true;
+        }
+
+        if let Err(e) = self.acceptor.lock().close_accept() { println!("{}", e) };
+        let connections = mem::replace(&mut *self.connections.lock(), Vec::new());
+        for connection in connections.iter() {
+            connection.close();
+        }
+    }
+
+    pub fn get_errors(&self) -> Vec<NtError> { self.errors.lock().clone() }
+
+    /// Returns the traffic counters for every connection combined.
+    pub fn stats(&self) -> Stats {
+        let connections = self.connections.lock().clone();
+        let depth = connections.iter().fold(0, |depth, c| {
+            depth + stats::queue_depth(c.send_queue.lock().as_slice())
+        });
+        let mut stats = self.stats.lock().clone();
+        stats.send_queue_depth = depth;
+        stats
+    }
+
+    /// Creates an RPC entry with `key` and `definition`, and calls
+    /// `handler` whenever a client executes it. Calls are handled on
+    /// the calling client's connection task, so a slow procedure only
+    /// holds up that client.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// fn echo(params: Vec<u8>) -> Vec<u8> { params }
+    /// try!(server.register_rpc("/Echo".to_string(), Vec::new(),
+    ///                          box (echo as fn(Vec<u8>) -> Vec<u8>)));
+    /// ```
+    pub fn register_rpc(&self, key: String, definition: Vec<u8>,
+                        handler: Box<RpcHandler + Send + Sync>) -> NtResult<()> {
+        if let Err(e) = protocol::check_length(key.len(), protocol::VERSION_3)
+            .and(protocol::check_length(definition.len(), protocol::VERSION_3)) {
+            return Err(e.with_key(key))
+        }
+        let mut entries = self.entries.lock();
+        let mut entry = protocol::Entry{name: key.clone(), id: protocol::CLIENT_REQUEST_ID,
+                                        sequence: protocol::SequenceNumber(0), flags: 0,
+                                        value: protocol::Rpc(definition), timestamp: protocol::UNSTAMPED};
+        if let Some(id) = entries.ids_by_name.get(&key) {
+            let existing = entries.by_id.get(id).unwrap().clone();
+            entry.id = existing.id;
+            return Err(NtError::new(KeyAlreadyExists(existing, entry)).with_key(key))
+        }
+
+        entry.id = match entries.allocate_id() {
+            Ok(id) => id,
+            Err(e) => return Err(e.with_key(key)),
+        };
+        entries.ids_by_name.insert(key, entry.id);
+        entries.by_id.insert(entry.id, entry.clone());
+        self.rpcs.lock().insert(entry.id, Arc::new(handler));
+        self.broadcast(Assignment(entry), None);
+        Ok(())
+    }
+
+    /// Adds a listener that is sent an `Event` whenever a client changes
+    /// an entry. Changes made with `set_entry` and `delete_entry` aren't
+    /// sent, so whatever made them isn't told about its own changes.
+    pub fn add_listener(&self, listener: Sender<Event>) {
+        self.listeners.add(listener)
+    }
+
+    /// Sets the entry with `key` from the server itself, assigning it an
+    /// id if it's new. An existing entry's type can't change, since
+    /// updates don't carry one. Values NT2 can't carry, like raw values,
+    /// are only sent to NT3 clients.
+    pub fn set_entry(&self, key: String, value: protocol::EntryType) -> NtResult<()> {
+        if let Err(e) = protocol::check_length(key.len(), protocol::VERSION_3)
+            .and(protocol::check_value(&value, protocol::VERSION_3)) {
+            return Err(e.with_key(key))
+        }
+        let mut entries = self.entries.lock();
+        let existing = entries.ids_by_name.get(&key).map(|id| *id);
+        match existing {
+            Some(id) => {
+                let current = entries.by_id.get_mut(&id).unwrap();
+                if !protocol::same_type(&current.value, &value) {
+                    return Err(NtError::new(TypeMismatch(key.clone())).with_key(key).with_id(id))
+                }
+                current.value = value;
+                current.sequence.increment();
+                self.broadcast(Update(current.clone()), None);
+            },
+            None => {
+                let id = match entries.allocate_id() {
+                    Ok(id) => id,
+                    Err(e) => return Err(e.with_key(key)),
+                };
+                let entry = protocol::Entry{name: key.clone(), id: id,
+                                            sequence: protocol::SequenceNumber(0), flags: 0,
+                                            value: value, timestamp: protocol::UNSTAMPED};
+                entries.ids_by_name.insert(key, entry.id);
+                entries.by_id.insert(entry.id, entry.clone());
+                self.broadcast(Assignment(entry), None);
+            },
+        }
+        Ok(())
+    }
+
+    /// Deletes the entry with `key` from the server itself.
+    pub fn delete_entry(&self, key: String) -> NtResult<()> {
+        let mut entries = self.entries.lock();
+        let id = match entries.ids_by_name.remove(&key) {
+            Some(id) => id,
+            None => return Err(NtError::new(KeyDoesntExist(key.clone())).with_key(key)),
+        };
+        entries.by_id.remove(&id);
+        self.rpcs.lock().remove(&id);
+        self.broadcast(Delete(id), None);
+        Ok(())
+    }
+
+    /// Limits the bytes sent per second to each connection, or removes
+    /// the limit with `None`. While a connection is over the limit later
+    /// updates to an entry replace queued ones, like `Client` does.
+    pub fn set_bandwidth_limit(&self, bytes_per_second: Option<u64>) {
+        let connections = self.connections.lock();
+        *self.bandwidth_limit.lock() = bytes_per_second;
+        for connection in connections.iter() {
+            connection.set_bandwidth_limit(bytes_per_second);
+        }
+    }
+    fn is_closed(&self) -> bool { *self.closed.lock() }
+
+    fn accept(server: Arc<Server>) {
+        let mut acceptor = server.acceptor.lock().clone();
+
+        for stream in acceptor.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let server = server.clone();
+                    spawn(proc() server.listen(stream));
+                },
+                Err(_) if server.is_closed() => return,
+                Err(e) => server.log_error(NtError::new(NetworkProblem(e)).during("accepting connection")),
+            }
+        }
+    }
+
+    fn send(&self) {
+        let keep_alive_cutoff: u64 = 1000 /*ms*/ / 20 /*ms*/;
+        let mut counter = 0;
+        let mut timer = Timer::new().unwrap(); // TODO: Possibility for panic?
+        let periodic = timer.periodic(Duration::milliseconds(20));
+
+        loop {
+            periodic.recv();
+            if self.is_closed() { return }
+
+            counter += 1;
+            let keep_alive = (counter % keep_alive_cutoff) == 0;
+            if keep_alive { counter = 0 }
+
+            let connections = self.connections.lock().clone();
+            for connection in connections.iter() {
+                if keep_alive { connection.queue(KeepAlive, &self.stats) }
+                if let Err(e) = connection.flush(&self.stats) {
+                    self.drop_connection(connection, e)
+                }
+            }
+        }
+    }
+
+    fn listen(&self, mut tcp_stream: TcpStream) {
+        let mut stream = protocol::CountingReader::new(tcp_stream.clone());
+        let connection = match self.handshake(&mut stream, tcp_stream.clone()) {
+            Ok(connection) => connection,
+            Err(e) => {
+                let _ = tcp_stream.close_read();
+                let _ = tcp_stream.close_write();
+                return self.log_dropped(e)
+            },
+        };
+
+        loop {
+            let offset = stream.count();
+            let message = protocol::parse_message(&mut stream, connection.version, |id| self.id_lookup(id));
+            if let Ok(ref message) = message {
+                self.stats.lock().record_received(message.message_type(), stream.count() - offset);
+            }
+            let result = match message {
+                Ok(Assignment(entry)) => self.handle_assignment(&connection, entry),
+                Ok(Update(entry)) => Ok(self.handle_update(&connection, entry)),
+                Ok(FlagsUpdate(id, flags)) => Ok(self.handle_flags_update(&connection, id, flags)),
+                Ok(Delete(id)) => Ok(self.handle_delete(&connection, id)),
+                Ok(ExecuteRpc(id, uid, params)) => Ok(self.handle_execute_rpc(&connection, id, uid, params)),
+                Ok(ClearAll(magic)) => Ok(self.handle_clear_all(&connection, magic)),
+                // Nothing else needs a response once connected
+                Ok(_) => Ok(()),
+                Err(e) => Err(e.at_offset(offset)),
+            };
+            if let Err(e) = result {
+                return self.drop_connection(&connection, e)
+            }
+        }
+    }
+
+    /// Reads the client's hello and starts a connection speaking its
+    /// version, NT2 or NT3. Other versions are told the newest one the
+    /// server speaks, so the client can say hello again with it. NT3
+    /// clients are sent a server hello before the entries.
+    fn handshake<R: Reader>(&self, r: &mut R, mut stream: TcpStream) -> NtResult<Arc<Connection>> {
+        let msg = try!(r.read_u8());
+        if msg != protocol::HELLO {
+            return Err(NtError::new(UnsupportedMessage(msg)).with_message(msg))
+        }
+        let (version, _) = try!(protocol::parse_hello(r));
+        if version != protocol::VERSION && version != protocol::VERSION_3 {
+            try!(protocol::write_message(&mut stream, &VersionUnsupported(protocol::VERSION_3),
+                                         protocol::VERSION));
+            self.stats.lock().record_sent(protocol::VERSION_UNSUPPORTED, 3);
+            return Err(NtError::new(UnsupportedVersion(version)).with_message(msg))
+        }
+        let connection = Arc::new(Connection::new(stream, version));
+        // Clients aren't remembered between connections, so none has
+        // been seen before
+        connection.queue(ServerHello(0, protocol::DEFAULT_IDENTITY.to_string()), &self.stats);
+
+        // Hold the entries while adding the connection so it can't miss
+        // any changes made between the assignments and joining.
+        let entries = self.entries.lock();
+        for entry in entries.by_id.values() {
+            connection.queue(Assignment(entry.clone()), &self.stats);
+        }
+        connection.queue(HelloComplete, &self.stats);
+        let mut connections = self.connections.lock();
+        connection.set_bandwidth_limit(*self.bandwidth_limit.lock());
+        connections.push(connection.clone());
+        Ok(connection)
+    }
+
+    /// Assigns an id to the client's new entry. Assignments of an
+    /// existing key are answered with the existing entry, and clients
+    /// too old to be sent it are dropped rather than left waiting.
+    fn handle_assignment(&self, connection: &Arc<Connection>, mut entry: protocol::Entry) -> NtResult<()> {
+        let mut entries = self.entries.lock();
+        if let Some(id) = entries.ids_by_name.get(&entry.name) {
+            // Tell the client the id of the existing entry instead
+            let existing = entries.by_id.get(id).unwrap().clone();
+            let key = entry.name.clone();
+            // Logged first, so the error is there by the time the client
+            // hears back
+            self.log_error(NtError::new(KeyAlreadyExists(existing.clone(), entry))
+                           .with_message(protocol::ENTRY_ASSIGNMENT).with_key(key.clone()));
+            if let Err(e) = protocol::check_value(&existing.value, connection.version) {
+                return Err(e.with_message(protocol::ENTRY_ASSIGNMENT).with_key(key).with_id(existing.id))
+            }
+            connection.queue(Assignment(existing), &self.stats);
+            return Ok(())
+        }
+
+        entry.id = match entries.allocate_id() {
+            Ok(id) => id,
+            Err(e) => {
+                let key = entry.name.clone();
+                self.log_error(e.with_message(protocol::ENTRY_ASSIGNMENT).with_key(key));
+                return Ok(())
+            },
+        };
+        entries.ids_by_name.insert(entry.name.clone(), entry.id);
+        entries.by_id.insert(entry.id, entry.clone());
+        self.listeners.notify(Added(entry.name.clone(), entry.value.clone()));
+        self.broadcast(Assignment(entry), None);
+        Ok(())
+    }
+
+    fn handle_update(&self, connection: &Arc<Connection>, entry: protocol::Entry) {
+        let mut entries = self.entries.lock();
+        let current = match entries.by_id.get_mut(&entry.id) {
+            Some(current) => current,
+            None => return, // Parsing the update already checked the id exists
+        };
+
+        if entry.sequence.is_newer_than(&current.sequence) {
+            // Updates don't carry flags, so keep the current ones
+            current.sequence = entry.sequence;
+            current.value = entry.value.clone();
+            self.listeners.notify(Updated(entry.name.clone(), entry.value.clone()));
+            self.broadcast(Update(entry), Some(connection));
+        } else {
+            self.log_error(NtError::new(OutOfOrderSequenceNumbers(current.sequence, entry.sequence))
+                           .with_message(protocol::ENTRY_UPDATE)
+                           .with_key(entry.name.clone()).with_id(entry.id));
+            current.sequence.increment();
+            self.broadcast(Update(current.clone()), None);
+        }
+    }
+
+    fn handle_flags_update(&self, connection: &Arc<Connection>, id: u16, flags: u8) {
+        let mut entries = self.entries.lock();
+        match entries.by_id.get_mut(&id) {
+            Some(current) => {
+                current.flags = flags;
+                self.listeners.notify(FlagsUpdated(current.name.clone(), flags));
+            },
+            None => return,
+        }
+        self.broadcast(FlagsUpdate(id, flags), Some(connection));
+    }
+
+    fn handle_execute_rpc(&self, connection: &Arc<Connection>, id: u16, uid: u16, params: Vec<u8>) {
+        let handler = match self.rpcs.lock().get(&id) {
+            Some(handler) => handler.clone(),
+            None => return self.log_error(NtError::new(IdDoesntExist(id))
+                                          .with_message(protocol::EXECUTE_RPC).with_id(id)),
+        };
+        let result = handler.call(params);
+        // Checked before queueing, so it doesn't fail the whole flush
+        if let Err(e) = protocol::check_length(result.len(), connection.version) {
+            return self.log_error(e.with_message(protocol::RPC_RESPONSE).with_id(id))
+        }
+        connection.queue(RpcResponse(id, uid, result), &self.stats);
+    }
+
+    fn handle_delete(&self, connection: &Arc<Connection>, id: u16) {
+        let mut entries = self.entries.lock();
+        let entry = match entries.by_id.remove(&id) {
+            Some(entry) => entry,
+            None => return,
+        };
+        entries.ids_by_name.remove(&entry.name);
+        self.rpcs.lock().remove(&id);
+        // The client already removed it, but may have updates to it queued
+        connection.forget(Some(id));
+        self.listeners.notify(Deleted(entry.name));
+        self.broadcast(Delete(id), Some(connection));
+    }
+
+    fn handle_clear_all(&self, connection: &Arc<Connection>, magic: u32) {
+        if magic != protocol::CLEAR_ALL_MAGIC { return }
+
+        let mut entries = self.entries.lock();
+        let mut names: Vec<String> = entries.ids_by_name.keys().map(|name| name.clone()).collect();
+        names.sort();
+        entries.by_id.clear();
+        entries.ids_by_name.clear();
+        self.rpcs.lock().clear();
+        connection.forget(None);
+        for name in names.into_iter() {
+            self.listeners.notify(Deleted(name));
+        }
+        self.broadcast(ClearAll(magic), Some(connection));
+    }
+
+    /// Queues `message` for every connection except `except`.
+    fn broadcast(&self, message: Message, except: Option<&Arc<Connection>>) {
+        let connections = self.connections.lock();
+        for connection in connections.iter() {
+            match except {
+                Some(except) if same_connection(connection, except) => (),
+                _ => connection.queue(message.clone(), &self.stats),
+            }
+        }
+    }
+
+    fn drop_connection(&self, connection: &Arc<Connection>, err: NtError) {
+        self.connections.lock().retain(|c| !same_connection(c, connection));
+        connection.close();
+        self.log_dropped(err)
+    }
+
+    /// Logs the error a connection was dropped for.
+    fn log_dropped(&self, err: NtError) {
+        match err.kind {
+            // Clients hanging up or the server closing isn't an error
+            NetworkProblem(ref e) if e.kind == EndOfFile => (),
+            _ if self.is_closed() => (),
+            _ => self.log_error(err),
+        }
+    }
+
+    fn id_lookup(&self, id: u16) -> Option<(String, protocol::EntryType)> {
+        let entries = self.entries.lock();
+        match entries.by_id.get(&id) {
+            Some(entry) => Some((entry.name.clone(), entry.value.clone())),
+            None => None,
+        }
+    }
+
+    fn log_error(&self, err: NtError) {
+        let mut errors = self.errors.lock();
+        errors.push(err);
+    }
+}
+
+impl Connection {
+    fn new(stream: TcpStream, version: u16) -> Connection {
+        Connection{
+            version: version,
+            send_queue: Mutex::new(Vec::new()),
+            limiter: Mutex::new(None),
+            stream: Mutex::new(stream),
+        }
+    }
+
+    fn set_bandwidth_limit(&self, bytes_per_second: Option<u64>) {
+        *self.limiter.lock() = bytes_per_second.map(|b| Limiter::new(b, precise_time_ns()));
+    }
+
+    fn clone_stream(&self) -> TcpStream { self.stream.lock().clone() }
+
+    /// Drops unsent messages about the entry with `id`, or about every
+    /// entry for `None`, once they've been deleted.
+    fn forget(&self, id: Option<u16>) {
+        self.send_queue.lock().retain(|queued| match (queued, id) {
+            (&Assignment(ref e), Some(id)) | (&Update(ref e), Some(id)) => e.id != id,
+            (&Assignment(_), None) | (&Update(_), None) | (&Delete(_), None) => false,
+            _ => true,
+        });
+    }
+
+    /// Queues `message`, replacing an unsent update to the same entry.
+    fn queue(&self, message: Message, stats: &Mutex<Stats>) {
+        if !protocol::can_send(&message, self.version) { return }
+        match message {
+            Delete(id) => self.forget(Some(id)),
+            ClearAll(_) => self.forget(None),
+            _ => (),
+        }
+        let mut queue = self.send_queue.lock();
+        // Anything else queued keeps the connection alive already
+        if let KeepAlive = message {
+            if !queue.is_empty() { return }
+        }
+        if let Update(ref entry) = message {
+            let queued = queue.iter_mut().find(|queued| match **queued {
+                Update(ref e) => e.id == entry.id,
+                _ => false,
+            });
+            if let Some(queued) = queued {
+                *queued = message.clone();
+                stats.lock().coalesced += 1;
+                return
+            }
+        }
+        queue.push(message);
+    }
+
+    /// Sends everything queued in a single write, unless the connection
+    /// is over its bandwidth limit.
+    fn flush(&self, stats: &Mutex<Stats>) -> NtResult<()> {
+        let mut queue = self.send_queue.lock();
+        if queue.is_empty() { return Ok(()) }
+        let start = precise_time_ns();
+        let mut limiter = self.limiter.lock();
+        if let Some(ref mut limiter) = *limiter {
+            if !limiter.ready(start) {
+                stats.lock().deferred_flushes += 1;
+                return Ok(())
+            }
+        }
+
+        let mut w = MemWriter::new();
+        let mut sizes = Vec::with_capacity(queue.len());
+        for message in queue.iter() {
+            let before = w.get_ref().len();
+            try!(protocol::write_message(&mut w, message, self.version));
+            sizes.push((message.message_type(), (w.get_ref().len() - before) as u64));
+        }
+        *queue = Vec::new();
+        try!(self.stream.lock().write(w.get_ref()));
+        if let Some(ref mut limiter) = *limiter {
+            limiter.consume(w.get_ref().len() as u64);
+        }
+
+        let mut stats = stats.lock();
+        for &(message, bytes) in sizes.iter() {
+            stats.record_sent(message, bytes);
+        }
+        stats.record_flush(precise_time_ns() - start);
+        Ok(())
+    }
+
+    fn close(&self) {
+        let mut stream = self.clone_stream();
+        let _ = stream.close_read();
+        let _ = stream.close_write();
+    }
+}
+
+/// An [NT4](https://github.com/wpilibsuite/allwpilib/blob/main/ntcore/doc/networktables4.adoc)
+/// server. Clients connect over WebSocket, publish topics and subscribe
+/// to them. Topics exist while they have a publisher, or for good once
+/// they are retained or persistent, and the latest value of a cached
+/// topic is sent to new subscribers. Clients that have synced their
+/// clocks stamp their own values. Other values, and any stamped too far
+/// ahead of the server's clock, are stamped with the server's clock when
+/// they arrive.
+///
+/// # Example
+///
+/// ```ignore
+/// let server = networktables::Nt4Server::new("0.0.0.0:5810").unwrap();
+/// ```
+pub struct Nt4Server {
+    topics: Mutex<Topics>,
+    connections: Mutex<Vec<Arc<Nt4Connection>>>,
+    acceptor: Mutex<TcpAcceptor>,
+    address: String,
+    start_ns: u64,
+    closed: Mutex<bool>,
+    errors: Mutex<Vec<NtError>>,
+}
+
+// Locking order to avoid deadlocks:
+// - topics
+// - connections
+// - a connection's state
+// - a connection's stream
+// - closed
+// - errors
+
+struct Topics {
+    by_id: HashMap<i64, Topic>,
+    ids_by_name: HashMap<String, i64>,
+    next_id: i64,
+}
+
+struct Topic {
+    name: String,
+    id: i64,
+    type_str: String,
+    properties: json::JsonObject,
+    /// The latest value, unless the topic isn't cached.
+    value: Option<nt4::ValueMessage>,
+    publishers: uint,
+}
+
+impl Topic {
+    fn announce(&self, pubuid: Option<i64>) -> nt4::ServerMessage {
+        nt4::Announce(self.name.clone(), self.id, self.type_str.clone(), pubuid, self.properties.clone())
+    }
+
+    /// Whether the topic outlives its last publisher.
+    fn is_retained(&self) -> bool {
+        nt4::flag(&self.properties, "retained", false) || nt4::flag(&self.properties, "persistent", false)
+    }
+
+    fn is_cached(&self) -> bool { nt4::flag(&self.properties, "cached", true) }
+}
+
+/// A client connected to the NT4 server. Text messages are sent on the
+/// server's next tick, and each topic's values once per the shortest
+/// period of the client's subscriptions to it.
+struct Nt4Connection {
+    state: Mutex<Nt4State>,
+    stream: Mutex<TcpStream>,
+}
+
+struct Nt4State {
+    /// The topic id published under each pubuid.
+    publishers: HashMap<i64, i64>,
+    subscriptions: HashMap<i64, (Vec<String>, nt4::SubscriptionOptions)>,
+    /// The ids of topics the client has been told about.
+    announced: HashSet<i64>,
+    text_queue: Vec<nt4::ServerMessage>,
+    values: Vec<QueuedValue>,
+    /// When each topic's values were last sent.
+    last_sent: HashMap<i64, u64>,
+}
+
+/// A value waiting to be sent, with the period of its topic.
+struct QueuedValue {
+    value: nt4::ValueMessage,
+    period_ns: u64,
+}
+
+impl Nt4Server {
+    pub fn new(address: &str) -> NtResult<Arc<Nt4Server>> {
+        let listener = try!(TcpListener::bind(address));
+        let mut acceptor = try!(listener.listen());
+        let address = format!("{}", try!(acceptor.socket_name()));
+
+        let server = Arc::new(Nt4Server{
+            topics: Mutex::new(Topics{
+                by_id: HashMap::new(),
+                ids_by_name: HashMap::new(),
+                next_id: 1,
+            }),
+            connections: Mutex::new(Vec::new()),
+            acceptor: Mutex::new(acceptor),
+            address: address,
+            start_ns: precise_time_ns(),
+            closed: Mutex::new(false),
+            errors: Mutex::new(Vec::new()),
+        });
+
+        let (server2, server3) = (server.clone(), server.clone());
+        spawn(proc() Nt4Server::accept(server2));
+        spawn(proc() server3.send());
+        Ok(server)
+    }
+
+    /// The address the server is listening on.
+    pub fn address(&self) -> &str { self.address.as_slice() }
+
+    pub fn close(&self) {
+        {
+            let mut closed = self.closed.lock();
+            if *closed { return }
+            *closed = true;
+        }
+
+        if let Err(e) = self.acceptor.lock().close_accept() { println!("{}", e) };
+        let connections = mem::replace(&mut *self.connections.lock(), Vec::new());
+        for connection in connections.iter() {
+            connection.close();
+        }
+    }
+
+    pub fn get_errors(&self) -> Vec<NtError> { self.errors.lock().clone() }
+
+    /// The names of every topic, sorted.
+    pub fn topic_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.topics.lock().ids_by_name.keys().map(|n| n.clone()).collect();
+        names.sort();
+        names
+    }
+
+    /// The server's clock: microseconds since it started.
+    pub fn now_us(&self) -> i64 {
+        ((precise_time_ns() - self.start_ns) / 1000) as i64
+    }
+
+    fn is_closed(&self) -> bool { *self.closed.lock() }
+
+    fn accept(server: Arc<Nt4Server>) {
+        let mut acceptor = server.acceptor.lock().clone();
+
+        for stream in acceptor.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let connection = Arc::new(Nt4Connection::new(stream));
+                    let server = server.clone();
+                    spawn(proc() server.listen(connection));
+                },
+                Err(_) if server.is_closed() => return,
+                Err(e) => server.log_error(NtError::new(NetworkProblem(e)).during("accepting connection")),
+            }
+        }
+    }
+
+    fn send(&self) {
+        let mut timer = Timer::new().unwrap(); // TODO: Possibility for panic?
+        let periodic = timer.periodic(Duration::milliseconds((nt4::MIN_PERIODIC * 1000f64) as i64));
+
+        loop {
+            periodic.recv();
+            if self.is_closed() { return }
+
+            let now = precise_time_ns();
+            let connections = self.connections.lock().clone();
+            for connection in connections.iter() {
+                if let Err(e) = connection.flush(now) {
+                    self.drop_connection(connection, e)
+                }
+            }
+        }
+    }
+
+    fn listen(&self, connection: Arc<Nt4Connection>) {
+        let mut stream = connection.clone_stream();
+        if let Err(e) = websocket::accept(&mut stream, websocket::NT4_PROTOCOLS.as_slice()) {
+            connection.close();
+            return self.log_error(e.during("accepting WebSocket"))
+        }
+        self.connections.lock().push(connection.clone());
+
+        loop {
+            let result = match websocket::read_frame(&mut stream) {
+                Ok(websocket::Text(text)) => self.handle_text(&connection, text.as_slice()),
+                Ok(websocket::Binary(bytes)) => self.handle_binary(&connection, bytes.as_slice()),
+                Ok(websocket::Ping(payload)) => connection.write(&websocket::Pong(payload)),
+                Ok(websocket::Pong(_)) => Ok(()),
+                Ok(websocket::Close) => {
+                    let _ = connection.write(&websocket::Close);
+                    Err(NtError::new(NetworkProblem(IoError{kind: EndOfFile, desc: "WebSocket closed",
+                                                            detail: None})))
+                },
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                return self.drop_connection(&connection, e)
+            }
+        }
+    }
+
+    fn handle_text(&self, connection: &Arc<Nt4Connection>, text: &str) -> NtResult<()> {
+        for message in try!(nt4::parse_client_messages(text)).into_iter() {
+            self.handle(connection, message);
+        }
+        Ok(())
+    }
+
+    fn handle_binary(&self, connection: &Arc<Nt4Connection>, bytes: &[u8]) -> NtResult<()> {
+        for value in try!(nt4::parse_values(bytes)).into_iter() {
+            try!(self.handle_value(connection, value));
+        }
+        Ok(())
+    }
+
+    fn handle(&self, connection: &Arc<Nt4Connection>, message: nt4::ClientMessage) {
+        match message {
+            nt4::Publish(name, pubuid, type_str, properties) =>
+                self.handle_publish(connection, name, pubuid, type_str, properties),
+            nt4::Unpublish(pubuid) => self.handle_unpublish(connection, pubuid),
+            nt4::SetProperties(name, update) => self.handle_set_properties(connection, name, update),
+            nt4::Subscribe(subuid, topics, options) => self.handle_subscribe(connection, subuid, topics, options),
+            nt4::Unsubscribe(subuid) => { connection.state.lock().subscriptions.remove(&subuid); },
+        }
+    }
+
+    fn handle_publish(&self, connection: &Arc<Nt4Connection>, name: String, pubuid: i64, type_str: String,
+                      properties: json::JsonObject) {
+        let mut topics = self.topics.lock();
+        // Another publisher under the same pubuid would never be unpublished
+        if connection.state.lock().publishers.contains_key(&pubuid) {
+            let err = NtError::new(InvalidMessage(format!("Publish for pubuid={} already in use.", pubuid)));
+            return self.log_error(err.with_key(name))
+        }
+        let existing = topics.ids_by_name.get(&name).map(|id| *id);
+        let id = match existing {
+            Some(id) => id,
+            None => {
+                let id = topics.next_id;
+                topics.next_id += 1;
+                topics.ids_by_name.insert(name.clone(), id);
+                topics.by_id.insert(id, Topic{name: name.clone(), id: id, type_str: type_str.clone(),
+                                              properties: properties, value: None, publishers: 0});
+                id
+            },
+        };
+        let topic = topics.by_id.get_mut(&id).unwrap();
+        if topic.type_str != type_str {
+            return self.log_error(NtError::new(TypeMismatch(name.clone())).with_key(name))
+        }
+        topic.publishers += 1;
+        connection.state.lock().publishers.insert(pubuid, id);
+
+        // The publisher is told the id even if it doesn't subscribe
+        for other in self.connections.lock().iter() {
+            let mut state = other.state.lock();
+            if same_connection(other, connection) {
+                state.announced.insert(id);
+                state.text_queue.push(topic.announce(Some(pubuid)));
+            } else if !state.announced.contains(&id) && state.wants(topic.name.as_slice()).is_some() {
+                state.announced.insert(id);
+                state.text_queue.push(topic.announce(None));
+            }
+        }
+    }
+
+    fn handle_unpublish(&self, connection: &Arc<Nt4Connection>, pubuid: i64) {
+        let mut topics = self.topics.lock();
+        let id = match connection.state.lock().publishers.remove(&pubuid) {
+            Some(id) => id,
+            None => return,
+        };
+        if let Some(topic) = topics.by_id.get_mut(&id) {
+            topic.publishers -= 1;
+        }
+        self.remove_if_unused(&mut *topics, id);
+    }
+
+    /// Removes the topic with `id` once nothing publishes it, unless
+    /// it's retained.
+    fn remove_if_unused(&self, topics: &mut Topics, id: i64) {
+        match topics.by_id.get(&id) {
+            Some(topic) if topic.publishers == 0 && !topic.is_retained() => (),
+            _ => return,
+        }
+        let topic = topics.by_id.remove(&id).unwrap();
+        topics.ids_by_name.remove(&topic.name);
+        for connection in self.connections.lock().iter() {
+            let mut state = connection.state.lock();
+            if state.announced.remove(&id) {
+                state.values.retain(|queued| queued.value.id != id);
+                state.last_sent.remove(&id);
+                state.text_queue.push(nt4::Unannounce(topic.name.clone(), id));
+            }
+        }
+    }
+
+    fn handle_set_properties(&self, connection: &Arc<Nt4Connection>, name: String, update: json::JsonObject) {
+        let mut topics = self.topics.lock();
+        let id = match topics.ids_by_name.get(&name) {
+            Some(id) => *id,
+            None => return,
+        };
+        {
+            let topic = topics.by_id.get_mut(&id).unwrap();
+            nt4::merge_properties(&mut topic.properties, &update);
+            if !topic.is_cached() { topic.value = None }
+        }
+
+        for other in self.connections.lock().iter() {
+            let mut state = other.state.lock();
+            if same_connection(other, connection) {
+                state.text_queue.push(nt4::Properties(name.clone(), true, update.clone()));
+            } else if state.announced.contains(&id) {
+                state.text_queue.push(nt4::Properties(name.clone(), false, update.clone()));
+            }
+        }
+        self.remove_if_unused(&mut *topics, id);
+    }
+
+    fn handle_subscribe(&self, connection: &Arc<Nt4Connection>, subuid: i64, names: Vec<String>,
+                        options: nt4::SubscriptionOptions) {
+        let topics = self.topics.lock();
+        let mut state = connection.state.lock();
+        state.subscriptions.insert(subuid, (names, options));
+
+        let mut ids: Vec<&i64> = topics.by_id.keys().collect();
+        ids.sort();
+        for id in ids.into_iter() {
+            let topic = topics.by_id.get(id).unwrap();
+            let all = match state.wants(topic.name.as_slice()) {
+                Some(all) => all,
+                None => continue,
+            };
+            if state.announced.insert(*id) {
+                state.text_queue.push(topic.announce(None));
+            }
+            match (all, &topic.value) {
+                (Some(all), &Some(ref value)) => {
+                    let period_ns = state.period_ns(topic.name.as_slice());
+                    state.queue_value(value.clone(), all, period_ns)
+                },
+                _ => (),
+            }
+        }
+    }
+
+    fn handle_value(&self, connection: &Arc<Nt4Connection>, value: nt4::ValueMessage) -> NtResult<()> {
+        // Answer time syncs straight away, so the round trip is accurate
+        if value.id == nt4::TIME_SYNC_ID {
+            let reply = nt4::ValueMessage{timestamp: self.now_us(), ..value};
+            let bytes = try!(nt4::write_values([reply].as_slice()));
+            return connection.write(&websocket::Binary(bytes))
+        }
+
+        let mut topics = self.topics.lock();
+        let id = match connection.state.lock().publishers.get(&value.id) {
+            Some(id) => *id,
+            None => {
+                self.log_error(NtError::new(InvalidMessage(format!("Value for unknown pubuid={}.", value.id))));
+                return Ok(())
+            },
+        };
+        let topic = topics.by_id.get_mut(&id).unwrap();
+        if value.type_id != nt4::type_id(topic.type_str.as_slice()) {
+            self.log_error(NtError::new(TypeMismatch(topic.name.clone())).with_key(topic.name.clone()));
+            return Ok(())
+        }
+
+        // Clients that have synced their clocks stamp values themselves,
+        // but a stamp from the future can't be right
+        let now = self.now_us();
+        let timestamp = match value.timestamp {
+            t if t > 0 && t <= now + MAX_CLOCK_SKEW_US => t,
+            _ => now,
+        };
+        let value = nt4::ValueMessage{id: id, timestamp: timestamp, ..value};
+        if topic.is_cached() { topic.value = Some(value.clone()) }
+        for other in self.connections.lock().iter() {
+            // Publishers already have their own values
+            if same_connection(other, connection) { continue }
+            let mut state = other.state.lock();
+            if !state.announced.contains(&id) { continue }
+            if let Some(Some(all)) = state.wants(topic.name.as_slice()) {
+                let period_ns = state.period_ns(topic.name.as_slice());
+                state.queue_value(value.clone(), all, period_ns);
+            }
+        }
+        Ok(())
+    }
+
+    fn drop_connection(&self, connection: &Arc<Nt4Connection>, err: NtError) {
+        self.connections.lock().retain(|c| !same_connection(c, connection));
+        connection.close();
+
+        // Whatever it published is no longer published
+        let mut topics = self.topics.lock();
+        let publishers = mem::replace(&mut connection.state.lock().publishers, HashMap::new());
+        for (_, id) in publishers.into_iter() {
+            if let Some(topic) = topics.by_id.get_mut(&id) {
+                topic.publishers -= 1;
+            }
+            self.remove_if_unused(&mut *topics, id);
+        }
+
+        match err.kind {
+            // Clients hanging up or the server closing isn't an error
+            NetworkProblem(ref e) if e.kind == EndOfFile => (),
+            _ if self.is_closed() => (),
+            _ => self.log_error(err),
+        }
+    }
+
+    fn log_error(&self, err: NtError) {
+        let mut errors = self.errors.lock();
+        errors.push(err);
+    }
+}
+
+impl Nt4Connection {
+    fn new(stream: TcpStream) -> Nt4Connection {
+        Nt4Connection{
+            state: Mutex::new(Nt4State{
+                publishers: HashMap::new(),
+                subscriptions: HashMap::new(),
+                announced: HashSet::new(),
+                text_queue: Vec::new(),
+                values: Vec::new(),
+                last_sent: HashMap::new(),
+            }),
+            stream: Mutex::new(stream),
+        }
+    }
+
+    fn clone_stream(&self) -> TcpStream { self.stream.lock().clone() }
+
+    fn write(&self, frame: &websocket::Frame) -> NtResult<()> {
+        websocket::write_frame(&mut *self.stream.lock(), frame, false)
+    }
+
+    /// Sends queued text messages, and the queued values of each topic
+    /// whose period has passed since its values were last sent.
+    fn flush(&self, now: u64) -> NtResult<()> {
+        let mut state = self.state.lock();
+        if !state.text_queue.is_empty() {
+            let text = nt4::write_server_messages(state.text_queue.as_slice());
+            state.text_queue = Vec::new();
+            try!(self.write(&websocket::Text(text)));
+        }
+        if state.values.is_empty() { return Ok(()) }
+
+        let values = mem::replace(&mut state.values, Vec::new());
+        let (due, waiting) = values.partition(|queued| {
+            state.last_sent.get(&queued.value.id).map_or(true, |&last| now - last >= queued.period_ns)
+        });
+        state.values = waiting;
+        if due.is_empty() { return Ok(()) }
+        let due: Vec<nt4::ValueMessage> = due.into_iter().map(|queued| queued.value).collect();
+        for value in due.iter() {
+            state.last_sent.insert(value.id, now);
+        }
+        let bytes = try!(nt4::write_values(due.as_slice()));
+        self.write(&websocket::Binary(bytes))
+    }
+
+    fn close(&self) {
+        let mut stream = self.clone_stream();
+        let _ = stream.close_read();
+        let _ = stream.close_write();
+    }
+}
+
+impl Nt4State {
+    /// Whether any subscription covers the topic `name`: `None` if not,
+    /// `Some(None)` if only to announce it, and otherwise whether any
+    /// wants every value.
+    fn wants(&self, name: &str) -> Option<Option<bool>> {
+        let mut wants = None;
+        for &(ref topics, ref options) in self.subscriptions.values() {
+            if !options.matches(topics.as_slice(), name) { continue }
+            wants = match (wants, options.topics_only) {
+                (None, true) => Some(None),
+                (None, false) | (Some(None), false) => Some(Some(options.all)),
+                (Some(Some(all)), false) => Some(Some(all || options.all)),
+                (wants, true) => wants,
+            };
+        }
+        wants
+    }
+
+    /// The shortest period of the subscriptions to the values of the
+    /// topic `name`.
+    fn period_ns(&self, name: &str) -> u64 {
+        let mut shortest = None;
+        for &(ref topics, ref options) in self.subscriptions.values() {
+            if options.topics_only || !options.matches(topics.as_slice(), name) { continue }
+            shortest = match shortest {
+                Some(periodic) if periodic <= options.periodic => Some(periodic),
+                _ => Some(options.periodic),
+            };
+        }
+        (shortest.unwrap_or(nt4::DEFAULT_PERIODIC) * 1e9) as u64
+    }
+
+    /// Queues `value` to be sent once `period_ns` has passed since its
+    /// topic's last values, replacing an unsent value of the same topic
+    /// unless `all` values are wanted.
+    fn queue_value(&mut self, value: nt4::ValueMessage, all: bool, period_ns: u64) {
+        if !all {
+            if let Some(queued) = self.values.iter_mut().find(|queued| queued.value.id == value.id) {
+                *queued = QueuedValue{value: value, period_ns: period_ns};
+                return
+            }
+        }
+        self.values.push(QueuedValue{value: value, period_ns: period_ns});
+    }
+}
+
+fn same_connection<T>(a: &Arc<T>, b: &Arc<T>) -> bool {
+    (&**a as *const T) == (&**b as *const T)
+}
+
+/// Tests
+#[cfg(test)]
+mod test {
+    use super::{Server, Nt4Server};
+    use super::super::{nt4, websocket, msgpack};
+    use super::super::protocol;
+    use super::super::protocol::{Message, Entry, EntryType, Number, KeepAlive, HelloComplete,
+                                 ClientHelloComplete, VersionUnsupported, Assignment, Update, FlagsUpdate,
+                                 Delete, ClearAll, Rpc, Raw, ExecuteRpc, RpcResponse, CLIENT_REQUEST_ID,
+                                 CLEAR_ALL_MAGIC, FLAG_PERSISTENT, UNSTAMPED};
+    use super::super::{SequenceNumber, OutOfOrderSequenceNumbers, KeyAlreadyExists, IdDoesntExist,
+                       TypeMismatch, OutOfIds, UnsupportedMessage, UnsupportedType};
+    use super::super::table::{Added, Updated, Deleted};
+    use serialize::json;
+    use std::collections::{HashMap, TreeMap};
+    use std::io::net::tcp::TcpStream;
+    use std::io::timer::sleep;
+    use std::time::Duration;
+
+    /// A client driven directly through the protocol, so tests control
+    /// exactly which sequence numbers are sent.
+    struct SimClient {
+        stream: TcpStream,
+        version: u16,
+        entries: HashMap<u16, Entry>,
+    }
+
+    impl SimClient {
+        fn connect(server: &Server) -> SimClient {
+            SimClient::connect_as(server, protocol::VERSION_3)
+        }
+
+        fn connect_as(server: &Server, version: u16) -> SimClient {
+            let mut stream = TcpStream::connect(server.address()).unwrap();
+            stream.set_read_timeout(Some(2000));
+            protocol::write_hello(&mut stream, version, "sim").unwrap();
+            let mut client = SimClient{stream: stream, version: version, entries: HashMap::new()};
+            while client.recv() != HelloComplete {}
+            if version >= protocol::VERSION_3 {
+                client.send(ClientHelloComplete);
+            }
+            client
+        }
+
+        fn send(&mut self, message: Message) {
+            protocol::write_message(&mut self.stream, &message, self.version).unwrap();
+        }
+
+        fn recv(&mut self) -> Message {
+            loop {
+                let message = {
+                    let entries = &self.entries;
+                    protocol::parse_message(&mut self.stream, self.version, |id| match entries.get(&id) {
+                        Some(e) => Some((e.name.clone(), e.value.clone())),
+                        None => None,
+                    }).unwrap()
+                };
+                match message {
+                    KeepAlive => continue,
+                    Assignment(ref e) => { self.entries.insert(e.id, e.clone()); },
+                    Delete(id) => { self.entries.remove(&id); },
+                    _ => (),
+                }
+                return message
+            }
+        }
+
+        fn recv_assignment(&mut self, name: &str) -> Entry {
+            loop {
+                match self.recv() {
+                    Assignment(e) => if e.name.as_slice() == name { return e },
+                    _ => (),
+                }
+            }
+        }
+
+        fn recv_update(&mut self, sequence: u16) -> Entry {
+            loop {
+                match self.recv() {
+                    Update(e) => if e.sequence == SequenceNumber(sequence) { return e },
+                    _ => (),
+                }
+            }
+        }
+
+        fn assign(&mut self, name: &str, value: EntryType) -> Entry {
+            self.send(Assignment(Entry{name: name.to_string(), id: CLIENT_REQUEST_ID,
+                                       sequence: SequenceNumber(0), flags: 0, value: value,
+                                       timestamp: UNSTAMPED}));
+            self.recv_assignment(name)
+        }
+    }
+
+    fn update(entry: &Entry, sequence: u16, value: f64) -> Message {
+        Update(Entry{sequence: SequenceNumber(sequence), value: Number(value), ..entry.clone()})
+    }
+
+    #[test]
+    fn server_assigns_ids() {
+        let server = Server::new("127.0.0.1:0").unwrap();
+        let mut a = SimClient::connect(&*server);
+        let mut b = SimClient::connect(&*server);
+
+        let entry = a.assign("/Number", Number(1f64));
+        assert!(entry.id != CLIENT_REQUEST_ID);
+        assert_eq!(entry, b.recv_assignment("/Number"));
+
+        // Assigning an existing key returns the existing entry
+        assert_eq!(entry, b.assign("/Number", Number(2f64)));
+        match server.get_errors()[0].kind {
+            KeyAlreadyExists(ref existing, _) => assert_eq!(entry, *existing),
+            ref kind => panic!("Unexpected error {}", kind),
+        }
+        server.close();
+    }
+
+    #[test]
+    fn server_forwards_newer_updates() {
+        let server = Server::new("127.0.0.1:0").unwrap();
+        let mut a = SimClient::connect(&*server);
+        let mut b = SimClient::connect(&*server);
+        let entry = a.assign("/Number", Number(0f64));
+        b.recv_assignment("/Number");
+
+        a.send(update(&entry, 1, 1f64));
+        assert_eq!(Number(1f64), b.recv_update(1).value);
+        a.send(FlagsUpdate(entry.id, FLAG_PERSISTENT));
+        assert_eq!(FlagsUpdate(entry.id, FLAG_PERSISTENT), b.recv());
+
+        // Clients connecting later see the latest value
+        let mut c = SimClient::connect(&*server);
+        let current = c.entries.get(&entry.id).unwrap();
+        assert_eq!(Number(1f64), current.value);
+        assert_eq!(SequenceNumber(1), current.sequence);
+        assert!(server.get_errors().is_empty());
+        server.close();
+    }
+
+    #[test]
+    fn server_speaks_each_clients_version() {
+        let server = Server::new("127.0.0.1:0").unwrap();
+        let mut old = SimClient::connect_as(&*server, protocol::VERSION);
+        let mut new = SimClient::connect(&*server);
+        let entry = new.assign("/Number", Number(0f64));
+        old.recv_assignment("/Number");
+
+        // NT2 clients aren't sent flags updates
+        new.send(FlagsUpdate(entry.id, FLAG_PERSISTENT));
+        new.send(update(&entry, 1, 1f64));
+        assert_eq!(update(&entry, 1, 1f64), old.recv());
+        assert!(server.get_errors().is_empty());
+
+        // Or raw values, or values too long for NT2 lengths
+        let long = protocol::String(String::from_char(70000, 'x'));
+        server.set_entry("/Raw".to_string(), Raw(vec![0x01])).unwrap();
+        server.set_entry("/Long".to_string(), long.clone()).unwrap();
+        server.set_entry("/String".to_string(), protocol::String("x".to_string())).unwrap();
+        match old.recv() {
+            Assignment(e) => assert_eq!("/String", e.name.as_slice()),
+            m => panic!("Unexpected message {}", m),
+        }
+        new.recv_assignment("/Raw");
+        assert_eq!(long, new.recv_assignment("/Long").value);
+
+        // Nor can they send them
+        protocol::write_flags_update(&mut old.stream, entry.id, 0).unwrap();
+        sleep(Duration::milliseconds(100));
+        assert_eq!(UnsupportedMessage(protocol::ENTRY_FLAGS_UPDATE), server.get_errors()[0].kind);
+
+        // Clients offering another version are told the newest one
+        let mut stream = TcpStream::connect(server.address()).unwrap();
+        stream.set_read_timeout(Some(2000));
+        protocol::write_hello(&mut stream, 0x0400, "sim").unwrap();
+        assert_eq!(VersionUnsupported(protocol::VERSION_3),
+                   protocol::parse_message(&mut stream, protocol::VERSION, |_| None).unwrap());
+        server.close();
+    }
+
+    #[test]
+    fn server_says_hello_as_the_spec_does() {
+        let server = Server::new("127.0.0.1:0").unwrap();
+        server.set_entry("/A".to_string(), protocol::Boolean(true)).unwrap();
+
+        // NT3: the server hello with no flags and the server's identity,
+        // then the entries and the server hello complete
+        let mut stream = TcpStream::connect(server.address()).unwrap();
+        stream.set_read_timeout(Some(2000));
+        stream.write(&[0x01, 0x03, 0x00, 0x03, b's', b'i', b'm']).unwrap();
+        let mut expected = vec![0x04, 0x00, 0x0D];
+        expected.push_all(b"networktables");
+        expected.push_all(&[0x10, 0x02, b'/', b'A', 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x03]);
+        assert_eq!(expected, stream.read_exact(expected.len()).unwrap());
+        // The client hello complete ends the handshake
+        stream.write(&[0x05]).unwrap();
+
+        // NT2: no identity and no server hello
+        let mut old = TcpStream::connect(server.address()).unwrap();
+        old.set_read_timeout(Some(2000));
+        old.write(&[0x01, 0x02, 0x00]).unwrap();
+        let expected = vec![0x10, 0x00, 0x02, b'/', b'A', 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x03];
+        assert_eq!(expected, old.read_exact(expected.len()).unwrap());
+        sleep(Duration::milliseconds(100));
+        assert!(server.get_errors().is_empty(), "{}", server.get_errors());
+        server.close();
+    }
+
+    #[test]
+    fn server_sets_entries_and_reports_client_changes() {
+        let server = Server::new("127.0.0.1:0").unwrap();
+        let (tx, rx) = channel();
+        server.add_listener(tx);
+        let mut a = SimClient::connect(&*server);
+
+        // Entries set by the server go to clients, but not listeners
+        server.set_entry("/Local".to_string(), Number(1f64)).unwrap();
+        let local = a.recv_assignment("/Local");
+        server.set_entry("/Local".to_string(), Number(2f64)).unwrap();
+        assert_eq!(Number(2f64), a.recv_update(1).value);
+        let err = server.set_entry("/Local".to_string(), protocol::Boolean(true)).unwrap_err();
+        assert_eq!(TypeMismatch("/Local".to_string()), err.kind);
+
+        let entry = a.assign("/Remote", Number(0f64));
+        assert_eq!(Added("/Remote".to_string(), Number(0f64)), rx.recv());
+        a.send(update(&entry, 1, 1f64));
+        assert_eq!(Updated("/Remote".to_string(), Number(1f64)), rx.recv());
+        a.send(Delete(entry.id));
+        assert_eq!(Deleted("/Remote".to_string()), rx.recv());
+
+        server.delete_entry("/Local".to_string()).unwrap();
+        assert_eq!(Delete(local.id), a.recv());
+        assert!(rx.try_recv().is_err());
+        server.close();
+    }
+
+    #[test]
+    fn server_runs_out_of_ids() {
+        let server = Server::new("127.0.0.1:0").unwrap();
+        let mut a = SimClient::connect(&*server);
+        server.entries.lock().next_id = CLIENT_REQUEST_ID - 1;
+        server.set_entry("/Last".to_string(), Number(0f64)).unwrap();
+        assert_eq!(CLIENT_REQUEST_ID - 1, a.recv_assignment("/Last").id);
+
+        // Neither the server nor clients can add entries once ids run out
+        let err = server.set_entry("/Server".to_string(), Number(0f64)).unwrap_err();
+        assert_eq!(OutOfIds, err.kind);
+        a.send(Assignment(Entry{name: "/Client".to_string(), id: CLIENT_REQUEST_ID,
+                                sequence: SequenceNumber(0), flags: 0, value: Number(0f64),
+                                timestamp: UNSTAMPED}));
+        sleep(Duration::milliseconds(100));
+        assert_eq!(OutOfIds, server.get_errors()[0].kind);
+        assert_eq!(Some("/Client".to_string()), server.get_errors()[0].key);
+        server.close();
+    }
+
+    #[test]
+    fn server_rejects_stale_updates() {
+        let server = Server::new("127.0.0.1:0").unwrap();
+        let mut a = SimClient::connect(&*server);
+        let mut b = SimClient::connect(&*server);
+        let entry = a.assign("/Number", Number(0f64));
+        b.recv_assignment("/Number");
+
+        a.send(update(&entry, 0, 1f64));
+        // Both clients get the current value with a newer sequence number
+        assert_eq!(Number(0f64), a.recv_update(1).value);
+        assert_eq!(Number(0f64), b.recv_update(1).value);
+        match server.get_errors()[0].kind {
+            OutOfOrderSequenceNumbers(old, new) => {
+                assert_eq!(SequenceNumber(0), old);
+                assert_eq!(SequenceNumber(0), new);
+            },
+            ref kind => panic!("Unexpected error {}", kind),
+        }
+        server.close();
+    }
+
+    #[test]
+    fn server_resolves_conflicting_updates() {
+        let server = Server::new("127.0.0.1:0").unwrap();
+        let mut clients = Vec::from_fn(3, |_| SimClient::connect(&*server));
+        let entry = clients[0].assign("/Number", Number(0f64));
+        for client in clients.slice_from_mut(1).iter_mut() {
+            client.recv_assignment("/Number");
+        }
+
+        // Two clients update from the same sequence number. Whichever
+        // reaches the server second loses.
+        clients[1].send(update(&entry, 1, 1f64));
+        clients[2].send(update(&entry, 1, 2f64));
+
+        let values: Vec<EntryType> = clients.iter_mut().map(|c| c.recv_update(2).value).collect();
+        assert!(values[0] == Number(1f64) || values[0] == Number(2f64));
+        assert_eq!(values[0], values[1]);
+        assert_eq!(values[0], values[2]);
+        assert_eq!(1, server.get_errors().len());
+        server.close();
+    }
+
+    #[test]
+    fn server_limits_bandwidth() {
+        let server = Server::new("127.0.0.1:0").unwrap();
+        let mut a = SimClient::connect(&*server);
+        let mut b = SimClient::connect(&*server);
+        let entry = a.assign("/Number", Number(0f64));
+        b.recv_assignment("/Number");
+
+        // The first update uses up the budget, so the rest wait and
+        // replace each other in the queue.
+        server.set_bandwidth_limit(Some(1));
+        a.send(update(&entry, 1, 1f64));
+        b.recv_update(1);
+        for i in range(2u16, 6u16) {
+            a.send(update(&entry, i, i as f64));
+        }
+        sleep(Duration::milliseconds(100));
+        let stats = server.stats();
+        assert!(stats.deferred_flushes > 0);
+        assert_eq!(3, stats.coalesced);
+        assert!(stats.send_queue_depth >= 1);
+
+        server.set_bandwidth_limit(None);
+        match b.recv() {
+            Update(e) => assert_eq!(update(&entry, 5, 5f64), Update(e)),
+            m => panic!("Unexpected message {}", m),
+        }
+        server.close();
+    }
+
+    #[test]
+    fn server_deletes_entries() {
+        let server = Server::new("127.0.0.1:0").unwrap();
+        let mut a = SimClient::connect(&*server);
+        let mut b = SimClient::connect(&*server);
+        let first = a.assign("/First", Number(1f64));
+        a.assign("/Second", Number(2f64));
+        b.recv_assignment("/Second");
+
+        a.send(Delete(first.id));
+        assert_eq!(Delete(first.id), b.recv());
+        assert!(!b.entries.contains_key(&first.id));
+
+        // A clear with the wrong magic is ignored
+        a.send(ClearAll(0));
+        a.send(ClearAll(CLEAR_ALL_MAGIC));
+        assert_eq!(ClearAll(CLEAR_ALL_MAGIC), b.recv());
+
+        // Clients connecting later don't see deleted entries
+        let c = SimClient::connect(&*server);
+        assert!(c.entries.is_empty());
+        assert!(server.get_errors().is_empty());
+        server.close();
+    }
+
+    #[test]
+    fn server_drops_nt2_clients_it_cant_answer() {
+        let server = Server::new("127.0.0.1:0").unwrap();
+        server.set_entry("/Raw".to_string(), Raw(vec![0x01])).unwrap();
+        let mut old = SimClient::connect_as(&*server, protocol::VERSION);
+
+        // The existing entry can't be sent over NT2, so rather than
+        // leave the client waiting on its id, the server hangs up
+        old.send(Assignment(Entry{name: "/Raw".to_string(), id: CLIENT_REQUEST_ID,
+                                  sequence: SequenceNumber(0), flags: 0, value: Number(1f64),
+                                  timestamp: UNSTAMPED}));
+        assert!(protocol::parse_message(&mut old.stream, protocol::VERSION, |_| None).is_err());
+        sleep(Duration::milliseconds(100));
+        let errors = server.get_errors();
+        match errors[0].kind {
+            KeyAlreadyExists(ref existing, _) => assert_eq!("/Raw", existing.name.as_slice()),
+            ref kind => panic!("Unexpected error {}", kind),
+        }
+        assert_eq!(UnsupportedType(0x03), errors[1].kind);
+        server.close();
+    }
+
+    #[test]
+    fn server_only_sends_deletes_to_nt3_clients() {
+        let server = Server::new("127.0.0.1:0").unwrap();
+        let mut old = SimClient::connect_as(&*server, protocol::VERSION);
+        let mut new = SimClient::connect(&*server);
+        server.set_entry("/First".to_string(), Number(1f64)).unwrap();
+        let first = new.recv_assignment("/First");
+        old.recv_assignment("/First");
+
+        server.delete_entry("/First".to_string()).unwrap();
+        new.send(ClearAll(CLEAR_ALL_MAGIC));
+        server.set_entry("/Second".to_string(), Number(2f64)).unwrap();
+        assert_eq!(Delete(first.id), new.recv());
+        // The NT2 client's next message is the assignment
+        match old.recv() {
+            Assignment(e) => assert_eq!("/Second", e.name.as_slice()),
+            m => panic!("Unexpected message {}", m),
+        }
+        assert!(server.get_errors().is_empty());
+        server.close();
+    }
+
+    fn reverse(mut params: Vec<u8>) -> Vec<u8> {
+        params.reverse();
+        params
+    }
+
+    #[test]
+    fn server_executes_rpcs() {
+        let server = Server::new("127.0.0.1:0").unwrap();
+        server.register_rpc("/Reverse".to_string(), vec![0x01],
+                            box (reverse as fn(Vec<u8>) -> Vec<u8>)).unwrap();
+        let mut a = SimClient::connect(&*server);
+        let entry = a.entries.values().next().unwrap().clone();
+        assert_eq!(Rpc(vec![0x01]), entry.value);
+
+        a.send(ExecuteRpc(entry.id, 7, vec![1, 2, 3]));
+        assert_eq!(RpcResponse(entry.id, 7, vec![3, 2, 1]), a.recv());
+
+        // Registering the same key again fails
+        assert!(server.register_rpc("/Reverse".to_string(), Vec::new(),
+                                    box (reverse as fn(Vec<u8>) -> Vec<u8>)).is_err());
+
+        // Calls to unknown procedures are logged and not answered
+        a.send(ExecuteRpc(entry.id + 1, 8, Vec::new()));
+        a.send(ExecuteRpc(entry.id, 9, Vec::new()));
+        assert_eq!(RpcResponse(entry.id, 9, Vec::new()), a.recv());
+        match server.get_errors()[0].kind {
+            IdDoesntExist(id) => assert_eq!(entry.id + 1, id),
+            ref kind => panic!("Unexpected error {}", kind),
+        }
+        server.close();
+    }
+
+    /// An NT4 client driven directly through the protocol.
+    struct SimNt4Client {
+        stream: TcpStream,
+    }
+
+    impl SimNt4Client {
+        fn connect(server: &Nt4Server) -> SimNt4Client {
+            let mut stream = TcpStream::connect(server.address()).unwrap();
+            stream.set_read_timeout(Some(2000));
+            websocket::connect(&mut stream, server.address(), "/nt/sim",
+                               websocket::NT4_PROTOCOLS.as_slice()).unwrap();
+            SimNt4Client{stream: stream}
+        }
+
+        fn send(&mut self, messages: Vec<nt4::ClientMessage>) {
+            let text = nt4::write_client_messages(messages.as_slice());
+            websocket::write_frame(&mut self.stream, &websocket::Text(text), true).unwrap();
+        }
+
+        fn send_value(&mut self, id: i64, type_id: u8, value: msgpack::Value) {
+            self.send_stamped_value(id, type_id, value, 0)
+        }
+
+        fn send_stamped_value(&mut self, id: i64, type_id: u8, value: msgpack::Value, timestamp: i64) {
+            let value = nt4::ValueMessage{id: id, timestamp: timestamp, type_id: type_id, value: value};
+            let bytes = nt4::write_values([value].as_slice()).unwrap();
+            websocket::write_frame(&mut self.stream, &websocket::Binary(bytes), true).unwrap();
+        }
+
+        fn recv_text(&mut self) -> Vec<nt4::ServerMessage> {
+            match websocket::read_frame(&mut self.stream).unwrap() {
+                websocket::Text(text) => nt4::parse_server_messages(text.as_slice()).unwrap(),
+                frame => panic!("Unexpected frame {}", frame),
+            }
+        }
+
+        fn recv_values(&mut self) -> Vec<nt4::ValueMessage> {
+            match websocket::read_frame(&mut self.stream).unwrap() {
+                websocket::Binary(bytes) => nt4::parse_values(bytes.as_slice()).unwrap(),
+                frame => panic!("Unexpected frame {}", frame),
+            }
+        }
+
+        fn publish(&mut self, name: &str, pubuid: i64, type_str: &str, properties: json::JsonObject) -> i64 {
+            self.send(vec![nt4::Publish(name.to_string(), pubuid, type_str.to_string(), properties)]);
+            match self.recv_text()[0] {
+                nt4::Announce(_, id, _, Some(p), _) if p == pubuid => id,
+                ref m => panic!("Unexpected message {}", m),
+            }
+        }
+
+        fn subscribe(&mut self, subuid: i64, topics: &[&str], options: nt4::SubscriptionOptions) {
+            let topics = topics.iter().map(|t| t.to_string()).collect();
+            self.send(vec![nt4::Subscribe(subuid, topics, options)]);
+        }
+    }
+
+    fn prefix() -> nt4::SubscriptionOptions {
+        nt4::SubscriptionOptions{prefix: true, ..nt4::SubscriptionOptions::new()}
+    }
+
+    fn properties(pairs: &[(&str, bool)]) -> json::JsonObject {
+        let mut properties = TreeMap::new();
+        for &(name, value) in pairs.iter() {
+            properties.insert(name.to_string(), json::Boolean(value));
+        }
+        properties
+    }
+
+    #[test]
+    fn nt4_server_announces_and_forwards_values() {
+        let server = Nt4Server::new("127.0.0.1:0").unwrap();
+        let mut a = SimNt4Client::connect(&*server);
+        let mut b = SimNt4Client::connect(&*server);
+        let id = a.publish("/Shooter/speed", 5, "double", properties(&[]));
+
+        b.subscribe(1, ["/Shooter/"].as_slice(), prefix());
+        assert_eq!(vec![nt4::Announce("/Shooter/speed".to_string(), id, "double".to_string(), None,
+                                      properties(&[]))], b.recv_text());
+
+        let before = server.now_us();
+        a.send_value(5, nt4::TYPE_DOUBLE, msgpack::F64(3000f64));
+        let values = b.recv_values();
+        assert_eq!(1, values.len());
+        assert_eq!(id, values[0].id);
+        assert_eq!(msgpack::F64(3000f64), values[0].value);
+        // Stamped with the server's clock, not the client's 0
+        assert!(values[0].timestamp >= before && values[0].timestamp <= server.now_us());
+
+        // Or the client's own stamp, unless it's ahead of the server
+        a.send_stamped_value(5, nt4::TYPE_DOUBLE, msgpack::F64(3100f64), 1);
+        assert_eq!(1, b.recv_values()[0].timestamp);
+        let before = server.now_us();
+        a.send_stamped_value(5, nt4::TYPE_DOUBLE, msgpack::F64(3200f64), before + 60000000);
+        let stamped = b.recv_values()[0].timestamp;
+        assert!(stamped >= before && stamped <= server.now_us());
+        a.send_stamped_value(5, nt4::TYPE_DOUBLE, msgpack::F64(3300f64), -5);
+        let values = b.recv_values();
+        assert!(values[0].timestamp >= before && values[0].timestamp <= server.now_us());
+
+        // New subscribers get the cached value
+        let mut c = SimNt4Client::connect(&*server);
+        c.subscribe(1, ["/Shooter/speed"].as_slice(), nt4::SubscriptionOptions::new());
+        c.recv_text();
+        assert_eq!(values, c.recv_values());
+        assert!(server.get_errors().is_empty());
+        server.close();
+    }
+
+    #[test]
+    fn nt4_server_removes_unretained_topics() {
+        let server = Nt4Server::new("127.0.0.1:0").unwrap();
+        let mut a = SimNt4Client::connect(&*server);
+        let mut b = SimNt4Client::connect(&*server);
+        b.subscribe(1, ["/"].as_slice(), nt4::SubscriptionOptions{topics_only: true, ..prefix()});
+        let temp = a.publish("/temp", 1, "int", properties(&[]));
+        let kept = a.publish("/kept", 2, "int", properties(&[("retained", true)]));
+        a.publish("/uncached", 3, "int", properties(&[("cached", false)]));
+        assert_eq!(3, b.recv_text().len() + b.recv_text().len() + b.recv_text().len());
+
+        a.send(vec![nt4::Unpublish(1), nt4::Unpublish(2)]);
+        assert_eq!(vec![nt4::Unannounce("/temp".to_string(), temp)], b.recv_text());
+        assert_eq!(vec![nt4::Unannounce("/temp".to_string(), temp)], a.recv_text());
+        assert_eq!(vec!["/kept".to_string(), "/uncached".to_string()], server.topic_names());
+
+        // Uncached values are forwarded but not kept for later subscribers
+        a.send_value(3, nt4::TYPE_INT, msgpack::Int(1));
+        let mut c = SimNt4Client::connect(&*server);
+        c.subscribe(1, ["/uncached"].as_slice(), nt4::SubscriptionOptions::new());
+        c.recv_text();
+        c.send_value(nt4::TIME_SYNC_ID, nt4::TYPE_INT, msgpack::Int(42));
+        assert_eq!(msgpack::Int(42), c.recv_values()[0].value);
+
+        // Dropping the retained property lets the topic go
+        let mut update = TreeMap::new();
+        update.insert("retained".to_string(), json::Null);
+        a.send(vec![nt4::SetProperties("/kept".to_string(), update.clone())]);
+        assert_eq!(nt4::Properties("/kept".to_string(), true, update.clone()), a.recv_text()[0]);
+        assert_eq!(vec![nt4::Properties("/kept".to_string(), false, update),
+                        nt4::Unannounce("/kept".to_string(), kept)], b.recv_text());
+        assert_eq!(vec!["/uncached".to_string()], server.topic_names());
+        assert!(server.get_errors().is_empty());
+        server.close();
+    }
+
+    #[test]
+    fn nt4_server_rejects_repeated_pubuids() {
+        let server = Nt4Server::new("127.0.0.1:0").unwrap();
+        let mut a = SimNt4Client::connect(&*server);
+        let mut b = SimNt4Client::connect(&*server);
+        b.subscribe(1, ["/"].as_slice(), prefix());
+        let first = a.publish("/first", 1, "int", properties(&[]));
+        b.recv_text();
+
+        a.send(vec![nt4::Publish("/second".to_string(), 1, "int".to_string(), properties(&[]))]);
+        a.send_value(1, nt4::TYPE_INT, msgpack::Int(5));
+        assert_eq!(first, b.recv_values()[0].id);
+        assert_eq!(vec!["/first".to_string()], server.topic_names());
+        assert_eq!(1, server.get_errors().len());
+
+        // So the topic goes once its one publisher is gone
+        a.send(vec![nt4::Unpublish(1)]);
+        assert_eq!(vec![nt4::Unannounce("/first".to_string(), first)], b.recv_text());
+        assert!(server.topic_names().is_empty());
+        server.close();
+    }
+
+    #[test]
+    fn nt4_server_sends_each_topic_at_its_own_rate() {
+        let server = Nt4Server::new("127.0.0.1:0").unwrap();
+        let mut a = SimNt4Client::connect(&*server);
+        let mut b = SimNt4Client::connect(&*server);
+        let fast = a.publish("/fast", 1, "int", properties(&[]));
+        let slow = a.publish("/slow", 2, "int", properties(&[]));
+        b.send(vec![nt4::Subscribe(1, vec!["/fast".to_string()],
+                                   nt4::SubscriptionOptions{periodic: 0.01, ..nt4::SubscriptionOptions::new()}),
+                    nt4::Subscribe(2, vec!["/slow".to_string()],
+                                   nt4::SubscriptionOptions{periodic: 1.0, ..nt4::SubscriptionOptions::new()})]);
+        let mut announced = 0;
+        while announced < 2 { announced += b.recv_text().len() }
+
+        // Each topic's first value goes straight out
+        a.send_value(1, nt4::TYPE_INT, msgpack::Int(1));
+        a.send_value(2, nt4::TYPE_INT, msgpack::Int(1));
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            received.extend(b.recv_values().into_iter().map(|v| v.id));
+        }
+        received.sort();
+        assert_eq!(vec![fast, slow], received);
+
+        // Then the fast topic doesn't wait on the slow one
+        a.send_value(1, nt4::TYPE_INT, msgpack::Int(2));
+        a.send_value(2, nt4::TYPE_INT, msgpack::Int(2));
+        let values = b.recv_values();
+        assert_eq!(1, values.len());
+        assert_eq!((fast, msgpack::Int(2)), (values[0].id, values[0].value.clone()));
+        let values = b.recv_values();
+        assert_eq!((slow, msgpack::Int(2)), (values[0].id, values[0].value.clone()));
+        assert!(server.get_errors().is_empty());
+        server.close();
+    }
+
+    #[test]
+    fn nt4_server_syncs_time() {
+        let server = Nt4Server::new("127.0.0.1:0").unwrap();
+        let mut a = SimNt4Client::connect(&*server);
+        a.send_value(nt4::TIME_SYNC_ID, nt4::TYPE_INT, msgpack::Int(1234));
+        let reply = a.recv_values();
+        assert_eq!(nt4::TIME_SYNC_ID, reply[0].id);
+        // The client's time is echoed back with the server's
+        assert_eq!(msgpack::Int(1234), reply[0].value);
+        assert!(reply[0].timestamp <= server.now_us());
+        server.close();
+    }
+
+    #[test]
+    fn nt4_server_sends_every_value_when_asked() {
+        let server = Nt4Server::new("127.0.0.1:0").unwrap();
+        let mut a = SimNt4Client::connect(&*server);
+        let mut b = SimNt4Client::connect(&*server);
+        a.publish("/count", 1, "int", properties(&[]));
+        b.subscribe(1, ["/count"].as_slice(), nt4::SubscriptionOptions{all: true, periodic: 0.5,
+                                                                         ..nt4::SubscriptionOptions::new()});
+        b.recv_text();
+        for i in range(0i64, 3) {
+            a.send_value(1, nt4::TYPE_INT, msgpack::Int(i));
+        }
+        let mut received = Vec::new();
+        while received.len() < 3 {
+            received.extend(b.recv_values().into_iter().map(|v| v.value));
+        }
+        assert_eq!(vec![msgpack::Int(0), msgpack::Int(1), msgpack::Int(2)], received);
+
+        // A value of the wrong type is dropped
+        a.send_value(1, nt4::TYPE_DOUBLE, msgpack::F64(1f64));
+        sleep(Duration::milliseconds(100));
+        assert_eq!(1, server.get_errors().len());
+        server.close();
+    }
+}
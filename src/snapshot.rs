@@ -0,0 +1,78 @@
+use super::protocol;
+use super::table::Get;
+
+use std::collections::HashMap;
+
+/// An immutable copy of the entries under a prefix, all taken at the
+/// same moment. Reading several related values from a snapshot
+/// guarantees none of them changed in between.
+///
+/// # Example
+///
+/// ```ignore
+/// let vision = client.snapshot("/Vision/");
+/// let x: Option<f64> = vision.get("/Vision/x".to_string());
+/// let y: Option<f64> = vision.get("/Vision/y".to_string());
+/// ```
+#[deriving(PartialEq,Clone,Show)]
+pub struct Snapshot {
+    entries: HashMap<String, protocol::EntryType>,
+}
+
+impl Snapshot {
+    pub fn new(entries: HashMap<String, protocol::EntryType>) -> Snapshot {
+        Snapshot{entries: entries}
+    }
+
+    /// The keys in the snapshot, sorted.
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self.entries.keys().map(|k| k.clone()).collect();
+        keys.sort();
+        keys
+    }
+
+    pub fn contains_key(&self, key: &String) -> bool { self.entries.contains_key(key) }
+    pub fn len(&self) -> uint { self.entries.len() }
+    pub fn is_empty(&self) -> bool { self.entries.is_empty() }
+
+    /// Returns the raw value for a key, whatever its type.
+    pub fn get_entry(&self, key: &String) -> Option<&protocol::EntryType> {
+        self.entries.get(key)
+    }
+}
+
+impl Get<bool> for Snapshot {
+    fn get(&self, key: String) -> Option<bool> {
+        match self.entries.get(&key) {
+            Some(&protocol::Boolean(b)) => Some(b),
+            _ => None,
+        }
+    }
+}
+
+impl Get<f64> for Snapshot {
+    fn get(&self, key: String) -> Option<f64> {
+        match self.entries.get(&key) {
+            Some(&protocol::Number(n)) => Some(n),
+            _ => None,
+        }
+    }
+}
+
+impl Get<String> for Snapshot {
+    fn get(&self, key: String) -> Option<String> {
+        match self.entries.get(&key) {
+            Some(&protocol::String(ref s)) => Some(s.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl Get<Vec<u8>> for Snapshot {
+    fn get(&self, key: String) -> Option<Vec<u8>> {
+        match self.entries.get(&key) {
+            Some(&protocol::Raw(ref bytes)) => Some(bytes.clone()),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use super::protocol;
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// The number and total size of messages of one type.
+#[deriving(PartialEq,Clone,Show)]
+pub struct MessageStats {
+    pub messages: u64,
+    pub bytes: u64,
+}
+
+/// Counters describing a connection's traffic, returned by
+/// `Client::stats`. Useful for checking how close a robot is to the
+/// field's bandwidth cap.
+#[deriving(PartialEq,Clone,Show)]
+pub struct Stats {
+    /// Messages sent, by message type.
+    pub sent: HashMap<u8, MessageStats>,
+    /// Messages received, by message type.
+    pub received: HashMap<u8, MessageStats>,
+    /// The number of times the send queue was written out.
+    pub flushes: u64,
+    /// The total time spent writing out the send queue.
+    pub flush_time_ns: u64,
+    /// The number of messages waiting to be sent, not counting keep
+    /// alives.
+    pub send_queue_depth: uint,
+    /// The number of updates rejected for having an old sequence number.
+    pub out_of_order: u64,
+    /// The number of flushes put off by the bandwidth limit.
+    pub deferred_flushes: u64,
+    /// The number of queued values replaced by a newer value for the
+    /// same key before being sent.
+    pub coalesced: u64,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats{sent: HashMap::new(), received: HashMap::new(), flushes: 0, flush_time_ns: 0,
+              send_queue_depth: 0, out_of_order: 0, deferred_flushes: 0, coalesced: 0}
+    }
+
+    pub fn record_sent(&mut self, message: u8, bytes: u64) {
+        Stats::record(&mut self.sent, message, bytes)
+    }
+
+    pub fn record_received(&mut self, message: u8, bytes: u64) {
+        Stats::record(&mut self.received, message, bytes)
+    }
+
+    pub fn record_flush(&mut self, ns: u64) {
+        self.flushes += 1;
+        self.flush_time_ns += ns;
+    }
+
+    fn record(counts: &mut HashMap<u8, MessageStats>, message: u8, bytes: u64) {
+        if let Some(stats) = counts.get_mut(&message) {
+            stats.messages += 1;
+            stats.bytes += bytes;
+            return
+        }
+        counts.insert(message, MessageStats{messages: 1, bytes: bytes});
+    }
+
+    /// The number of messages of type `message` sent.
+    pub fn messages_sent(&self, message: u8) -> u64 {
+        self.sent.get(&message).map_or(0, |s| s.messages)
+    }
+
+    /// The number of messages of type `message` received.
+    pub fn messages_received(&self, message: u8) -> u64 {
+        self.received.get(&message).map_or(0, |s| s.messages)
+    }
+
+    pub fn keep_alives_sent(&self) -> u64 {
+        self.messages_sent(protocol::KEEP_ALIVE)
+    }
+
+    pub fn bytes_sent(&self) -> u64 {
+        self.sent.values().fold(0, |total, s| total + s.bytes)
+    }
+
+    pub fn bytes_received(&self) -> u64 {
+        self.received.values().fold(0, |total, s| total + s.bytes)
+    }
+
+    /// The average time taken to write out the send queue, or zero if
+    /// it hasn't been yet.
+    pub fn average_flush_latency(&self) -> Duration {
+        match self.flushes {
+            0 => Duration::zero(),
+            n => Duration::nanoseconds((self.flush_time_ns / n) as i64),
+        }
+    }
+}
+
+/// The number of messages in `queue` waiting to be sent, not counting
+/// keep alives since they don't carry entries.
+pub fn queue_depth(queue: &[protocol::Message]) -> uint {
+    queue.iter().filter(|m| m.message_type() != protocol::KEEP_ALIVE).count()
+}
+
+/// Tests
+#[cfg(test)]
+mod test {
+    use super::Stats;
+    use std::time::Duration;
+
+    #[test]
+    fn stats_counts() {
+        let mut stats = Stats::new();
+        assert_eq!(0, stats.bytes_sent());
+        assert_eq!(Duration::zero(), stats.average_flush_latency());
+
+        stats.record_sent(0x00, 1);
+        stats.record_sent(0x10, 12);
+        stats.record_sent(0x10, 14);
+        stats.record_received(0x11, 8);
+        assert_eq!(2, stats.messages_sent(0x10));
+        assert_eq!(1, stats.messages_sent(0x00));
+        assert_eq!(0, stats.messages_sent(0x11));
+        assert_eq!(1, stats.messages_received(0x11));
+        assert_eq!(27, stats.bytes_sent());
+        assert_eq!(8, stats.bytes_received());
+
+        stats.record_flush(1000);
+        stats.record_flush(3000);
+        assert_eq!(Duration::nanoseconds(2000), stats.average_flush_latency());
+    }
+}
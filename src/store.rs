@@ -0,0 +1,275 @@
+use super::protocol::Entry;
+use super::snapshot::Snapshot;
+
+use std::collections::{HashMap, HashSet};
+use std::mem;
+
+/// The entries known to a client. Each entry is stored once, in a slab
+/// indexed by id, with an index from name to id alongside it. Entries
+/// set locally that the server hasn't assigned an id yet are kept
+/// separately until it does.
+pub struct Store {
+    slab: Vec<Option<Entry>>,
+    ids: HashMap<String, u16>,
+    pending: HashMap<String, Entry>,
+    /// Names deleted after their request for an id went out. The server
+    /// assigns them anyway, so they're deleted once it does.
+    deleted: HashSet<String>,
+}
+
+impl Store {
+    pub fn new() -> Store {
+        Store{slab: Vec::new(), ids: HashMap::new(), pending: HashMap::new(), deleted: HashSet::new()}
+    }
+
+    /// Returns the entry with `name`, whether or not it's been assigned.
+    pub fn get(&self, name: &String) -> Option<&Entry> {
+        match self.get_assigned(name) {
+            Some(entry) => Some(entry),
+            None => self.pending.get(name),
+        }
+    }
+
+    /// Returns the entry with `name` if the server has assigned it.
+    pub fn get_assigned(&self, name: &String) -> Option<&Entry> {
+        match self.ids.get(name) {
+            Some(&id) => self.get_by_id(id),
+            None => None,
+        }
+    }
+
+    pub fn get_by_id(&self, id: u16) -> Option<&Entry> {
+        match self.slab.get(id as uint) {
+            Some(&Some(ref entry)) => Some(entry),
+            _ => None,
+        }
+    }
+
+    pub fn get_assigned_mut(&mut self, name: &String) -> Option<&mut Entry> {
+        match self.ids.get(name) {
+            Some(&id) => match self.slab.get_mut(id as uint) {
+                Some(&Some(ref mut entry)) => Some(entry),
+                _ => None,
+            },
+            None => None,
+        }
+    }
+
+    pub fn get_by_id_mut(&mut self, id: u16) -> Option<&mut Entry> {
+        match self.slab.get_mut(id as uint) {
+            Some(&Some(ref mut entry)) => Some(entry),
+            _ => None,
+        }
+    }
+
+    /// Inserts an assigned entry, replacing any entry with the same id.
+    pub fn insert(&mut self, entry: Entry) {
+        let index = entry.id as uint;
+        while self.slab.len() <= index {
+            self.slab.push(None);
+        }
+        if let Some(ref old) = self.slab[index] {
+            if old.name != entry.name {
+                self.ids.remove(&old.name);
+            }
+        }
+        self.ids.insert(entry.name.clone(), entry.id);
+        self.slab[index] = Some(entry);
+    }
+
+    /// Removes the entry with `name`, whether or not it's been assigned.
+    pub fn remove(&mut self, name: &String) -> Option<Entry> {
+        match self.ids.get(name) {
+            Some(&id) => self.remove_by_id(id),
+            None => self.pending.remove(name),
+        }
+    }
+
+    pub fn remove_by_id(&mut self, id: u16) -> Option<Entry> {
+        let entry = match self.slab.get_mut(id as uint) {
+            Some(slot) => slot.take(),
+            None => None,
+        };
+        if let Some(ref entry) = entry {
+            self.ids.remove(&entry.name);
+        }
+        entry
+    }
+
+    /// Removes every entry, returning the names of those removed. Names
+    /// marked deleted stay marked.
+    pub fn clear(&mut self) -> Vec<String> {
+        let mut names: Vec<String> = self.ids.keys().chain(self.pending.keys()).map(|k| k.clone()).collect();
+        names.sort();
+        let deleted = mem::replace(&mut self.deleted, HashSet::new());
+        *self = Store{deleted: deleted, ..Store::new()};
+        names
+    }
+
+    /// Copies the values of every entry whose name starts with `prefix`.
+    pub fn snapshot(&self, prefix: &str) -> Snapshot {
+        let mut values = HashMap::new();
+        let assigned = self.slab.iter().filter_map(|slot| slot.as_ref());
+        for entry in assigned.chain(self.pending.values()) {
+            if entry.name.as_slice().starts_with(prefix) {
+                values.insert(entry.name.clone(), entry.value.clone());
+            }
+        }
+        Snapshot::new(values)
+    }
+
+    pub fn get_pending_mut(&mut self, name: &String) -> Option<&mut Entry> {
+        self.pending.get_mut(name)
+    }
+
+    /// Inserts an entry waiting on an id. Setting a name again cancels
+    /// deleting it once assigned, since the server's assignment is now
+    /// the new entry's.
+    pub fn insert_pending(&mut self, entry: Entry) {
+        self.deleted.remove(&entry.name);
+        self.pending.insert(entry.name.clone(), entry);
+    }
+
+    pub fn remove_pending(&mut self, name: &String) -> Option<Entry> {
+        self.pending.remove(name)
+    }
+
+    pub fn pending_names(&self) -> Vec<String> {
+        self.pending.keys().map(|k| k.clone()).collect()
+    }
+
+    /// Marks `name` to be deleted once the server assigns it.
+    pub fn mark_deleted(&mut self, name: String) {
+        self.deleted.insert(name);
+    }
+
+    /// Unmarks `name`, returning whether it was marked deleted.
+    pub fn take_deleted(&mut self, name: &String) -> bool {
+        self.deleted.remove(name)
+    }
+}
+
+/// Tests
+#[cfg(test)]
+mod test {
+    use super::Store;
+    use super::super::protocol::{Entry, Number, UNSTAMPED};
+    use super::super::SequenceNumber;
+
+    fn entry(name: &str, id: u16, value: f64) -> Entry {
+        Entry{name: name.to_string(), id: id, sequence: SequenceNumber(0), flags: 0, value: Number(value),
+              timestamp: UNSTAMPED}
+    }
+
+    #[test]
+    fn store_indexes() {
+        let mut store = Store::new();
+        store.insert(entry("/A", 3, 1f64));
+        store.insert_pending(entry("/B", 0xFFFF, 2f64));
+
+        assert_eq!(Some(&entry("/A", 3, 1f64)), store.get(&"/A".to_string()));
+        assert_eq!(Some(&entry("/A", 3, 1f64)), store.get_by_id(3));
+        assert_eq!(Some(&entry("/B", 0xFFFF, 2f64)), store.get(&"/B".to_string()));
+        assert_eq!(None, store.get_assigned(&"/B".to_string()));
+        assert_eq!(None, store.get_by_id(0));
+        assert_eq!(None, store.get_by_id(4));
+
+        // Reusing an id drops the old name
+        store.insert(entry("/C", 3, 3f64));
+        assert_eq!(None, store.get(&"/A".to_string()));
+        assert_eq!(Some(&entry("/C", 3, 3f64)), store.get_by_id(3));
+
+        let snapshot = store.snapshot("/");
+        assert_eq!(vec!["/B".to_string(), "/C".to_string()], snapshot.keys());
+        assert_eq!(1, store.snapshot("/C").len());
+
+        assert_eq!(Some(entry("/B", 0xFFFF, 2f64)), store.remove_pending(&"/B".to_string()));
+        assert_eq!(None, store.get(&"/B".to_string()));
+
+        store.insert(entry("/D", 1, 4f64));
+        store.insert_pending(entry("/E", 0xFFFF, 5f64));
+        assert_eq!(Some(entry("/D", 1, 4f64)), store.remove(&"/D".to_string()));
+        assert_eq!(None, store.get_by_id(1));
+        assert_eq!(None, store.remove_by_id(1));
+        assert_eq!(vec!["/C".to_string(), "/E".to_string()], store.clear());
+        assert!(store.snapshot("").is_empty());
+    }
+
+    #[test]
+    fn store_marks_deleted_names() {
+        let mut store = Store::new();
+        store.mark_deleted("/A".to_string());
+        store.mark_deleted("/B".to_string());
+        store.clear();
+        assert!(store.take_deleted(&"/A".to_string()));
+        assert!(!store.take_deleted(&"/A".to_string()));
+
+        // Setting it again cancels the delete
+        store.insert_pending(entry("/B", 0xFFFF, 1f64));
+        assert!(!store.take_deleted(&"/B".to_string()));
+    }
+}
+
+/// Benchmarks comparing many threads reading from the store against
+/// the pair of mutex guarded maps it replaced.
+#[cfg(test)]
+mod bench {
+    extern crate test;
+
+    use self::test::Bencher;
+    use super::Store;
+    use super::super::protocol::{Entry, Number, UNSTAMPED};
+    use super::super::SequenceNumber;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex, RWLock};
+
+    const THREADS: uint = 8;
+    const GETS: uint = 1000;
+    const KEYS: uint = 100;
+
+    fn key(i: uint) -> String { format!("/Bench/{}", i % KEYS) }
+
+    fn entry(i: uint) -> Entry {
+        Entry{name: key(i), id: i as u16, sequence: SequenceNumber(0), flags: 0,
+              value: Number(i as f64), timestamp: UNSTAMPED}
+    }
+
+    /// Runs `f` on `THREADS` threads at once and waits for them all.
+    fn contended<T: Send + Sync>(shared: &Arc<T>, f: fn(&T, uint)) {
+        let (tx, rx) = channel();
+        for t in range(0, THREADS) {
+            let (shared, tx) = (shared.clone(), tx.clone());
+            spawn(proc() {
+                for i in range(0, GETS) { f(&*shared, t + i) }
+                tx.send(());
+            });
+        }
+        for _ in range(0, THREADS) { rx.recv() }
+    }
+
+    #[bench]
+    fn bench_mutex_maps_contended_get(b: &mut Bencher) {
+        let mut by_name = HashMap::new();
+        for i in range(0, KEYS) { by_name.insert(key(i), entry(i)); }
+        let shared = Arc::new(Mutex::new(by_name));
+
+        fn get(map: &Mutex<HashMap<String, Entry>>, i: uint) {
+            let map = map.lock();
+            test::black_box(map.get(&key(i)).map(|e| e.value.clone()));
+        }
+        b.iter(|| contended(&shared, get));
+    }
+
+    #[bench]
+    fn bench_store_contended_get(b: &mut Bencher) {
+        let mut store = Store::new();
+        for i in range(0, KEYS) { store.insert(entry(i)); }
+        let shared = Arc::new(RWLock::new(store));
+
+        fn get(store: &RWLock<Store>, i: uint) {
+            let store = store.read();
+            test::black_box(store.get(&key(i)).map(|e| e.value.clone()));
+        }
+        b.iter(|| contended(&shared, get));
+    }
+}
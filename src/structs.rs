@@ -0,0 +1,471 @@
+use super::protocol;
+use super::protocol::{Boolean, Number};
+use super::snapshot::Snapshot;
+use super::{NtResult, NtError, UnsupportedField, KeyDoesntExist, TypeMismatch};
+
+use serialize::{Encodable, Decodable, Encoder, Decoder};
+
+/// Encodes a value as entries under `prefix`. Each struct field becomes
+/// a key under the prefix, named after the field, and nested structs
+/// become sub-tables. Numbers of every size are stored as `Number`s,
+/// chars and strs as `String`s, and enums without fields as the name of
+/// the variant. Sequences and maps aren't supported.
+///
+/// Returns the entries to set along with the keys of `None` options,
+/// which are to be deleted along with any sub-table under them.
+pub fn encode<T: Encodable<TableEncoder, NtError>>(prefix: &str, value: &T)
+        -> NtResult<(Vec<(String, protocol::EntryType)>, Vec<String>)> {
+    let mut encoder = TableEncoder::new(prefix);
+    try!(value.encode(&mut encoder));
+    Ok((encoder.entries, encoder.deleted))
+}
+
+/// Decodes a value from the entries under `prefix` in `snapshot`, the
+/// reverse of `encode`.
+pub fn decode<T: Decodable<TableDecoder, NtError>>(snapshot: Snapshot, prefix: &str) -> NtResult<T> {
+    let mut decoder = TableDecoder{snapshot: snapshot, path: vec![trim_prefix(prefix)]};
+    Decodable::decode(&mut decoder)
+}
+
+fn trim_prefix(prefix: &str) -> String {
+    prefix.trim_right_chars('/').to_string()
+}
+
+pub struct TableEncoder {
+    path: Vec<String>,
+    entries: Vec<(String, protocol::EntryType)>,
+    deleted: Vec<String>,
+}
+
+impl TableEncoder {
+    fn new(prefix: &str) -> TableEncoder {
+        TableEncoder{path: vec![trim_prefix(prefix)], entries: Vec::new(), deleted: Vec::new()}
+    }
+
+    fn key(&self) -> String { self.path.connect("/") }
+
+    fn emit_value(&mut self, value: protocol::EntryType) -> NtResult<()> {
+        let key = self.key();
+        self.entries.push((key, value));
+        Ok(())
+    }
+
+    fn emit_child(&mut self, name: String, f: |&mut TableEncoder| -> NtResult<()>) -> NtResult<()> {
+        self.path.push(name);
+        let result = f(self);
+        self.path.pop();
+        result
+    }
+
+    fn unsupported(&self) -> NtError {
+        NtError::new(UnsupportedField(self.key()))
+    }
+}
+
+impl Encoder<NtError> for TableEncoder {
+    fn emit_nil(&mut self) -> NtResult<()> { Err(self.unsupported()) }
+
+    fn emit_uint(&mut self, v: uint) -> NtResult<()> { self.emit_value(Number(v as f64)) }
+    fn emit_u64(&mut self, v: u64) -> NtResult<()> { self.emit_value(Number(v as f64)) }
+    fn emit_u32(&mut self, v: u32) -> NtResult<()> { self.emit_value(Number(v as f64)) }
+    fn emit_u16(&mut self, v: u16) -> NtResult<()> { self.emit_value(Number(v as f64)) }
+    fn emit_u8(&mut self, v: u8) -> NtResult<()> { self.emit_value(Number(v as f64)) }
+    fn emit_int(&mut self, v: int) -> NtResult<()> { self.emit_value(Number(v as f64)) }
+    fn emit_i64(&mut self, v: i64) -> NtResult<()> { self.emit_value(Number(v as f64)) }
+    fn emit_i32(&mut self, v: i32) -> NtResult<()> { self.emit_value(Number(v as f64)) }
+    fn emit_i16(&mut self, v: i16) -> NtResult<()> { self.emit_value(Number(v as f64)) }
+    fn emit_i8(&mut self, v: i8) -> NtResult<()> { self.emit_value(Number(v as f64)) }
+    fn emit_bool(&mut self, v: bool) -> NtResult<()> { self.emit_value(Boolean(v)) }
+    fn emit_f64(&mut self, v: f64) -> NtResult<()> { self.emit_value(Number(v)) }
+    fn emit_f32(&mut self, v: f32) -> NtResult<()> { self.emit_value(Number(v as f64)) }
+    fn emit_char(&mut self, v: char) -> NtResult<()> {
+        self.emit_value(protocol::String(String::from_char(1, v)))
+    }
+    fn emit_str(&mut self, v: &str) -> NtResult<()> {
+        self.emit_value(protocol::String(v.to_string()))
+    }
+
+    fn emit_enum(&mut self, _name: &str, f: |&mut TableEncoder| -> NtResult<()>) -> NtResult<()> {
+        f(self)
+    }
+
+    fn emit_enum_variant(&mut self, v_name: &str, _v_id: uint, len: uint,
+                         _f: |&mut TableEncoder| -> NtResult<()>) -> NtResult<()> {
+        match len {
+            0 => self.emit_str(v_name),
+            _ => Err(self.unsupported()),
+        }
+    }
+
+    fn emit_enum_variant_arg(&mut self, _a_idx: uint,
+                             _f: |&mut TableEncoder| -> NtResult<()>) -> NtResult<()> {
+        Err(self.unsupported())
+    }
+
+    fn emit_enum_struct_variant(&mut self, _v_name: &str, _v_id: uint, _len: uint,
+                                _f: |&mut TableEncoder| -> NtResult<()>) -> NtResult<()> {
+        Err(self.unsupported())
+    }
+
+    fn emit_enum_struct_variant_field(&mut self, _f_name: &str, _f_idx: uint,
+                                      _f: |&mut TableEncoder| -> NtResult<()>) -> NtResult<()> {
+        Err(self.unsupported())
+    }
+
+    fn emit_struct(&mut self, _name: &str, _len: uint,
+                   f: |&mut TableEncoder| -> NtResult<()>) -> NtResult<()> {
+        f(self)
+    }
+
+    fn emit_struct_field(&mut self, f_name: &str, _f_idx: uint,
+                         f: |&mut TableEncoder| -> NtResult<()>) -> NtResult<()> {
+        self.emit_child(f_name.to_string(), f)
+    }
+
+    // Tuples are sub-tables keyed by index
+    fn emit_tuple(&mut self, _len: uint, f: |&mut TableEncoder| -> NtResult<()>) -> NtResult<()> {
+        f(self)
+    }
+
+    fn emit_tuple_arg(&mut self, idx: uint, f: |&mut TableEncoder| -> NtResult<()>) -> NtResult<()> {
+        self.emit_child(idx.to_string(), f)
+    }
+
+    fn emit_tuple_struct(&mut self, _name: &str, _len: uint,
+                         f: |&mut TableEncoder| -> NtResult<()>) -> NtResult<()> {
+        f(self)
+    }
+
+    fn emit_tuple_struct_arg(&mut self, f_idx: uint,
+                             f: |&mut TableEncoder| -> NtResult<()>) -> NtResult<()> {
+        self.emit_child(f_idx.to_string(), f)
+    }
+
+    // A None removes the key, so it reads back as None
+    fn emit_option(&mut self, f: |&mut TableEncoder| -> NtResult<()>) -> NtResult<()> { f(self) }
+    fn emit_option_none(&mut self) -> NtResult<()> {
+        let key = self.key();
+        self.deleted.push(key);
+        Ok(())
+    }
+    fn emit_option_some(&mut self, f: |&mut TableEncoder| -> NtResult<()>) -> NtResult<()> { f(self) }
+
+    // TODO: Sequences once array entries are supported
+    fn emit_seq(&mut self, _len: uint, _f: |&mut TableEncoder| -> NtResult<()>) -> NtResult<()> {
+        Err(self.unsupported())
+    }
+
+    fn emit_seq_elt(&mut self, _idx: uint, _f: |&mut TableEncoder| -> NtResult<()>) -> NtResult<()> {
+        Err(self.unsupported())
+    }
+
+    fn emit_map(&mut self, _len: uint, _f: |&mut TableEncoder| -> NtResult<()>) -> NtResult<()> {
+        Err(self.unsupported())
+    }
+
+    fn emit_map_elt_key(&mut self, _idx: uint,
+                        _f: |&mut TableEncoder| -> NtResult<()>) -> NtResult<()> {
+        Err(self.unsupported())
+    }
+
+    fn emit_map_elt_val(&mut self, _idx: uint,
+                        _f: |&mut TableEncoder| -> NtResult<()>) -> NtResult<()> {
+        Err(self.unsupported())
+    }
+}
+
+pub struct TableDecoder {
+    snapshot: Snapshot,
+    path: Vec<String>,
+}
+
+impl TableDecoder {
+    fn key(&self) -> String { self.path.connect("/") }
+
+    fn read_value(&mut self) -> NtResult<protocol::EntryType> {
+        let key = self.key();
+        match self.snapshot.get_entry(&key) {
+            Some(value) => Ok(value.clone()),
+            None => Err(NtError::new(KeyDoesntExist(key.clone())).with_key(key)),
+        }
+    }
+
+    fn read_number(&mut self) -> NtResult<f64> {
+        match try!(self.read_value()) {
+            Number(n) => Ok(n),
+            _ => Err(self.mismatch()),
+        }
+    }
+
+    /// Reads a number that must be a whole number between `min` and `max`.
+    fn read_integer(&mut self, min: f64, max: f64) -> NtResult<f64> {
+        let n = try!(self.read_number());
+        if n.fract() != 0f64 || n < min || n > max {
+            return Err(self.mismatch())
+        }
+        Ok(n)
+    }
+
+    fn read_string(&mut self) -> NtResult<String> {
+        match try!(self.read_value()) {
+            protocol::String(s) => Ok(s),
+            _ => Err(self.mismatch()),
+        }
+    }
+
+    /// Whether the current key or any key under it exists.
+    fn exists(&self) -> bool {
+        let key = self.key();
+        let child = format!("{}/", key);
+        self.snapshot.keys().iter().any(|k| *k == key || k.as_slice().starts_with(child.as_slice()))
+    }
+
+    /// The number of consecutive sub-tables indexed from 0.
+    fn count_indices(&mut self) -> uint {
+        let mut len = 0u;
+        loop {
+            self.path.push(len.to_string());
+            let exists = self.exists();
+            self.path.pop();
+            if !exists { return len }
+            len += 1;
+        }
+    }
+
+    fn read_child<T>(&mut self, name: String, f: |&mut TableDecoder| -> NtResult<T>) -> NtResult<T> {
+        self.path.push(name);
+        let result = f(self);
+        self.path.pop();
+        result
+    }
+
+    fn mismatch(&self) -> NtError {
+        let key = self.key();
+        NtError::new(TypeMismatch(key.clone())).with_key(key)
+    }
+
+    fn unsupported(&self) -> NtError {
+        NtError::new(UnsupportedField(self.key()))
+    }
+}
+
+impl Decoder<NtError> for TableDecoder {
+    fn read_nil(&mut self) -> NtResult<()> { Err(self.unsupported()) }
+
+    fn read_uint(&mut self) -> NtResult<uint> {
+        Ok(try!(self.read_integer(0f64, ::std::uint::MAX as f64)) as uint)
+    }
+    fn read_u64(&mut self) -> NtResult<u64> {
+        Ok(try!(self.read_integer(0f64, ::std::u64::MAX as f64)) as u64)
+    }
+    fn read_u32(&mut self) -> NtResult<u32> {
+        Ok(try!(self.read_integer(0f64, ::std::u32::MAX as f64)) as u32)
+    }
+    fn read_u16(&mut self) -> NtResult<u16> {
+        Ok(try!(self.read_integer(0f64, ::std::u16::MAX as f64)) as u16)
+    }
+    fn read_u8(&mut self) -> NtResult<u8> {
+        Ok(try!(self.read_integer(0f64, ::std::u8::MAX as f64)) as u8)
+    }
+    fn read_int(&mut self) -> NtResult<int> {
+        Ok(try!(self.read_integer(::std::int::MIN as f64, ::std::int::MAX as f64)) as int)
+    }
+    fn read_i64(&mut self) -> NtResult<i64> {
+        Ok(try!(self.read_integer(::std::i64::MIN as f64, ::std::i64::MAX as f64)) as i64)
+    }
+    fn read_i32(&mut self) -> NtResult<i32> {
+        Ok(try!(self.read_integer(::std::i32::MIN as f64, ::std::i32::MAX as f64)) as i32)
+    }
+    fn read_i16(&mut self) -> NtResult<i16> {
+        Ok(try!(self.read_integer(::std::i16::MIN as f64, ::std::i16::MAX as f64)) as i16)
+    }
+    fn read_i8(&mut self) -> NtResult<i8> {
+        Ok(try!(self.read_integer(::std::i8::MIN as f64, ::std::i8::MAX as f64)) as i8)
+    }
+
+    fn read_bool(&mut self) -> NtResult<bool> {
+        match try!(self.read_value()) {
+            Boolean(b) => Ok(b),
+            _ => Err(self.mismatch()),
+        }
+    }
+
+    fn read_f64(&mut self) -> NtResult<f64> { self.read_number() }
+    fn read_f32(&mut self) -> NtResult<f32> { Ok(try!(self.read_number()) as f32) }
+
+    fn read_char(&mut self) -> NtResult<char> {
+        let s = try!(self.read_string());
+        match s.as_slice().char_len() {
+            1 => Ok(s.as_slice().char_at(0)),
+            _ => Err(self.mismatch()),
+        }
+    }
+
+    fn read_str(&mut self) -> NtResult<String> { self.read_string() }
+
+    fn read_enum<T>(&mut self, _name: &str, f: |&mut TableDecoder| -> NtResult<T>) -> NtResult<T> {
+        f(self)
+    }
+
+    fn read_enum_variant<T>(&mut self, names: &[&str],
+                            f: |&mut TableDecoder, uint| -> NtResult<T>) -> NtResult<T> {
+        let name = try!(self.read_string());
+        match names.iter().position(|n| *n == name.as_slice()) {
+            Some(idx) => f(self, idx),
+            None => Err(self.mismatch()),
+        }
+    }
+
+    fn read_enum_variant_arg<T>(&mut self, _a_idx: uint,
+                                _f: |&mut TableDecoder| -> NtResult<T>) -> NtResult<T> {
+        Err(self.unsupported())
+    }
+
+    fn read_enum_struct_variant<T>(&mut self, _names: &[&str],
+                                   _f: |&mut TableDecoder, uint| -> NtResult<T>) -> NtResult<T> {
+        Err(self.unsupported())
+    }
+
+    fn read_enum_struct_variant_field<T>(&mut self, _f_name: &str, _f_idx: uint,
+                                         _f: |&mut TableDecoder| -> NtResult<T>) -> NtResult<T> {
+        Err(self.unsupported())
+    }
+
+    fn read_struct<T>(&mut self, _s_name: &str, _len: uint,
+                      f: |&mut TableDecoder| -> NtResult<T>) -> NtResult<T> {
+        f(self)
+    }
+
+    fn read_struct_field<T>(&mut self, f_name: &str, _f_idx: uint,
+                            f: |&mut TableDecoder| -> NtResult<T>) -> NtResult<T> {
+        self.read_child(f_name.to_string(), f)
+    }
+
+    fn read_tuple<T>(&mut self, f: |&mut TableDecoder, uint| -> NtResult<T>) -> NtResult<T> {
+        let len = self.count_indices();
+        f(self, len)
+    }
+
+    fn read_tuple_arg<T>(&mut self, a_idx: uint, f: |&mut TableDecoder| -> NtResult<T>) -> NtResult<T> {
+        self.read_child(a_idx.to_string(), f)
+    }
+
+    fn read_tuple_struct<T>(&mut self, _s_name: &str,
+                            f: |&mut TableDecoder, uint| -> NtResult<T>) -> NtResult<T> {
+        let len = self.count_indices();
+        f(self, len)
+    }
+
+    fn read_tuple_struct_arg<T>(&mut self, a_idx: uint,
+                                f: |&mut TableDecoder| -> NtResult<T>) -> NtResult<T> {
+        self.read_child(a_idx.to_string(), f)
+    }
+
+    fn read_option<T>(&mut self, f: |&mut TableDecoder, bool| -> NtResult<T>) -> NtResult<T> {
+        let exists = self.exists();
+        f(self, exists)
+    }
+
+    fn read_seq<T>(&mut self, _f: |&mut TableDecoder, uint| -> NtResult<T>) -> NtResult<T> {
+        Err(self.unsupported())
+    }
+
+    fn read_seq_elt<T>(&mut self, _idx: uint, _f: |&mut TableDecoder| -> NtResult<T>) -> NtResult<T> {
+        Err(self.unsupported())
+    }
+
+    fn read_map<T>(&mut self, _f: |&mut TableDecoder, uint| -> NtResult<T>) -> NtResult<T> {
+        Err(self.unsupported())
+    }
+
+    fn read_map_elt_key<T>(&mut self, _idx: uint,
+                           _f: |&mut TableDecoder| -> NtResult<T>) -> NtResult<T> {
+        Err(self.unsupported())
+    }
+
+    fn read_map_elt_val<T>(&mut self, _idx: uint,
+                           _f: |&mut TableDecoder| -> NtResult<T>) -> NtResult<T> {
+        Err(self.unsupported())
+    }
+
+    fn error(&mut self, _err: &str) -> NtError {
+        self.mismatch()
+    }
+}
+
+/// Tests
+#[cfg(test)]
+mod test {
+    use super::super::{Table, Get, Set, LocalTable, KeyDoesntExist, TypeMismatch, UnsupportedField};
+
+    #[deriving(Encodable, Decodable, PartialEq, Show)]
+    enum Mode { Idle, Spinning }
+
+    #[deriving(Encodable, Decodable, PartialEq, Show)]
+    struct Pid { p: f64, i: f64, d: f64 }
+
+    #[deriving(Encodable, Decodable, PartialEq, Show)]
+    struct ShooterConfig {
+        speed: f64,
+        enabled: bool,
+        name: String,
+        mode: Mode,
+        retries: u8,
+        trim: Option<f64>,
+        pid: Pid,
+    }
+
+    fn config() -> ShooterConfig {
+        ShooterConfig{speed: 3000f64, enabled: true, name: "Shooter".to_string(), mode: Spinning,
+                      retries: 3, trim: None, pid: Pid{p: 0.1, i: 0f64, d: 0.01}}
+    }
+
+    #[test]
+    fn struct_round_trip() {
+        let table = LocalTable::new();
+        table.set_struct("/Shooter/", &config()).unwrap();
+
+        assert_eq!(Some(3000f64), table.get("/Shooter/speed".to_string()));
+        assert_eq!(Some("Spinning".to_string()), table.get("/Shooter/mode".to_string()));
+        assert_eq!(Some(0.1f64), table.get("/Shooter/pid/p".to_string()));
+        assert!(!table.snapshot("/Shooter/trim").contains_key(&"/Shooter/trim".to_string()));
+
+        let decoded: ShooterConfig = table.get_struct("/Shooter").unwrap();
+        assert_eq!(config(), decoded);
+    }
+
+    #[test]
+    fn struct_none_deletes_keys() {
+        let table = LocalTable::new();
+        let mut value = config();
+        value.trim = Some(0.5f64);
+        table.set_struct("/Shooter", &value).unwrap();
+        table.set("/Shooter/trimmed".to_string(), true).unwrap();
+        assert_eq!(Some(0.5f64), table.get("/Shooter/trim".to_string()));
+
+        value.trim = None;
+        table.set_struct("/Shooter", &value).unwrap();
+        let trim: Option<f64> = table.get("/Shooter/trim".to_string());
+        assert_eq!(None, trim);
+        // Only the key itself and keys under it are removed
+        assert_eq!(Some(true), table.get("/Shooter/trimmed".to_string()));
+        let decoded: ShooterConfig = table.get_struct("/Shooter").unwrap();
+        assert_eq!(value, decoded);
+    }
+
+    #[test]
+    fn struct_decode_errors() {
+        let table = LocalTable::new();
+        table.set_struct("/Shooter", &config()).unwrap();
+
+        let err = table.get_struct::<ShooterConfig>("/Missing").unwrap_err();
+        assert_eq!(KeyDoesntExist("/Missing/speed".to_string()), err.kind);
+
+        table.set("/Shooter/retries".to_string(), 3.5f64).unwrap();
+        let err = table.get_struct::<ShooterConfig>("/Shooter").unwrap_err();
+        assert_eq!(TypeMismatch("/Shooter/retries".to_string()), err.kind);
+
+        let err = table.set_struct("/List", &vec![1f64, 2f64]).unwrap_err();
+        assert_eq!(UnsupportedField("/List".to_string()), err.kind);
+    }
+}
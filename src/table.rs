@@ -0,0 +1,364 @@
+use super::protocol;
+use super::{NtResult, NtError};
+use super::snapshot::Snapshot;
+use super::batch::Batch;
+use super::structs;
+use super::structs::{TableEncoder, TableDecoder};
+
+use serialize::{Encodable, Decodable};
+use serialize::json::JsonObject;
+
+use std::sync::Mutex;
+use std::collections::HashMap;
+
+/// A trait for getting values of different types by a key.
+pub trait Get<T> {
+    /// Returns the value of `Some(T)` if it exists in the tabel and
+    /// is coercible into the desired value.
+    fn get(&self, key: String) -> Option<T>;
+}
+
+/// A trait for setting values of different types by a key.
+pub trait Set<T> {
+    /// Returns the value of `Some(T)` if it exists in the tabel and
+    /// is coercible into the desired value.
+    fn set(&self, key: String, value: T) -> NtResult<()>;
+}
+
+/// A table of values that can be read, written and listened to. Code
+/// written against `Table` instead of `Client` can be run against a
+/// `LocalTable` without a server.
+///
+/// # Example
+///
+/// ```ignore
+/// fn update_counter<T: networktables::Table>(table: &T) {
+///     let n: f64 = table.get("/Counter".to_string()).unwrap_or(0f64);
+///     let _ = table.set("/Counter".to_string(), n + 1f64);
+/// }
+///
+/// update_counter(&networktables::LocalTable::new());
+/// ```
+pub trait Table : Get<bool> + Get<f64> + Get<String> + Set<bool> + Set<f64> + Set<String> {
+    /// Adds a listener that is sent an `Event` whenever an entry
+    /// changes. Listeners are dropped once their receiver hangs up.
+    fn add_listener(&self, listener: Sender<Event>);
+
+    /// Returns the values of every entry whose key starts with
+    /// `prefix`, as they were at a single point in time.
+    fn snapshot(&self, prefix: &str) -> Snapshot;
+
+    /// Sets every value set on the `Batch` passed to `f` at once. No
+    /// reader sees some of the values without the others, and a
+    /// `Client` sends them all together.
+    fn batch(&self, f: |&Batch|) -> NtResult<()>;
+
+    /// Removes the entry with `key`, if it exists.
+    fn delete(&self, key: String) -> NtResult<()>;
+
+    /// Removes every entry.
+    fn delete_all(&self) -> NtResult<()>;
+
+    /// Sets each field of `value` as an entry under `prefix`, all in one
+    /// batch. Nested structs become sub-tables. Fields that are `None`
+    /// are deleted once the batch is set, along with anything under them.
+    ///
+    /// ```ignore
+    /// #[deriving(Encodable, Decodable)]
+    /// struct ShooterConfig { speed: f64, enabled: bool }
+    ///
+    /// try!(client.set_struct("/Shooter", &config));
+    /// let config = try!(client.get_struct::<ShooterConfig>("/Shooter"));
+    /// ```
+    fn set_struct<T: Encodable<TableEncoder, NtError>>(&self, prefix: &str, value: &T) -> NtResult<()> {
+        let (entries, deleted) = try!(structs::encode(prefix, value));
+        try!(self.batch(|b| {
+            for &(ref key, ref value) in entries.iter() {
+                let _ = b.set_entry(key.clone(), value.clone());
+            }
+        }));
+        for key in deleted.iter() {
+            let child = format!("{}/", key);
+            for existing in self.snapshot(key.as_slice()).keys().into_iter() {
+                if existing == *key || existing.as_slice().starts_with(child.as_slice()) {
+                    try!(self.delete(existing));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a value set by `set_struct` from a single snapshot of the
+    /// entries under `prefix`.
+    fn get_struct<T: Decodable<TableDecoder, NtError>>(&self, prefix: &str) -> NtResult<T> {
+        structs::decode(self.snapshot(prefix), prefix)
+    }
+}
+
+/// A change to an entry in a table.
+#[deriving(PartialEq,Clone,Show)]
+pub enum Event {
+    /// An entry was created with the given key and value.
+    Added(String, protocol::EntryType),
+    /// An existing entry was given a new value.
+    Updated(String, protocol::EntryType),
+    /// The flags of the entry with the given key changed.
+    FlagsUpdated(String, u8),
+    /// The entry with the given key was removed.
+    Deleted(String),
+    /// Properties of the NT4 topic with the given key changed. Only
+    /// the changed properties are included, with `null` for removed
+    /// ones.
+    PropertiesUpdated(String, JsonObject),
+}
+
+/// The listeners registered with a table.
+pub struct Listeners {
+    senders: Mutex<Vec<Sender<Event>>>,
+}
+
+impl Listeners {
+    pub fn new() -> Listeners {
+        Listeners{senders: Mutex::new(Vec::new())}
+    }
+
+    pub fn add(&self, listener: Sender<Event>) {
+        self.senders.lock().push(listener);
+    }
+
+    /// Sends `event` to every listener, dropping those that have hung up.
+    pub fn notify(&self, event: Event) {
+        let mut senders = self.senders.lock();
+        senders.retain(|sender| sender.send_opt(event.clone()).is_ok());
+    }
+}
+
+/// A table that only exists in memory. Sets are applied immediately
+/// and fire the same events as a `Client`, which makes it useful for
+/// testing code without a server.
+pub struct LocalTable {
+    entries: Mutex<HashMap<String, protocol::EntryType>>,
+    listeners: Listeners,
+}
+
+impl LocalTable {
+    pub fn new() -> LocalTable {
+        LocalTable{
+            entries: Mutex::new(HashMap::new()),
+            listeners: Listeners::new(),
+        }
+    }
+
+    fn get_entry(&self, key: String) -> Option<protocol::EntryType> {
+        let entries = self.entries.lock();
+        match entries.get(&key) {
+            Some(value) => Some(value.clone()),
+            None => None,
+        }
+    }
+
+    fn set_entry(&self, key: String, value: protocol::EntryType) -> NtResult<()> {
+        let event = {
+            let mut entries = self.entries.lock();
+            let event = match entries.contains_key(&key) {
+                true => Updated(key.clone(), value.clone()),
+                false => Added(key.clone(), value.clone()),
+            };
+            entries.insert(key, value);
+            event
+        };
+        self.listeners.notify(event);
+        Ok(())
+    }
+}
+
+impl Table for LocalTable {
+    fn add_listener(&self, listener: Sender<Event>) {
+        self.listeners.add(listener)
+    }
+
+    fn snapshot(&self, prefix: &str) -> Snapshot {
+        let entries = self.entries.lock();
+        let mut values = HashMap::new();
+        for (key, value) in entries.iter() {
+            if key.as_slice().starts_with(prefix) {
+                values.insert(key.clone(), value.clone());
+            }
+        }
+        Snapshot::new(values)
+    }
+
+    fn batch(&self, f: |&Batch|) -> NtResult<()> {
+        let batch = Batch::new();
+        f(&batch);
+
+        let mut events = Vec::new();
+        {
+            let mut entries = self.entries.lock();
+            for (key, value) in batch.into_entries().into_iter() {
+                events.push(match entries.contains_key(&key) {
+                    true => Updated(key.clone(), value.clone()),
+                    false => Added(key.clone(), value.clone()),
+                });
+                entries.insert(key, value);
+            }
+        }
+        for event in events.into_iter() {
+            self.listeners.notify(event);
+        }
+        Ok(())
+    }
+
+    fn delete(&self, key: String) -> NtResult<()> {
+        let removed = self.entries.lock().remove(&key).is_some();
+        if removed {
+            self.listeners.notify(Deleted(key));
+        }
+        Ok(())
+    }
+
+    fn delete_all(&self) -> NtResult<()> {
+        let mut keys: Vec<String> = {
+            let mut entries = self.entries.lock();
+            let keys = entries.keys().map(|k| k.clone()).collect();
+            entries.clear();
+            keys
+        };
+        keys.sort();
+        for key in keys.into_iter() {
+            self.listeners.notify(Deleted(key));
+        }
+        Ok(())
+    }
+}
+
+impl Get<bool> for LocalTable {
+    fn get(&self, key: String) -> Option<bool> {
+        match self.get_entry(key) {
+            Some(protocol::Boolean(b)) => Some(b),
+            _ => None,
+        }
+    }
+}
+
+impl Get<f64> for LocalTable {
+    fn get(&self, key: String) -> Option<f64> {
+        match self.get_entry(key) {
+            Some(protocol::Number(n)) => Some(n),
+            _ => None,
+        }
+    }
+}
+
+impl Get<String> for LocalTable {
+    fn get(&self, key: String) -> Option<String> {
+        match self.get_entry(key) {
+            Some(protocol::String(s)) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+impl Get<Vec<u8>> for LocalTable {
+    fn get(&self, key: String) -> Option<Vec<u8>> {
+        match self.get_entry(key) {
+            Some(protocol::Raw(bytes)) => Some(bytes),
+            _ => None,
+        }
+    }
+}
+
+impl Set<bool> for LocalTable {
+    fn set(&self, key: String, value: bool) -> NtResult<()> {
+        self.set_entry(key, protocol::Boolean(value))
+    }
+}
+
+impl Set<f64> for LocalTable {
+    fn set(&self, key: String, value: f64) -> NtResult<()> {
+        self.set_entry(key, protocol::Number(value))
+    }
+}
+
+impl Set<String> for LocalTable {
+    fn set(&self, key: String, value: String) -> NtResult<()> {
+        self.set_entry(key, protocol::String(value))
+    }
+}
+
+impl Set<Vec<u8>> for LocalTable {
+    fn set(&self, key: String, value: Vec<u8>) -> NtResult<()> {
+        self.set_entry(key, protocol::Raw(value))
+    }
+}
+
+/// Tests
+#[cfg(test)]
+mod test {
+    use super::{Table, Get, Set, LocalTable, Added, Updated, Deleted};
+    use super::super::protocol::Number;
+
+    #[test]
+    fn local_table_get_set() {
+        let table = LocalTable::new();
+        let missing: Option<f64> = table.get("/Number".to_string());
+        assert_eq!(None, missing);
+
+        table.set("/Number".to_string(), 42f64).unwrap();
+        table.set("/Bool".to_string(), true).unwrap();
+        assert_eq!(Some(42f64), table.get("/Number".to_string()));
+        assert_eq!(Some(true), table.get("/Bool".to_string()));
+        table.set("/Raw".to_string(), vec![0x00u8, 0xFF]).unwrap();
+        assert_eq!(Some(vec![0x00u8, 0xFF]), table.get("/Raw".to_string()));
+
+        // Values of the wrong type aren't coerced
+        let wrong: Option<bool> = table.get("/Number".to_string());
+        assert_eq!(None, wrong);
+    }
+
+    #[test]
+    fn local_table_events_and_snapshots() {
+        let table = LocalTable::new();
+        let (tx, rx) = channel();
+        table.add_listener(tx);
+
+        table.set("/Number".to_string(), 1f64).unwrap();
+        table.set("/Number".to_string(), 2f64).unwrap();
+        assert_eq!(Added("/Number".to_string(), Number(1f64)), rx.recv());
+        assert_eq!(Updated("/Number".to_string(), Number(2f64)), rx.recv());
+
+        // Snapshots copy only the prefix
+        table.set("/Vision/x".to_string(), 3f64).unwrap();
+        let snapshot = table.snapshot("/Vision/");
+        table.set("/Vision/x".to_string(), 4f64).unwrap();
+        assert_eq!(vec!["/Vision/x".to_string()], snapshot.keys());
+        assert_eq!(Some(3f64), snapshot.get("/Vision/x".to_string()));
+
+        // Batches are applied together
+        table.batch(|b| {
+            b.set("/Vision/x".to_string(), 5f64).unwrap();
+            b.set("/Vision/y".to_string(), 6f64).unwrap();
+            let x: Option<f64> = table.get("/Vision/x".to_string());
+            assert_eq!(Some(4f64), x);
+        }).unwrap();
+        assert_eq!(Some(5f64), table.get("/Vision/x".to_string()));
+        assert_eq!(Some(6f64), table.get("/Vision/y".to_string()));
+
+        // Deletes
+        table.delete("/Vision/y".to_string()).unwrap();
+        table.delete("/Missing".to_string()).unwrap();
+        let y: Option<f64> = table.get("/Vision/y".to_string());
+        assert_eq!(None, y);
+        table.delete_all().unwrap();
+        assert!(table.snapshot("").is_empty());
+        for _ in range(0u, 4u) { rx.recv(); }
+        assert_eq!(Deleted("/Vision/y".to_string()), rx.recv());
+        assert_eq!(Deleted("/Number".to_string()), rx.recv());
+        assert_eq!(Deleted("/Vision/x".to_string()), rx.recv());
+
+        // A hung up listener doesn't stop sets
+        drop(rx);
+        table.set("/Bool".to_string(), false).unwrap();
+        assert_eq!(Some(false), table.get("/Bool".to_string()));
+    }
+}
@@ -0,0 +1,384 @@
+use super::{NtResult, NtError, HandshakeFailed, UnsupportedOpcode, ValueTooLarge, InvalidMessage,
+            StringConversionError};
+
+use serialize::base64::{ToBase64, STANDARD};
+use std::io::MemWriter;
+use std::rand;
+
+/// The WebSocket subprotocols for NT4.1 and NT4.0, in order of preference.
+pub const NT4_PROTOCOLS: [&'static str, ..2] = ["v4.1.networktables.first.wpi.edu",
+                                                "networktables.first.wpi.edu"];
+
+// Appended to the client's key to make the accept key, from RFC 6455.
+const GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// HTTP heads longer than this are rejected.
+const MAX_HEAD: uint = 8192;
+/// The largest message accepted, including every fragment.
+pub const MAX_PAYLOAD: uint = 16 << 20;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+/// A complete WebSocket message. Fragmented messages are put back
+/// together by `read_frame`.
+#[deriving(Show, Clone, PartialEq)]
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close,
+}
+
+/// Answers a client's opening handshake, agreeing on the first of
+/// `protocols` the client offered. Returns the agreed protocol.
+pub fn accept<S: Reader + Writer>(stream: &mut S, protocols: &[&str]) -> NtResult<String> {
+    let head = try!(read_head(stream));
+    if !head[0].as_slice().starts_with("GET ") {
+        return Err(handshake_failed(format!("Expected a GET request, got {}.", head[0])))
+    }
+    let key = match header(&head, "sec-websocket-key") {
+        Some(key) => key,
+        None => {
+            try!(stream.write_str("HTTP/1.1 400 Bad Request\r\n\r\n"));
+            return Err(handshake_failed("Missing Sec-WebSocket-Key.".to_string()))
+        },
+    };
+    let offered = header(&head, "sec-websocket-protocol").unwrap_or(String::new());
+    let offered: Vec<&str> = offered.as_slice().split(',').map(|p| p.trim()).collect();
+    let protocol = match protocols.iter().find(|p| offered.contains(*p)) {
+        Some(protocol) => protocol.to_string(),
+        None => {
+            try!(stream.write_str("HTTP/1.1 400 Bad Request\r\n\r\n"));
+            return Err(handshake_failed(format!("No supported protocol in {}.", offered)))
+        },
+    };
+
+    try!(stream.write_str(format!("HTTP/1.1 101 Switching Protocols\r\n\
+                                   Upgrade: websocket\r\n\
+                                   Connection: Upgrade\r\n\
+                                   Sec-WebSocket-Accept: {}\r\n\
+                                   Sec-WebSocket-Protocol: {}\r\n\r\n",
+                                  accept_key(key.as_slice()), protocol).as_slice()));
+    Ok(protocol)
+}
+
+/// Makes the opening handshake with the server at `host`, offering
+/// `protocols`. Returns the protocol the server agreed on.
+pub fn connect<S: Reader + Writer>(stream: &mut S, host: &str, path: &str, protocols: &[&str])
+                                   -> NtResult<String> {
+    let nonce: Vec<u8> = Vec::from_fn(16, |_| rand::random::<u8>());
+    let key = nonce.as_slice().to_base64(STANDARD);
+    try!(stream.write_str(format!("GET {} HTTP/1.1\r\n\
+                                   Host: {}\r\n\
+                                   Upgrade: websocket\r\n\
+                                   Connection: Upgrade\r\n\
+                                   Sec-WebSocket-Key: {}\r\n\
+                                   Sec-WebSocket-Version: 13\r\n\
+                                   Sec-WebSocket-Protocol: {}\r\n\r\n",
+                                  path, host, key, protocols.connect(", ")).as_slice()));
+
+    let head = try!(read_head(stream));
+    if !head[0].as_slice().contains(" 101 ") {
+        return Err(handshake_failed(format!("Server refused the upgrade: {}.", head[0])))
+    }
+    if header(&head, "sec-websocket-accept") != Some(accept_key(key.as_slice())) {
+        return Err(handshake_failed("Wrong Sec-WebSocket-Accept.".to_string()))
+    }
+    match header(&head, "sec-websocket-protocol") {
+        Some(ref protocol) if protocols.contains(&protocol.as_slice()) => Ok(protocol.clone()),
+        protocol => Err(handshake_failed(format!("Server chose an unsupported protocol {}.", protocol))),
+    }
+}
+
+fn handshake_failed(reason: String) -> NtError {
+    NtError::new(HandshakeFailed(reason))
+}
+
+/// The Sec-WebSocket-Accept value answering `key`.
+pub fn accept_key(key: &str) -> String {
+    let mut input = key.to_string();
+    input.push_str(GUID);
+    sha1(input.as_bytes()).as_slice().to_base64(STANDARD)
+}
+
+/// Reads the lines of an HTTP request or response head, up to the
+/// blank line ending it.
+fn read_head<R: Reader>(r: &mut R) -> NtResult<Vec<String>> {
+    let mut head = Vec::new();
+    while !head.as_slice().ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HEAD {
+            return Err(handshake_failed("HTTP head too long.".to_string()))
+        }
+        head.push(try!(r.read_u8()));
+    }
+    match String::from_utf8(head) {
+        Ok(head) => Ok(head.as_slice().lines_any().map(|l| l.to_string()).collect()),
+        Err(_) => Err(NtError::new(StringConversionError)),
+    }
+}
+
+/// The value of the header `name`, which must be lowercase.
+fn header(head: &Vec<String>, name: &str) -> Option<String> {
+    for line in head.iter().skip(1) {
+        let mut parts = line.as_slice().splitn(1, ':');
+        let key = parts.next().unwrap_or("").trim();
+        let lower: String = key.chars().map(|c| c.to_lowercase()).collect();
+        if lower.as_slice() == name {
+            return parts.next().map(|v| v.trim().to_string())
+        }
+    }
+    None
+}
+
+/// Writes `frame` as a single, final frame. Clients must `mask` their
+/// frames and servers must not.
+pub fn write_frame<W: Writer>(w: &mut W, frame: &Frame, mask: bool) -> NtResult<()> {
+    let (opcode, payload) = match *frame {
+        Text(ref s) => (OP_TEXT, s.as_bytes()),
+        Binary(ref bytes) => (OP_BINARY, bytes.as_slice()),
+        Ping(ref bytes) => (OP_PING, bytes.as_slice()),
+        Pong(ref bytes) => (OP_PONG, bytes.as_slice()),
+        Close => (OP_CLOSE, b""),
+    };
+
+    // Build the whole frame so it goes out in one write
+    let mut buf = MemWriter::new();
+    try!(buf.write_u8(0x80 | opcode));
+    let mask_bit = if mask { 0x80 } else { 0x00 };
+    match payload.len() {
+        len if len < 126 => try!(buf.write_u8(mask_bit | len as u8)),
+        len if len <= 0xFFFF => {
+            try!(buf.write_u8(mask_bit | 126));
+            try!(buf.write_be_u16(len as u16));
+        },
+        len => {
+            try!(buf.write_u8(mask_bit | 127));
+            try!(buf.write_be_u64(len as u64));
+        },
+    }
+    if mask {
+        let key: Vec<u8> = Vec::from_fn(4, |_| rand::random::<u8>());
+        try!(buf.write(key.as_slice()));
+        let masked: Vec<u8> = payload.iter().enumerate().map(|(i, b)| *b ^ key[i % 4]).collect();
+        try!(buf.write(masked.as_slice()));
+    } else {
+        try!(buf.write(payload));
+    }
+    Ok(try!(w.write(buf.get_ref())))
+}
+
+/// Reads the next complete message. Pings and pongs arriving between
+/// the fragments of a message are dropped.
+pub fn read_frame<R: Reader>(r: &mut R) -> NtResult<Frame> {
+    // The opcode and payload of a fragmented message so far
+    let mut message: Option<(u8, Vec<u8>)> = None;
+    loop {
+        let first = try!(r.read_u8());
+        let second = try!(r.read_u8());
+        let (fin, opcode) = (first & 0x80 != 0, first & 0x0F);
+        let len = match second & 0x7F {
+            126 => try!(r.read_be_u16()) as u64,
+            127 => try!(r.read_be_u64()),
+            len => len as u64,
+        };
+        let so_far = message.as_ref().map_or(0, |&(_, ref payload)| payload.len());
+        if len > (MAX_PAYLOAD - so_far) as u64 {
+            return Err(NtError::new(ValueTooLarge(len as uint, MAX_PAYLOAD)))
+        }
+        let key = match second & 0x80 {
+            0 => None,
+            _ => Some(try!(r.read_exact(4))),
+        };
+        let mut payload = try!(r.read_exact(len as uint));
+        if let Some(key) = key {
+            for (i, b) in payload.iter_mut().enumerate() {
+                *b ^= key[i % 4];
+            }
+        }
+
+        match opcode {
+            OP_CLOSE => return Ok(Close),
+            OP_PING | OP_PONG if message.is_some() => continue,
+            OP_PING => return Ok(Ping(payload)),
+            OP_PONG => return Ok(Pong(payload)),
+            OP_TEXT | OP_BINARY if message.is_none() => message = Some((opcode, payload)),
+            OP_CONTINUATION if message.is_some() => {
+                if let Some((_, ref mut so_far)) = message {
+                    so_far.push_all(payload.as_slice());
+                }
+            },
+            OP_TEXT | OP_BINARY | OP_CONTINUATION =>
+                return Err(NtError::new(InvalidMessage("Unexpected WebSocket fragment.".to_string()))),
+            opcode => return Err(NtError::new(UnsupportedOpcode(opcode))),
+        }
+
+        if fin {
+            return match message.take().unwrap() {
+                (OP_TEXT, payload) => match String::from_utf8(payload) {
+                    Ok(s) => Ok(Text(s)),
+                    Err(_) => Err(NtError::new(StringConversionError)),
+                },
+                (_, payload) => Ok(Binary(payload)),
+            }
+        }
+    }
+}
+
+/// The SHA-1 digest of `data`. Only used for the accept key, which is
+/// why it isn't worth a dependency.
+fn sha1(data: &[u8]) -> [u8, ..20] {
+    let mut h = [0x67452301u32, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0x00);
+    }
+    let bits = data.len() as u64 * 8;
+    for i in range(0u, 8) {
+        message.push((bits >> (56 - i * 8)) as u8);
+    }
+
+    for chunk in message.as_slice().chunks(64) {
+        let mut w = [0u32, ..80];
+        for i in range(0u, 16) {
+            w[i] = chunk[i * 4] as u32 << 24 | chunk[i * 4 + 1] as u32 << 16 |
+                   chunk[i * 4 + 2] as u32 << 8 | chunk[i * 4 + 3] as u32;
+        }
+        for i in range(16u, 80) {
+            w[i] = rotate_left(w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16], 1);
+        }
+
+        let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+        for i in range(0u, 80) {
+            let (f, k) = match i {
+                0 ... 19 => ((b & c) | (!b & d), 0x5A827999u32),
+                20 ... 39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40 ... 59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = rotate_left(a, 5) + f + e + k + w[i];
+            e = d;
+            d = c;
+            c = rotate_left(b, 30);
+            b = a;
+            a = temp;
+        }
+        h[0] += a;
+        h[1] += b;
+        h[2] += c;
+        h[3] += d;
+        h[4] += e;
+    }
+
+    let mut digest = [0u8, ..20];
+    for i in range(0u, 20) {
+        digest[i] = (h[i / 4] >> (24 - (i % 4) * 8)) as u8;
+    }
+    digest
+}
+
+fn rotate_left(x: u32, n: uint) -> u32 {
+    (x << n) | (x >> (32 - n))
+}
+
+/// Tests
+#[cfg(test)]
+mod test {
+    use super::{Frame, Text, Binary, Ping, Close, write_frame, read_frame, accept, connect, accept_key,
+                sha1, NT4_PROTOCOLS};
+    use super::super::HandshakeFailed;
+    use serialize::hex::ToHex;
+    use std::io::{MemWriter, BufReader, Listener, Acceptor};
+    use std::io::net::tcp::{TcpListener, TcpStream};
+
+    fn round_trip(frame: &Frame, mask: bool) -> Frame {
+        let mut w = MemWriter::new();
+        write_frame(&mut w, frame, mask).unwrap();
+        let bytes = w.unwrap();
+        let mut r = BufReader::new(bytes.as_slice());
+        let parsed = read_frame(&mut r).unwrap();
+        assert!(r.eof());
+        parsed
+    }
+
+    #[test]
+    fn websocket_accept_key() {
+        assert_eq!("da39a3ee5e6b4b0d3255bfef95601890afd80709", sha1(b"").as_slice().to_hex().as_slice());
+        assert_eq!("a9993e364706816aba3e25717850c26c9cd0d89d", sha1(b"abc").as_slice().to_hex().as_slice());
+        // The example from RFC 6455
+        assert_eq!("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=", accept_key("dGhlIHNhbXBsZSBub25jZQ==").as_slice());
+    }
+
+    #[test]
+    fn websocket_frames_round_trip() {
+        let frames = [Text("".to_string()), Text("[{\"method\":\"subscribe\"}]".to_string()),
+                      Binary(Vec::from_elem(125, 0x01)), Binary(Vec::from_elem(126, 0x02)),
+                      Binary(Vec::from_elem(0x10000, 0x03)), Ping(vec![0x04]), Close];
+        for frame in frames.iter() {
+            assert_eq!(*frame, round_trip(frame, false));
+            assert_eq!(*frame, round_trip(frame, true));
+        }
+    }
+
+    #[test]
+    fn websocket_fragments() {
+        // "Hel" + ping + "lo", the second fragment masked
+        let bytes = [0x01u8, 0x03, 0x48, 0x65, 0x6C,
+                     0x89, 0x00,
+                     0x80, 0x82, 0x01, 0x02, 0x03, 0x04, 0x6C ^ 0x01, 0x6F ^ 0x02];
+        let mut r = BufReader::new(bytes.as_slice());
+        assert_eq!(Text("Hello".to_string()), read_frame(&mut r).unwrap());
+
+        // A continuation without a message to continue
+        assert!(read_frame(&mut BufReader::new([0x80u8, 0x00].as_slice())).is_err());
+        // An oversized length is rejected before reading the payload
+        let huge = [0x82u8, 0x7F, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00];
+        assert!(read_frame(&mut BufReader::new(huge.as_slice())).is_err());
+    }
+
+    #[test]
+    fn websocket_handshake() {
+        let mut acceptor = TcpListener::bind("127.0.0.1:0").unwrap().listen().unwrap();
+        let address = format!("{}", acceptor.socket_name().unwrap());
+        let (tx, rx) = channel();
+        spawn(proc() {
+            let mut stream = acceptor.accept().unwrap();
+            tx.send(accept(&mut stream, NT4_PROTOCOLS.as_slice()).unwrap());
+            let frame = read_frame(&mut stream).unwrap();
+            write_frame(&mut stream, &frame, false).unwrap();
+        });
+
+        let mut stream = TcpStream::connect(address.as_slice()).unwrap();
+        let protocol = connect(&mut stream, address.as_slice(), "/nt/test",
+                               ["networktables.first.wpi.edu"].as_slice()).unwrap();
+        assert_eq!("networktables.first.wpi.edu", protocol.as_slice());
+        assert_eq!(protocol, rx.recv());
+
+        write_frame(&mut stream, &Text("echo".to_string()), true).unwrap();
+        assert_eq!(Text("echo".to_string()), read_frame(&mut stream).unwrap());
+    }
+
+    #[test]
+    fn websocket_refuses_unknown_protocols() {
+        let mut acceptor = TcpListener::bind("127.0.0.1:0").unwrap().listen().unwrap();
+        let address = format!("{}", acceptor.socket_name().unwrap());
+        let (tx, rx) = channel();
+        spawn(proc() {
+            let mut stream = acceptor.accept().unwrap();
+            tx.send(accept(&mut stream, NT4_PROTOCOLS.as_slice()).unwrap_err().kind);
+        });
+
+        let mut stream = TcpStream::connect(address.as_slice()).unwrap();
+        assert!(connect(&mut stream, address.as_slice(), "/", ["chat"].as_slice()).is_err());
+        match rx.recv() {
+            HandshakeFailed(_) => (),
+            kind => panic!("Unexpected error {}", kind),
+        }
+    }
+}
@@ -0,0 +1,784 @@
+extern crate networktables;
+extern crate serialize;
+
+use networktables::{Client, Nt4Server, State, Get, Set, Table, Entry, SequenceNumber, Connected,
+                    Initializing, Closed, Added, Updated, FlagsUpdated, Deleted, PropertiesUpdated,
+                    FLAG_PERSISTENT, KeyDoesntExist, TypeMismatch, UnknownRpcCall, ValueTooLarge,
+                    OutOfOrderSequenceNumbers, IdAlreadyExists, RequiresNt4, RequiresNt3, OutOfRpcUids,
+                    UnsupportedType, UNSTAMPED};
+use networktables::mock::{MockServer, Assignment, Update, FlagsUpdate, Delete, ClearAll, ExecuteRpc,
+                          RpcResponse, Number, Raw, Rpc, CLIENT_REQUEST_ID,
+                          HELLO, ENTRY_ASSIGNMENT, ENTRY_UPDATE, CLEAR_ALL_MAGIC};
+
+use serialize::json;
+use std::collections::TreeMap;
+use std::io::timer::sleep;
+use std::time::Duration;
+
+fn entry(name: &str, id: u16, sequence: u16, value: f64) -> Entry {
+    Entry{name: name.to_string(), id: id, sequence: SequenceNumber(sequence), flags: 0,
+          value: Number(value), timestamp: UNSTAMPED}
+}
+
+/// Polls `f` until it's true, giving up after a couple of seconds.
+fn wait_for(f: || -> bool) -> bool {
+    for _ in range(0u, 200u) {
+        if f() { return true }
+        sleep(Duration::milliseconds(10));
+    }
+    false
+}
+
+fn is_error(state: State) -> bool {
+    match state {
+        Initializing | Connected | Closed => false,
+        _ => true,
+    }
+}
+
+#[test]
+fn client_handshake() {
+    let mut server = MockServer::new().unwrap();
+    let client = Client::new(server.address()).unwrap();
+    server.handshake().unwrap();
+    assert!(wait_for(|| client.get_state() == Connected));
+    client.close();
+}
+
+#[test]
+fn client_says_hello_as_the_spec_does() {
+    let mut server = MockServer::new().unwrap();
+    let client = Client::new(server.address()).unwrap();
+    // Set while saying hello, so the server doesn't have it yet
+    client.set("/B".to_string(), 2f64).unwrap();
+    server.accept().unwrap();
+    let mut hello = vec![0x01, 0x03, 0x00, 0x0D];
+    hello.push_all(b"networktables");
+    assert_eq!(hello, server.recv_raw(hello.len()).unwrap());
+
+    // The server hello, the server's entries and the server hello complete
+    server.send_raw(&[0x04, 0x00, 0x04, b'm', b'o', b'c', b'k',
+                      0x10, 0x02, b'/', b'A', 0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01,
+                      0x03]).unwrap();
+    // Then the client's entries and the client hello complete
+    let expected = vec![0x10, 0x02, b'/', b'B', 0x01, 0xFF, 0xFF, 0x00, 0x01, 0x00,
+                        0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                        0x05];
+    assert_eq!(expected, server.recv_raw(expected.len()).unwrap());
+    assert!(wait_for(|| client.get_state() == Connected));
+    assert_eq!(Some(true), client.get("/A".to_string()));
+    assert_eq!(17, client.stats().sent.get(&HELLO).unwrap().bytes);
+    client.close();
+}
+
+#[test]
+fn client_falls_back_to_nt2() {
+    let mut server = MockServer::new().unwrap();
+    let client = Client::new(server.address()).unwrap();
+    server.handshake_nt2().unwrap();
+    assert!(wait_for(|| client.get_state() == Connected));
+    assert_eq!(2, client.stats().messages_sent(HELLO));
+    // The NT2 hello has no identity
+    assert_eq!(17 + 3, client.stats().sent.get(&HELLO).unwrap().bytes);
+
+    // Messages are NT2 ones from then on
+    server.send(&Assignment(entry("/A", 1, 1, 1f64))).unwrap();
+    assert!(wait_for(|| client.get("/A".to_string()) == Some(1f64)));
+    client.set("/A".to_string(), 2f64).unwrap();
+    assert_eq!(Update(entry("/A", 1, 2, 2f64)), server.recv_skipping_keep_alives().unwrap());
+
+    // Which have no flags or deletes
+    let err = client.set_persistent("/A".to_string()).unwrap_err();
+    assert_eq!(RequiresNt3("set flags"), err.kind);
+    assert_eq!(Some(0), client.get_flags("/A".to_string()));
+    let err = client.delete("/A".to_string()).unwrap_err();
+    assert_eq!(RequiresNt3("delete entries"), err.kind);
+    assert_eq!(RequiresNt3("delete entries"), client.delete_all().unwrap_err().kind);
+    let err = client.call_rpc("/A".to_string(), Vec::new()).unwrap_err();
+    assert_eq!(RequiresNt3("call RPCs"), err.kind);
+    let err = client.set("/Raw".to_string(), vec![0x01u8]).unwrap_err();
+    assert_eq!(UnsupportedType(0x03), err.kind);
+    assert!(wait_for(|| client.get("/A".to_string()) == Some(2f64)));
+    server.send_raw([0x12u8, 0x00, 0x01, 0x01].as_slice()).unwrap();
+    assert!(wait_for(|| is_error(client.get_state())));
+}
+
+#[test]
+fn client_applies_assignments_and_updates() {
+    let mut server = MockServer::new().unwrap();
+    let client = Client::new(server.address()).unwrap();
+    let (tx, rx) = channel();
+    client.add_listener(tx);
+    server.handshake().unwrap();
+
+    server.send(&Assignment(entry("/Number", 3, 1, 1f64))).unwrap();
+    assert_eq!(Added("/Number".to_string(), Number(1f64)), rx.recv());
+    let added = client.get_timestamp("/Number".to_string()).unwrap();
+    server.send(&Update(entry("/Number", 3, 2, 2f64))).unwrap();
+    assert_eq!(Updated("/Number".to_string(), Number(2f64)), rx.recv());
+
+    let n: Option<f64> = client.get("/Number".to_string());
+    assert_eq!(Some(2f64), n);
+    // NT2 values only have the time they arrived
+    let updated = client.get_timestamp("/Number".to_string()).unwrap();
+    assert!(updated.local_ns >= added.local_ns && added.local_ns > 0);
+    assert_eq!(None, updated.server_us);
+    assert_eq!(None, client.server_time());
+    client.close();
+}
+
+#[test]
+fn client_rejects_out_of_order_updates() {
+    let mut server = MockServer::new().unwrap();
+    let client = Client::new(server.address()).unwrap();
+    server.handshake().unwrap();
+
+    server.send(&Assignment(entry("/Number", 3, 5, 1f64))).unwrap();
+    server.send(&Update(entry("/Number", 3, 5, 2f64))).unwrap();
+    assert!(wait_for(|| client.get_errors().len() == 1));
+    match client.get_errors()[0].kind {
+        OutOfOrderSequenceNumbers(old, new) => {
+            assert_eq!(SequenceNumber(5), old);
+            assert_eq!(SequenceNumber(5), new);
+        },
+        ref kind => panic!("Unexpected error {}", kind),
+    }
+
+    let n: Option<f64> = client.get("/Number".to_string());
+    assert_eq!(Some(1f64), n);
+    client.close();
+}
+
+#[test]
+fn client_rejects_duplicate_ids() {
+    let mut server = MockServer::new().unwrap();
+    let client = Client::new(server.address()).unwrap();
+    server.handshake().unwrap();
+
+    server.send(&Assignment(entry("/First", 3, 0, 1f64))).unwrap();
+    server.send(&Assignment(entry("/Second", 3, 0, 2f64))).unwrap();
+    assert!(wait_for(|| client.get_errors().len() == 1));
+    match client.get_errors()[0].kind {
+        IdAlreadyExists(ref existing, ref incoming) => {
+            assert_eq!("/First", existing.name.as_slice());
+            assert_eq!("/Second", incoming.name.as_slice());
+        },
+        ref kind => panic!("Unexpected error {}", kind),
+    }
+    client.close();
+}
+
+#[test]
+fn client_sends_assignment_for_new_key() {
+    let mut server = MockServer::new().unwrap();
+    let client = Client::new(server.address()).unwrap();
+    server.handshake().unwrap();
+
+    client.set("/Number".to_string(), 4f64).unwrap();
+    match server.recv_skipping_keep_alives().unwrap() {
+        Assignment(e) => {
+            assert_eq!("/Number", e.name.as_slice());
+            assert_eq!(CLIENT_REQUEST_ID, e.id);
+            assert_eq!(Number(4f64), e.value);
+        },
+        m => panic!("Unexpected message {}", m),
+    }
+    client.close();
+}
+
+#[test]
+fn client_rejects_values_of_another_type() {
+    let mut server = MockServer::new().unwrap();
+    let client = Client::new(server.address()).unwrap();
+    server.handshake().unwrap();
+    server.send(&Assignment(entry("/Number", 1, 1, 1f64))).unwrap();
+    assert!(wait_for(|| client.get("/Number".to_string()) == Some(1f64)));
+
+    let err = client.set("/Number".to_string(), true).unwrap_err();
+    assert_eq!(TypeMismatch("/Number".to_string()), err.kind);
+    assert_eq!(Some(1f64), client.get("/Number".to_string()));
+    // Entries waiting on an id are checked too
+    client.set("/Pending".to_string(), 2f64).unwrap();
+    let err = client.set("/Pending".to_string(), vec![0x01u8]).unwrap_err();
+    assert_eq!(TypeMismatch("/Pending".to_string()), err.kind);
+    // And no part of a batch is set if any of it mismatches
+    let err = client.batch(|b| {
+        b.set("/Other".to_string(), 3f64).unwrap();
+        b.set("/Number".to_string(), false).unwrap();
+    }).unwrap_err();
+    assert_eq!(TypeMismatch("/Number".to_string()), err.kind);
+    assert_eq!(None::<f64>, client.get("/Other".to_string()));
+
+    // Only the value that type checked was sent
+    match server.recv_skipping_keep_alives().unwrap() {
+        Assignment(e) => assert_eq!(entry("/Pending", CLIENT_REQUEST_ID, 1, 2f64), e),
+        m => panic!("Unexpected message {}", m),
+    }
+    assert!(wait_for(|| client.stats().send_queue_depth == 0));
+    client.close();
+}
+
+#[test]
+fn client_fails_on_truncated_frame() {
+    let mut server = MockServer::new().unwrap();
+    let client = Client::new(server.address()).unwrap();
+    server.handshake().unwrap();
+
+    server.send_truncated(&Assignment(entry("/Number", 3, 0, 1f64)), 6).unwrap();
+    server.drop_connection();
+    assert!(wait_for(|| is_error(client.get_state())));
+}
+
+#[test]
+fn client_fails_on_disconnect() {
+    let mut server = MockServer::new().unwrap();
+    let client = Client::new(server.address()).unwrap();
+    server.handshake().unwrap();
+
+    server.drop_connection();
+    assert!(wait_for(|| is_error(client.get_state())));
+}
+
+#[test]
+fn client_fails_on_unknown_message_type() {
+    let mut server = MockServer::new().unwrap();
+    let client = Client::new(server.address()).unwrap();
+    server.handshake().unwrap();
+
+    server.send_raw([0x7Fu8].as_slice()).unwrap();
+    assert!(wait_for(|| is_error(client.get_state())));
+}
+
+#[test]
+fn client_reads_pending_assignments() {
+    let mut server = MockServer::new().unwrap();
+    let client = Client::new(server.address()).unwrap();
+    server.handshake().unwrap();
+
+    client.set("/Number".to_string(), 4f64).unwrap();
+    let n: Option<f64> = client.get("/Number".to_string());
+    assert_eq!(Some(4f64), n);
+
+    // The server's assignment replaces the pending one
+    match server.recv_skipping_keep_alives().unwrap() {
+        Assignment(e) => server.send(&Assignment(Entry{id: 7, ..e})).unwrap(),
+        m => panic!("Unexpected message {}", m),
+    }
+    server.send(&Update(entry("/Number", 7, 2, 5f64))).unwrap();
+    assert!(wait_for(|| client.get("/Number".to_string()) == Some(5f64)));
+    assert!(client.get_errors().is_empty());
+    client.close();
+}
+
+#[test]
+fn client_resends_values_set_before_assignment() {
+    let mut server = MockServer::new().unwrap();
+    let client = Client::new(server.address()).unwrap();
+    server.handshake().unwrap();
+
+    client.set("/Number".to_string(), 1f64).unwrap();
+    let assigned = match server.recv_skipping_keep_alives().unwrap() {
+        Assignment(e) => Entry{id: 7, ..e},
+        m => panic!("Unexpected message {}", m),
+    };
+
+    // A second set before the assignment comes back doesn't request
+    // another id, it's sent as an update once the id is known.
+    client.set("/Number".to_string(), 2f64).unwrap();
+    let n: Option<f64> = client.get("/Number".to_string());
+    assert_eq!(Some(2f64), n);
+    server.send(&Assignment(assigned.clone())).unwrap();
+    match server.recv_skipping_keep_alives().unwrap() {
+        Update(e) => {
+            assert_eq!(7, e.id);
+            assert_eq!(Number(2f64), e.value);
+            assert!(e.sequence.is_newer_than(&assigned.sequence));
+        },
+        m => panic!("Unexpected message {}", m),
+    }
+    client.close();
+}
+
+#[test]
+fn client_keeps_assignments_of_another_type() {
+    let mut server = MockServer::new().unwrap();
+    let client = Client::new(server.address()).unwrap();
+    server.handshake().unwrap();
+
+    client.set("/Number".to_string(), 1f64).unwrap();
+    server.recv_skipping_keep_alives().unwrap();
+    // Another client assigned the key first, with another type
+    server.send(&Assignment(Entry{value: Raw(vec![0x01]), ..entry("/Number", 7, 1, 0f64)})).unwrap();
+    assert!(wait_for(|| client.get("/Number".to_string()) == Some(vec![0x01u8])));
+    assert_eq!(TypeMismatch("/Number".to_string()), client.get_errors()[0].kind);
+
+    // The local value isn't sent on as an update
+    client.set("/Other".to_string(), 2f64).unwrap();
+    match server.recv_skipping_keep_alives().unwrap() {
+        Assignment(e) => assert_eq!("/Other", e.name.as_slice()),
+        m => panic!("Unexpected message {}", m),
+    }
+    client.close();
+}
+
+#[test]
+fn client_sends_batches_together() {
+    let mut server = MockServer::new().unwrap();
+    let client = Client::new(server.address()).unwrap();
+    server.handshake().unwrap();
+
+    client.batch(|b| {
+        b.set("/Setpoint/left".to_string(), 1f64).unwrap();
+        b.set("/Setpoint/right".to_string(), 2f64).unwrap();
+    }).unwrap();
+    let snapshot = client.snapshot("/Setpoint/");
+    assert_eq!(2, snapshot.len());
+
+    let mut names = Vec::new();
+    for _ in range(0u, 2u) {
+        match server.recv_skipping_keep_alives().unwrap() {
+            Assignment(e) => names.push(e.name),
+            m => panic!("Unexpected message {}", m),
+        }
+    }
+    assert_eq!(vec!["/Setpoint/left".to_string(), "/Setpoint/right".to_string()], names);
+    client.close();
+}
+
+#[test]
+fn client_reads_its_own_writes() {
+    let mut server = MockServer::new().unwrap();
+    let client = Client::new(server.address()).unwrap();
+    let (tx, rx) = channel();
+    client.add_listener(tx);
+    server.handshake().unwrap();
+
+    server.send(&Assignment(entry("/Number", 3, 5, 1f64))).unwrap();
+    assert_eq!(Added("/Number".to_string(), Number(1f64)), rx.recv());
+
+    // The set is visible straight away, before the server sees it
+    client.set("/Number".to_string(), 2f64).unwrap();
+    assert_eq!(Updated("/Number".to_string(), Number(2f64)), rx.recv());
+    let n: Option<f64> = client.get("/Number".to_string());
+    assert_eq!(Some(2f64), n);
+    match server.recv_skipping_keep_alives().unwrap() {
+        Update(e) => assert_eq!(entry("/Number", 3, 6, 2f64), e),
+        m => panic!("Unexpected message {}", m),
+    }
+
+    // An update that isn't newer than our write doesn't replace it
+    server.send(&Update(entry("/Number", 3, 6, 3f64))).unwrap();
+    assert!(wait_for(|| client.get_errors().len() == 1));
+    let n: Option<f64> = client.get("/Number".to_string());
+    assert_eq!(Some(2f64), n);
+
+    server.send(&Update(entry("/Number", 3, 7, 4f64))).unwrap();
+    assert_eq!(Updated("/Number".to_string(), Number(4f64)), rx.recv());
+    let n: Option<f64> = client.get("/Number".to_string());
+    assert_eq!(Some(4f64), n);
+    client.close();
+}
+
+#[test]
+fn client_counts_traffic() {
+    let mut server = MockServer::new().unwrap();
+    let client = Client::new(server.address()).unwrap();
+    server.handshake().unwrap();
+
+    server.send(&Assignment(entry("/Number", 3, 5, 1f64))).unwrap();
+    server.send(&Update(entry("/Number", 3, 5, 2f64))).unwrap();
+    assert!(wait_for(|| client.stats().out_of_order == 1));
+
+    client.set("/Number".to_string(), 3f64).unwrap();
+    client.set("/Other".to_string(), 4f64).unwrap();
+    server.recv_skipping_keep_alives().unwrap();
+    server.recv_skipping_keep_alives().unwrap();
+    assert!(wait_for(|| client.stats().send_queue_depth == 0));
+
+    let stats = client.stats();
+    assert_eq!(1, stats.messages_sent(HELLO));
+    assert_eq!(1, stats.messages_sent(ENTRY_UPDATE));
+    assert_eq!(1, stats.messages_sent(ENTRY_ASSIGNMENT));
+    assert_eq!(1, stats.messages_received(ENTRY_ASSIGNMENT));
+    assert_eq!(1, stats.messages_received(ENTRY_UPDATE));
+    // An NT3 update is a 1 byte message type, 2 byte id, 2 byte sequence, 1 byte value
+    // type and an 8 byte number
+    assert_eq!(14, stats.sent.get(&ENTRY_UPDATE).unwrap().bytes);
+    assert!(stats.flushes >= 1);
+    client.close();
+}
+
+#[test]
+fn client_coalesces_updates_when_limited() {
+    let mut server = MockServer::new().unwrap();
+    let client = Client::new(server.address()).unwrap();
+    server.handshake().unwrap();
+    server.send(&Assignment(entry("/Number", 3, 1, 0f64))).unwrap();
+    assert!(wait_for(|| client.get("/Number".to_string()) == Some(0f64)));
+
+    // The first update uses up the budget, so the rest wait and
+    // replace each other in the queue.
+    client.set_bandwidth_limit(Some(1));
+    client.set("/Number".to_string(), 1f64).unwrap();
+    server.recv_skipping_keep_alives().unwrap();
+    for i in range(2u, 5u) {
+        client.set("/Number".to_string(), i as f64).unwrap();
+    }
+    sleep(Duration::milliseconds(100));
+    let stats = client.stats();
+    assert!(stats.deferred_flushes > 0);
+    assert_eq!(2, stats.coalesced);
+    assert_eq!(1, stats.send_queue_depth);
+
+    client.set_bandwidth_limit(None);
+    match server.recv_skipping_keep_alives().unwrap() {
+        Update(e) => assert_eq!(entry("/Number", 3, 5, 4f64), e),
+        m => panic!("Unexpected message {}", m),
+    }
+    client.close();
+}
+
+#[test]
+fn client_keep_alives_wait_for_bandwidth_limit() {
+    let mut server = MockServer::new().unwrap();
+    let client = Client::new(server.address()).unwrap();
+    server.handshake().unwrap();
+
+    // The assignment puts the limiter seconds into debt
+    client.set_bandwidth_limit(Some(1));
+    client.set("/Number".to_string(), 1f64).unwrap();
+    server.recv_skipping_keep_alives().unwrap();
+    let sent = client.stats().keep_alives_sent();
+    sleep(Duration::milliseconds(1100));
+    assert_eq!(sent, client.stats().keep_alives_sent());
+    assert_eq!(0, client.stats().send_queue_depth);
+    client.close();
+}
+
+#[test]
+fn client_deletes_entries() {
+    let mut server = MockServer::new().unwrap();
+    let client = Client::new(server.address()).unwrap();
+    let (tx, rx) = channel();
+    client.add_listener(tx);
+    server.handshake().unwrap();
+
+    server.send(&Assignment(entry("/A", 1, 1, 1f64))).unwrap();
+    server.send(&Assignment(entry("/B", 2, 1, 2f64))).unwrap();
+    server.send(&Assignment(entry("/C", 3, 1, 3f64))).unwrap();
+    for _ in range(0u, 3u) { rx.recv(); }
+
+    client.delete("/A".to_string()).unwrap();
+    assert_eq!(Deleted("/A".to_string()), rx.recv());
+    let a: Option<f64> = client.get("/A".to_string());
+    assert_eq!(None, a);
+    assert_eq!(Delete(1), server.recv_skipping_keep_alives().unwrap());
+
+    server.send(&Delete(2)).unwrap();
+    assert_eq!(Deleted("/B".to_string()), rx.recv());
+
+    // A clear with the wrong magic is ignored
+    server.send(&ClearAll(0)).unwrap();
+    server.send(&ClearAll(CLEAR_ALL_MAGIC)).unwrap();
+    assert_eq!(Deleted("/C".to_string()), rx.recv());
+    assert!(client.snapshot("").is_empty());
+
+    client.set("/D".to_string(), 4f64).unwrap();
+    client.delete_all().unwrap();
+    assert_eq!(Added("/D".to_string(), Number(4f64)), rx.recv());
+    assert_eq!(Deleted("/D".to_string()), rx.recv());
+    assert_eq!(ClearAll(CLEAR_ALL_MAGIC), server.recv_skipping_keep_alives().unwrap());
+    assert!(client.get_errors().is_empty());
+    client.close();
+}
+
+#[test]
+fn client_deletes_entries_assigned_after_the_delete() {
+    let mut server = MockServer::new().unwrap();
+    let client = Client::new(server.address()).unwrap();
+    server.handshake().unwrap();
+
+    client.set("/A".to_string(), 1f64).unwrap();
+    let request = match server.recv_skipping_keep_alives().unwrap() {
+        Assignment(e) => e,
+        m => panic!("Unexpected message {}", m),
+    };
+    // The request is out, so the entry is deleted once it's assigned
+    client.delete("/A".to_string()).unwrap();
+    server.send(&Assignment(Entry{id: 4, ..request.clone()})).unwrap();
+    assert_eq!(Delete(4), server.recv_skipping_keep_alives().unwrap());
+    let a: Option<f64> = client.get("/A".to_string());
+    assert_eq!(None, a);
+
+    // Likewise for a delete_all
+    client.set("/B".to_string(), 2f64).unwrap();
+    let request = match server.recv_skipping_keep_alives().unwrap() {
+        Assignment(e) => e,
+        m => panic!("Unexpected message {}", m),
+    };
+    client.delete_all().unwrap();
+    assert_eq!(ClearAll(CLEAR_ALL_MAGIC), server.recv_skipping_keep_alives().unwrap());
+    server.send(&Assignment(Entry{id: 5, ..request})).unwrap();
+    assert_eq!(Delete(5), server.recv_skipping_keep_alives().unwrap());
+    assert!(client.snapshot("").is_empty());
+    assert!(client.get_errors().is_empty());
+    client.close();
+}
+
+#[test]
+fn client_sets_and_receives_flags() {
+    let mut server = MockServer::new().unwrap();
+    let client = Client::new(server.address()).unwrap();
+    let (tx, rx) = channel();
+    client.add_listener(tx);
+    server.handshake().unwrap();
+
+    server.send(&Assignment(entry("/A", 1, 1, 1f64))).unwrap();
+    server.send(&Assignment(entry("/B", 2, 1, 2f64))).unwrap();
+    for _ in range(0u, 2u) { rx.recv(); }
+    assert_eq!(Some(0), client.get_flags("/A".to_string()));
+    assert_eq!(None, client.get_flags("/Missing".to_string()));
+
+    client.set_persistent("/A".to_string()).unwrap();
+    assert_eq!(FlagsUpdated("/A".to_string(), FLAG_PERSISTENT), rx.recv());
+    assert_eq!(Some(FLAG_PERSISTENT), client.get_flags("/A".to_string()));
+    assert_eq!(FlagsUpdate(1, FLAG_PERSISTENT), server.recv_skipping_keep_alives().unwrap());
+
+    server.send(&FlagsUpdate(2, FLAG_PERSISTENT)).unwrap();
+    assert_eq!(FlagsUpdated("/B".to_string(), FLAG_PERSISTENT), rx.recv());
+    // Updates keep the flags
+    server.send(&Update(entry("/B", 2, 2, 3f64))).unwrap();
+    rx.recv();
+    assert_eq!(Some(FLAG_PERSISTENT), client.get_flags("/B".to_string()));
+
+    let err = client.set_flags("/Missing".to_string(), 0).unwrap_err();
+    assert_eq!(KeyDoesntExist("/Missing".to_string()), err.kind);
+    client.close();
+}
+
+#[test]
+fn client_calls_rpcs() {
+    let mut server = MockServer::new().unwrap();
+    let client = Client::new(server.address()).unwrap();
+    let (tx, rx) = channel();
+    client.add_listener(tx);
+    server.handshake().unwrap();
+
+    server.send(&Assignment(Entry{value: Rpc(vec![0x01]), ..entry("/Reverse", 1, 0, 0f64)})).unwrap();
+    server.send(&Assignment(entry("/Number", 2, 1, 1f64))).unwrap();
+    for _ in range(0u, 2u) { rx.recv(); }
+
+    let first = client.call_rpc("/Reverse".to_string(), vec![1, 2]).unwrap();
+    let second = client.call_rpc("/Reverse".to_string(), vec![3]).unwrap();
+    let (a, b) = match (server.recv_skipping_keep_alives().unwrap(),
+                        server.recv_skipping_keep_alives().unwrap()) {
+        (ExecuteRpc(1, a, ref p), ExecuteRpc(1, b, ref q)) => {
+            assert_eq!(vec![1, 2], *p);
+            assert_eq!(vec![3], *q);
+            (a, b)
+        },
+        m => panic!("Unexpected messages {}", m),
+    };
+    assert!(a != b);
+
+    // Responses are matched to calls by their unique id
+    server.send(&RpcResponse(1, b, vec![3])).unwrap();
+    server.send(&RpcResponse(1, a, vec![2, 1])).unwrap();
+    assert_eq!(vec![2, 1], first.recv());
+    assert_eq!(vec![3], second.recv());
+
+    server.send(&RpcResponse(1, a, Vec::new())).unwrap();
+    assert!(wait_for(|| !client.get_errors().is_empty()));
+    assert_eq!(UnknownRpcCall(1, a), client.get_errors()[0].kind);
+
+    let err = client.call_rpc("/Number".to_string(), Vec::new()).unwrap_err();
+    assert_eq!(TypeMismatch("/Number".to_string()), err.kind);
+    let err = client.call_rpc("/Missing".to_string(), Vec::new()).unwrap_err();
+    assert_eq!(KeyDoesntExist("/Missing".to_string()), err.kind);
+
+    // Calls the server never responds to give up after the timeout
+    client.set_rpc_timeout(Duration::milliseconds(50));
+    let unanswered = client.call_rpc("/Reverse".to_string(), vec![4]).unwrap();
+    assert!(unanswered.recv_opt().is_err());
+    client.close();
+}
+
+#[test]
+fn client_runs_out_of_rpc_uids() {
+    let mut server = MockServer::new().unwrap();
+    let client = Client::new(server.address()).unwrap();
+    client.set_rpc_timeout(Duration::minutes(1));
+    let (tx, rx) = channel();
+    client.add_listener(tx);
+    // Without a hello complete, the calls stay queued and unanswered
+    server.accept().unwrap();
+    server.recv().unwrap();
+    server.send(&Assignment(Entry{value: Rpc(vec![0x01]), ..entry("/Rpc", 1, 0, 0f64)})).unwrap();
+    rx.recv();
+
+    let calls: Vec<_> = range(0u, 0x10000).map(|_| client.call_rpc("/Rpc".to_string(), Vec::new()).unwrap())
+                                          .collect();
+    let err = client.call_rpc("/Rpc".to_string(), Vec::new()).unwrap_err();
+    assert_eq!(OutOfRpcUids(1), err.kind);
+    assert_eq!(0x10000, calls.len());
+    client.close();
+}
+
+#[test]
+fn client_sends_and_receives_raw_values() {
+    let mut server = MockServer::new().unwrap();
+    let client = Client::new(server.address()).unwrap();
+    server.handshake().unwrap();
+
+    let pose = Vec::from_fn(300, |i| i as u8);
+    client.set("/Pose".to_string(), pose.clone()).unwrap();
+    let request = Entry{value: Raw(pose.clone()), ..entry("/Pose", CLIENT_REQUEST_ID, 0, 0f64)};
+    assert_eq!(Assignment(request), server.recv_skipping_keep_alives().unwrap());
+
+    server.send(&Assignment(Entry{value: Raw(pose), ..entry("/Pose", 1, 0, 0f64)})).unwrap();
+    server.send(&Update(Entry{value: Raw(vec![0x01]), ..entry("/Pose", 1, 1, 0f64)})).unwrap();
+    assert!(wait_for(|| {
+        let pose: Option<Vec<u8>> = client.get("/Pose".to_string());
+        pose == Some(vec![0x01])
+    }));
+    // Values of another type aren't coerced
+    let pose: Option<String> = client.get("/Pose".to_string());
+    assert_eq!(None, pose);
+    assert!(client.get_errors().is_empty());
+    client.close();
+}
+
+#[test]
+fn client_sends_long_values_over_nt3() {
+    let mut server = MockServer::new().unwrap();
+    let client = Client::new(server.address()).unwrap();
+    server.handshake().unwrap();
+    assert!(wait_for(|| client.get_state() == Connected));
+
+    // NT3 lengths are LEB128, so they aren't held to 16 bits
+    let long = Vec::from_elem(70000, 0xABu8);
+    client.set("/Long".to_string(), long.clone()).unwrap();
+    match server.recv_skipping_keep_alives().unwrap() {
+        Assignment(e) => assert_eq!(Raw(long), e.value),
+        m => panic!("Unexpected message {}", m),
+    }
+    let longer = Vec::from_elem(80000, 0xCDu8);
+    server.send(&Assignment(Entry{value: Raw(longer.clone()), ..entry("/Long", 1, 0, 0f64)})).unwrap();
+    assert!(wait_for(|| {
+        let value: Option<Vec<u8>> = client.get("/Long".to_string());
+        value == Some(longer.clone())
+    }));
+    assert!(client.get_errors().is_empty());
+    client.close();
+}
+
+#[test]
+fn client_rejects_values_too_large_to_send() {
+    let mut server = MockServer::new().unwrap();
+    let client = Client::new(server.address()).unwrap();
+    server.handshake_nt2().unwrap();
+    assert!(wait_for(|| client.get_state() == Connected));
+
+    let long = String::from_char(70000, 'x');
+    let err = client.set("/Long".to_string(), long.clone()).unwrap_err();
+    assert_eq!(ValueTooLarge(70000, 0xFFFF), err.kind);
+    let err = client.set(long.clone(), 1f64).unwrap_err();
+    assert_eq!(ValueTooLarge(70000, 0xFFFF), err.kind);
+    let value: Option<String> = client.get("/Long".to_string());
+    assert_eq!(None, value);
+
+    // None of a batch is queued if any of it is too large
+    let err = client.batch(|b| {
+        b.set("/Short".to_string(), "x".to_string()).unwrap();
+        b.set("/Long".to_string(), long.clone()).unwrap();
+    }).unwrap_err();
+    assert_eq!(ValueTooLarge(70000, 0xFFFF), err.kind);
+    assert!(client.snapshot("").is_empty());
+
+    // The connection carries on
+    client.set("/Short".to_string(), String::from_char(0xFFFF, 'x')).unwrap();
+    match server.recv_skipping_keep_alives().unwrap() {
+        Assignment(e) => assert_eq!("/Short", e.name.as_slice()),
+        m => panic!("Unexpected message {}", m),
+    }
+    assert!(client.get_errors().is_empty());
+    assert!(wait_for(|| client.get_state() == Connected));
+    client.close();
+}
+
+#[test]
+fn nt4_clients_share_values_and_properties() {
+    let server = Nt4Server::new("127.0.0.1:0").unwrap();
+    let a = Client::new_nt4(server.address(), "a").unwrap();
+    let b = Client::new_nt4(server.address(), "b").unwrap();
+    let (tx, rx) = channel();
+    b.add_listener(tx);
+
+    a.set("/Speed".to_string(), 1.5f64).unwrap();
+    assert_eq!(Added("/Speed".to_string(), Number(1.5)), rx.recv());
+    assert_eq!(Some(1.5f64), b.get("/Speed".to_string()));
+
+    assert!(wait_for(|| a.get_properties("/Speed".to_string()).is_some()));
+    a.set_property("/Speed".to_string(), "unit".to_string(), json::String("m/s".to_string())).unwrap();
+    let mut update = TreeMap::new();
+    update.insert("unit".to_string(), json::String("m/s".to_string()));
+    assert_eq!(PropertiesUpdated("/Speed".to_string(), update), rx.recv());
+    assert_eq!(Some(&json::String("m/s".to_string())),
+               b.get_properties("/Speed".to_string()).unwrap().get(&"unit".to_string()));
+
+    // Uncached values reach listeners without being stored, so the
+    // stored value is deleted
+    let (a_tx, a_rx) = channel();
+    a.add_listener(a_tx);
+    a.set_property("/Speed".to_string(), "cached".to_string(), json::Boolean(false)).unwrap();
+    assert_eq!(Deleted("/Speed".to_string()), a_rx.recv());
+    assert_eq!(Deleted("/Speed".to_string()), rx.recv());
+    rx.recv();
+    a.set("/Speed".to_string(), 2.5f64).unwrap();
+    assert_eq!(Added("/Speed".to_string(), Number(2.5)), rx.recv());
+    let (in_a, in_b): (Option<f64>, Option<f64>) = (a.get("/Speed".to_string()), b.get("/Speed".to_string()));
+    assert_eq!((None, None), (in_a, in_b));
+
+    let err = a.set_property("/Missing".to_string(), "unit".to_string(), json::Null).unwrap_err();
+    assert_eq!(KeyDoesntExist("/Missing".to_string()), err.kind);
+    assert!(a.get_errors().is_empty() && b.get_errors().is_empty());
+    a.close();
+    b.close();
+    server.close();
+}
+
+#[test]
+fn client_only_sets_properties_over_nt4() {
+    let mut server = MockServer::new().unwrap();
+    let client = Client::new(server.address()).unwrap();
+    server.handshake().unwrap();
+    let err = client.set_property("/A".to_string(), "unit".to_string(), json::Null).unwrap_err();
+    assert_eq!(RequiresNt4("set properties"), err.kind);
+    assert_eq!(None, client.get_properties("/A".to_string()));
+    client.close();
+}
+
+#[test]
+fn nt4_client_syncs_time_and_stamps_values() {
+    let server = Nt4Server::new("127.0.0.1:0").unwrap();
+    let a = Client::new_nt4(server.address(), "a").unwrap();
+    let b = Client::new_nt4(server.address(), "b").unwrap();
+    let (tx, rx) = channel();
+    b.add_listener(tx);
+
+    assert!(wait_for(|| a.server_time().is_some()));
+    // Both clocks are on this machine, so the estimate should be close
+    let (estimate, now) = (a.server_time().unwrap(), server.now_us());
+    assert!(estimate > now - 50000 && estimate < now + 50000);
+
+    a.set("/Target".to_string(), 3f64).unwrap();
+    assert_eq!(Added("/Target".to_string(), Number(3f64)), rx.recv());
+    let sent = a.get_timestamp("/Target".to_string()).unwrap();
+    let received = b.get_timestamp("/Target".to_string()).unwrap();
+    // The value keeps the time it was set, on the server's clock
+    assert_eq!(sent.server_us, received.server_us);
+    assert!(received.local_ns >= sent.local_ns);
+    a.close();
+    b.close();
+    server.close();
+}